@@ -0,0 +1,183 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const SHADER_EXTENSIONS: &[&str] = &[
+    "vert", "tesc", "tese", "geom", "task", "mesh", "frag", "comp",
+];
+const DEFAULT_ENTRY_POINT: &str = "main";
+/// Default `VKGUIDE_EFFECT_DIR`, whose effects are precompiled as `effects/<file>`.
+const EFFECT_DIR: &str = "shaders/effects";
+
+/// Key of a permutation in the precompiled shader table. Must match `ShaderDefines::fingerprint`.
+fn permutation_key(defines: &BTreeMap<String, String>) -> String {
+    defines
+        .iter()
+        .map(|(define, value)| {
+            if value.is_empty() {
+                define.clone()
+            } else {
+                format!("{define}={value}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Splits a line of `shaders/permutations.txt` into the shader name, its entry point and its
+/// defines, `None` for blank lines and comments.
+fn parse_permutation(line: &str) -> Option<(&str, &str, BTreeMap<String, String>)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let mut words = line.split_whitespace();
+    let shader = words.next()?;
+    let (name, entry_point) = shader
+        .split_once(':')
+        .unwrap_or((shader, DEFAULT_ENTRY_POINT));
+    let defines = words
+        .map(|define| {
+            let (define, value) = define.split_once('=').unwrap_or((define, ""));
+            (define.to_owned(), value.to_owned())
+        })
+        .collect();
+    Some((name, entry_point, defines))
+}
+
+/// Compiles one permutation of `path` and returns the `match` arm serving it.
+fn compile(
+    glslc: &str,
+    include_paths: &[PathBuf],
+    out_dir: &Path,
+    path: &Path,
+    entry_point: &str,
+    defines: &BTreeMap<String, String>,
+) -> String {
+    // Shaders are named by their path relative to `shaders/`.
+    let name = path
+        .strip_prefix("shaders")
+        .unwrap_or(path)
+        .to_str()
+        .expect("shader names are utf-8")
+        .replace('\\', "/");
+    let fingerprint = permutation_key(defines);
+    let mut output_name = name.replace('/', ".");
+    if entry_point != DEFAULT_ENTRY_POINT {
+        output_name.push('.');
+        output_name.push_str(entry_point);
+    }
+    if !fingerprint.is_empty() {
+        output_name.push('.');
+        output_name.push_str(&fingerprint.replace(['=', ','], "_"));
+    }
+    let output = out_dir.join(format!("{output_name}.spv"));
+    let mut command = Command::new(glslc);
+    command.arg("--target-env=vulkan1.3");
+    if let Some(stem) = name.strip_suffix(".hlsl") {
+        // HLSL files are named `<name>.<stage>.hlsl`.
+        let stage = Path::new(stem)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_else(|| panic!("{name} is missing its stage extension"));
+        command
+            .arg("-x")
+            .arg("hlsl")
+            .arg(format!("-fshader-stage={stage}"))
+            .arg(format!("-fentry-point={entry_point}"));
+    }
+    for include_path in include_paths {
+        command.arg("-I").arg(include_path);
+    }
+    for (define, value) in defines {
+        if value.is_empty() {
+            command.arg(format!("-D{define}"));
+        } else {
+            command.arg(format!("-D{define}={value}"));
+        }
+    }
+    let status = command
+        .arg(path)
+        .arg("-o")
+        .arg(&output)
+        .status()
+        .unwrap_or_else(|e| panic!("could not run {glslc}: {e}"));
+    assert!(status.success(), "could not compile {name} [{fingerprint}]");
+    format!(
+        "        ({name:?}, {entry_point:?}, {fingerprint:?}) => Some(include_bytes!({:?})),\n",
+        output.display().to_string()
+    )
+}
+
+/// Without the `runtime-shaders` feature, every shader in `shaders/` is compiled to SPIR-V with
+/// glslc (or `$GLSLC`) so that the binary can ship without shaderc. Permutations besides the
+/// default one, and every HLSL entry point, are listed in `shaders/permutations.txt`.
+fn main() {
+    println!("cargo::rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_RUNTIME_SHADERS").is_some() {
+        return;
+    }
+    println!("cargo::rerun-if-changed=shaders");
+    println!("cargo::rerun-if-env-changed=GLSLC");
+    println!("cargo::rerun-if-env-changed=VKGUIDE_SHADER_INCLUDE_PATH");
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    let out_dir = Path::new(&out_dir);
+    let glslc = env::var("GLSLC").unwrap_or_else(|_| "glslc".to_owned());
+    // Same search order as `ShaderCompiler`, glslc resolves quoted includes next to the includer.
+    let mut include_paths = vec![PathBuf::from("shaders")];
+    if let Some(extra) = env::var_os("VKGUIDE_SHADER_INCLUDE_PATH") {
+        include_paths.extend(env::split_paths(&extra));
+    }
+    let mut table = String::from(
+        "fn precompiled_spirv(name: &str, entry_point: &str, defines: &str) -> Option<&'static [u8]> {\n    match (name, entry_point, defines) {\n",
+    );
+    let mut entries = fs::read_dir("shaders")
+        .expect("could not read shaders dir")
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            p.is_file()
+                && p.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| SHADER_EXTENSIONS.contains(&e))
+        })
+        .collect::<Vec<_>>();
+    if let Ok(effects) = fs::read_dir(EFFECT_DIR) {
+        entries.extend(
+            effects
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|e| e == "comp")),
+        );
+    }
+    entries.sort();
+    for path in entries {
+        table.push_str(&compile(
+            &glslc,
+            &include_paths,
+            out_dir,
+            &path,
+            DEFAULT_ENTRY_POINT,
+            &BTreeMap::new(),
+        ));
+    }
+
+    let permutations = fs::read_to_string("shaders/permutations.txt").unwrap_or_default();
+    for (name, entry_point, defines) in permutations.lines().filter_map(parse_permutation) {
+        table.push_str(&compile(
+            &glslc,
+            &include_paths,
+            out_dir,
+            &Path::new("shaders").join(name),
+            entry_point,
+            &defines,
+        ));
+    }
+    table.push_str("        _ => None,\n    }\n}\n");
+    fs::write(out_dir.join("precompiled_shaders.rs"), table)
+        .expect("could not write precompiled shader table");
+}
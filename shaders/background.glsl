@@ -0,0 +1,40 @@
+// Shared by the background effects in shaders/effects, which include it after enabling
+// GL_EXT_buffer_reference and implement main_image. Parameters are data1..data4, the built-in
+// inputs are read from PushConstants.uniforms.
+layout(local_size_x = 16, local_size_y = 16) in;
+
+layout(rgba16f, set = 0, binding = 0) uniform image2D image;
+
+layout(buffer_reference, std430) readonly buffer BackgroundUniforms {
+  mat4 view;
+  mat4 proj;
+  mat4 inverse_view_proj;
+  vec2 resolution;
+  float time;
+  uint frame;
+  // xy: cursor position in texels, z: 1 while the left button is held.
+  vec4 mouse;
+};
+
+layout(push_constant) uniform constants {
+  vec4 data1;
+  vec4 data2;
+  vec4 data3;
+  vec4 data4;
+  BackgroundUniforms uniforms;
+}
+PushConstants;
+
+// frag_coord is the texel center, with the origin at the top left.
+void main_image(out vec4 color, in vec2 frag_coord);
+
+void main() {
+  ivec2 texel_coord = ivec2(gl_GlobalInvocationID.xy);
+  ivec2 size = ivec2(PushConstants.uniforms.resolution);
+
+  if (texel_coord.x < size.x && texel_coord.y < size.y) {
+    vec4 color;
+    main_image(color, vec2(texel_coord) + 0.5);
+    imageStore(image, texel_coord, color);
+  }
+}
@@ -0,0 +1,24 @@
+#version 450
+
+// Same as colored_triangle_mesh.vert, with vertices coming from vertex input bindings instead of
+// a buffer device address.
+
+layout(location = 0) in vec3 in_pos;
+layout(location = 1) in float in_uv_x;
+layout(location = 2) in vec3 in_normal;
+layout(location = 3) in float in_uv_y;
+layout(location = 4) in vec4 in_color;
+
+layout(location = 0) out vec3 out_color;
+layout(location = 1) out vec2 out_uv;
+
+layout(push_constant) uniform constants {
+  mat4 render_matrix;
+}
+PushConstants;
+
+void main() {
+  gl_Position = PushConstants.render_matrix * vec4(in_pos, 1.0);
+  out_color = in_color.xyz;
+  out_uv = vec2(in_uv_x, in_uv_y);
+}
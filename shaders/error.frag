@@ -0,0 +1,11 @@
+#version 450
+
+// Fallback used in dev mode while the real fragment shader does not compile.
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+  ivec2 cell = ivec2(gl_FragCoord.xy) / 16;
+  bool magenta = ((cell.x ^ cell.y) & 1) != 0;
+  out_color = magenta ? vec4(1.0, 0.0, 1.0, 1.0) : vec4(0.0, 0.0, 0.0, 1.0);
+}
@@ -0,0 +1,43 @@
+#version 450
+#extension GL_EXT_buffer_reference : require
+
+// Fallback used in dev mode while the real vertex shader does not compile. Deliberately
+// self-contained so that a broken include cannot break it too.
+
+struct Vertex {
+  vec3 pos;
+  float uv_x;
+  vec3 normal;
+  float uv_y;
+  vec4 color;
+};
+
+layout(buffer_reference, std430) readonly buffer VertexBuffer {
+  Vertex vertices[];
+};
+layout(push_constant) uniform PushConstants {
+  mat4 render_matrix;
+  VertexBuffer vertex_buffer;
+  vec2 pad;
+}
+push_constants;
+
+#ifdef USE_SCENE_DATA
+layout(set = 0, binding = 0) uniform SceneData {
+  mat4 view;
+  mat4 proj;
+  mat4 view_proj;
+  vec4 ambient_color;
+  vec4 sun_direction;
+  vec4 sun_color;
+}
+scene_data;
+#endif
+
+void main() {
+  Vertex v = push_constants.vertex_buffer.vertices[gl_VertexIndex];
+  gl_Position = push_constants.render_matrix * vec4(v.pos, 1.0);
+#ifdef USE_SCENE_DATA
+  gl_Position = scene_data.view_proj * gl_Position;
+#endif
+}
@@ -0,0 +1,21 @@
+// Horizontal counterpart of gradient_color.comp, written in HLSL.
+
+struct PushConstants {
+  float4 data1;
+  float4 data2;
+  float4 data3;
+  float4 data4;
+};
+
+[[vk::push_constant]] PushConstants push_constants;
+[[vk::binding(0, 0)]] [[vk::image_format("rgba16f")]] RWTexture2D<float4> image;
+
+[numthreads(16, 16, 1)]
+void CSMain(uint3 thread_id : SV_DispatchThreadID) {
+  uint width, height;
+  image.GetDimensions(width, height);
+  if (thread_id.x < width && thread_id.y < height) {
+    float blend = float(thread_id.x) / float(width);
+    image[thread_id.xy] = lerp(push_constants.data1, push_constants.data2, blend);
+  }
+}
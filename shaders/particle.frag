@@ -0,0 +1,11 @@
+#version 450
+layout(location = 0) in vec4 in_color;
+layout(location = 1) in vec2 in_uv;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+  // Soft round sprite, blended additively.
+  float falloff = max(1.0 - dot(in_uv, in_uv), 0.0);
+  out_color = vec4(in_color.rgb, in_color.a * falloff * falloff);
+}
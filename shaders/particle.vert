@@ -0,0 +1,38 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+#extension GL_EXT_buffer_reference : require
+#include "particles.glsl"
+
+layout(location = 0) out vec4 out_color;
+layout(location = 1) out vec2 out_uv;
+
+layout(push_constant) uniform constants {
+  mat4 view_proj;
+  // xyz: camera right in world space, w: particle size.
+  vec4 camera_right;
+  vec4 camera_up;
+  vec4 end_color;
+  ParticleBuffer particle_buffer;
+}
+PushConstants;
+
+// Six vertices per particle, two triangles facing the camera.
+const vec2 CORNERS[6] = vec2[](vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+                               vec2(-1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0));
+
+void main() {
+  Particle particle = PushConstants.particle_buffer.particles[gl_VertexIndex / 6];
+  vec2 corner = CORNERS[gl_VertexIndex % 6];
+  out_uv = corner;
+  float lifetime = particle.velocity_lifetime.w;
+  if (lifetime <= 0.0) {
+    // Outside the clip volume, so dead particles are clipped away.
+    gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+    out_color = vec4(0.0);
+    return;
+  }
+  out_color = mix(particle.color, PushConstants.end_color, particle.position_age.w / lifetime);
+  float size = PushConstants.camera_right.w;
+  vec3 offset = (PushConstants.camera_right.xyz * corner.x + PushConstants.camera_up.xyz * corner.y) * size;
+  gl_Position = PushConstants.view_proj * vec4(particle.position_age.xyz + offset, 1.0);
+}
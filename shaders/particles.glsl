@@ -0,0 +1,31 @@
+// Particle state shared by the particle compute passes and particle.vert. A particle is dead when
+// its lifetime is 0.
+struct Particle {
+  // xyz: world position, w: age in seconds.
+  vec4 position_age;
+  // xyz: velocity, w: lifetime in seconds.
+  vec4 velocity_lifetime;
+  vec4 color;
+};
+
+layout(buffer_reference, std430) buffer ParticleBuffer {
+  Particle particles[];
+};
+
+const uint PARTICLE_WORKGROUP_SIZE = 64;
+
+// Indices of the live particles, starting with the `VkDispatchIndirectCommand` that updates them.
+layout(buffer_reference, std430) buffer AliveList {
+  uint groups_x;
+  uint groups_y;
+  uint groups_z;
+  uint count;
+  uint indices[];
+};
+
+// Adds a particle to `list`, growing its dispatch to cover it.
+void append_alive(AliveList list, uint index) {
+  uint slot = atomicAdd(list.count, 1);
+  list.indices[slot] = index;
+  atomicMax(list.groups_x, slot / PARTICLE_WORKGROUP_SIZE + 1);
+}
@@ -0,0 +1,113 @@
+use ash::vk;
+
+use crate::{
+    frames::FRAMES_IN_FLIGHT,
+    timeline::Timeline,
+    utils::{QueueTransfer, create_cmd_buffer_info},
+    vulkan::Vulkan,
+};
+
+struct AsyncComputeFrame {
+    pool: vk::CommandPool,
+    cmd: vk::CommandBuffer,
+    /// Timeline value of the last submission from this slot.
+    submitted: u64,
+}
+
+/// Per frame command buffers for the dedicated compute queue, so compute work such as particle
+/// simulation overlaps with the graphics queue. Resources used by both queues are handed over with
+/// [`Self::transfer`], released on the queue that used them last and acquired on the other.
+pub struct AsyncCompute {
+    queue: vk::Queue,
+    transfer: QueueTransfer,
+    frames: Vec<AsyncComputeFrame>,
+    /// Signaled by every compute submission.
+    timeline: Timeline,
+}
+
+impl AsyncCompute {
+    /// `None` when the device has no dedicated compute queue.
+    pub fn new(vulkan: &Vulkan) -> eyre::Result<Option<Self>> {
+        let Some(queue) = vulkan.compute_queue() else {
+            return Ok(None);
+        };
+        let device = vulkan.device();
+        let families = vulkan.queue_family_indices();
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(families.compute);
+        let frames = (0..FRAMES_IN_FLIGHT)
+            .map(|_| {
+                let pool = unsafe { device.create_command_pool(&pool_info, None) }?;
+                let buffer_info = create_cmd_buffer_info().pool(pool).call();
+                let cmd = unsafe { device.allocate_command_buffers(&buffer_info) }?[0];
+                Ok(AsyncComputeFrame {
+                    pool,
+                    cmd,
+                    submitted: 0,
+                })
+            })
+            .collect::<eyre::Result<_>>()?;
+        tracing::info!("async compute on queue family {}", families.compute);
+        Ok(Some(Self {
+            queue,
+            transfer: QueueTransfer::new(families.compute, families.graphics),
+            frames,
+            timeline: Timeline::new(device)?,
+        }))
+    }
+    /// Ownership transfer from the compute to the graphics queue family,
+    /// [`QueueTransfer::reversed`] for the way back.
+    pub const fn transfer(&self) -> QueueTransfer {
+        self.transfer
+    }
+    /// Starts recording the compute commands of frame slot `frame`. The graphics submission of
+    /// the slot waited on the previous ones, so this only blocks if that one never came.
+    pub fn begin(&self, device: &ash::Device, frame: usize) -> eyre::Result<vk::CommandBuffer> {
+        let frame = &self.frames[frame];
+        self.timeline.wait(device, frame.submitted)?;
+        unsafe { device.reset_command_buffer(frame.cmd, vk::CommandBufferResetFlags::empty()) }?;
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe { device.begin_command_buffer(frame.cmd, &begin_info) }?;
+        Ok(frame.cmd)
+    }
+    /// Submits the commands recorded since [`Self::begin`] once `graphics` is signaled, which
+    /// should be the last graphics submission, done with the resources it took over. Returns the
+    /// timeline value the submission signals.
+    pub fn submit(
+        &mut self,
+        device: &ash::Device,
+        frame: usize,
+        graphics: vk::SemaphoreSubmitInfo,
+    ) -> eyre::Result<u64> {
+        let value = self.timeline.next();
+        let frame = &mut self.frames[frame];
+        frame.submitted = value;
+        unsafe { device.end_command_buffer(frame.cmd) }?;
+        let cmd_infos = [vk::CommandBufferSubmitInfo::default()
+            .command_buffer(frame.cmd)
+            .device_mask(0)];
+        let wait_infos = [graphics];
+        let signal_infos = [self
+            .timeline
+            .signal_info(vk::PipelineStageFlags2::ALL_COMMANDS, value)];
+        let submit_info = vk::SubmitInfo2::default()
+            .wait_semaphore_infos(&wait_infos)
+            .signal_semaphore_infos(&signal_infos)
+            .command_buffer_infos(&cmd_infos);
+        unsafe { device.queue_submit2(self.queue, &[submit_info], vk::Fence::null()) }?;
+        Ok(value)
+    }
+    /// Makes a graphics submission wait for the last compute submission at `stage`, which has to
+    /// cover the stages acquiring resources from the compute queue.
+    pub fn wait_info(&self, stage: vk::PipelineStageFlags2) -> vk::SemaphoreSubmitInfo<'static> {
+        self.timeline.wait_info(stage, self.timeline.last())
+    }
+    pub fn destroy(&self, device: &ash::Device) {
+        for frame in &self.frames {
+            unsafe { device.destroy_command_pool(frame.pool, None) };
+        }
+        self.timeline.destroy(device);
+    }
+}
@@ -13,6 +13,17 @@ impl AllocatedBuffer {
         size: u64,
         usage: vk::BufferUsageFlags,
         mem_usage: vk_mem::MemoryUsage,
+    ) -> eyre::Result<Self> {
+        Self::shared(allocator, size, usage, mem_usage, &[])
+    }
+    /// Shared by `queue_families` without ownership transfers when there are several of them,
+    /// owned by one family at a time otherwise.
+    pub fn shared(
+        allocator: &vk_mem::Allocator,
+        size: u64,
+        usage: vk::BufferUsageFlags,
+        mem_usage: vk_mem::MemoryUsage,
+        queue_families: &[u32],
     ) -> eyre::Result<Self> {
         let additional_flags = match mem_usage {
             vk_mem::MemoryUsage::Auto
@@ -23,7 +34,12 @@ impl AllocatedBuffer {
 
             _ => vk_mem::AllocationCreateFlags::empty(),
         };
-        let info = vk::BufferCreateInfo::default().usage(usage).size(size);
+        let mut info = vk::BufferCreateInfo::default().usage(usage).size(size);
+        if queue_families.len() > 1 {
+            info = info
+                .sharing_mode(vk::SharingMode::CONCURRENT)
+                .queue_family_indices(queue_families);
+        }
         let alloc_info = vk_mem::AllocationCreateInfo {
             usage: mem_usage,
             flags: vk_mem::AllocationCreateFlags::MAPPED | additional_flags,
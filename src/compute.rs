@@ -1,120 +1,609 @@
+use std::marker::PhantomData;
+
 use ash::vk;
 use eyre::eyre;
 use glam::Vec4;
 
-use crate::{shader::ShaderCompiler, texture::DrawImage};
+use crate::{
+    descriptors::{BoundDescriptor, DescriptorBackend, DescriptorBufferAllocator},
+    effect_parameters::{EffectParameter, EffectPresets, ParameterSchema, ParameterType},
+    effects::load_effects,
+    shader::{ShaderCompiler, ShaderDependencies, ShaderKind, ShaderSource},
+    shader_errors::ShaderErrors,
+    specialization::SpecializationConstants,
+    texture::DrawImage,
+    utils::image_subresource_range,
+};
 const RED: Vec4 = Vec4::new(1.0, 0.0, 0.0, 1.0);
 const BLUE: Vec4 = Vec4::new(0.0, 0.0, 1.0, 1.0);
 const BLACK: Vec4 = Vec4::ZERO;
+const FALLBACK_SHADER: &str = "error.comp";
 
+/// A background drawn by a compute shader writing the [`DrawImage`], tweaked through
+/// [`ComputePushConstants`].
 pub struct ComputeEffect {
-    name: String,
-    pipeline: vk::Pipeline,
-    layout: vk::PipelineLayout,
+    pipeline: ComputePipeline<BackgroundPushConstants>,
     pub data: ComputePushConstants,
+    parameters: ParameterSchema,
+    presets: EffectPresets,
+}
+
+/// [`ComputePushConstants`] followed by the address of the frame's
+/// [`BackgroundUniforms`](crate::effects::BackgroundUniforms).
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct BackgroundPushConstants {
+    data: ComputePushConstants,
+    uniforms: vk::DeviceAddress,
+    _pad: u64,
 }
 
 pub fn create_compute_effects(
     device: &ash::Device,
     draw_image: &DrawImage,
     shader_compiler: &ShaderCompiler,
+    descriptor_backend: DescriptorBackend,
+    pipeline_cache: vk::PipelineCache,
+    shader_errors: &mut ShaderErrors,
 ) -> eyre::Result<Vec<ComputeEffect>> {
-    let gradient_effect = {
-        let src = include_str!("../shaders/gradient_color.comp");
-        let module = shader_compiler.create_shader_module_from_str(
-            device,
-            src,
-            shaderc::ShaderKind::Compute,
-            "gradient_color.comp",
-            "main",
-        )?;
-        ComputeEffect::new(
-            device,
-            draw_image,
-            "Gradient Color",
-            module,
-            ComputePushConstants::new(RED, BLUE, BLACK, BLACK),
-        )?
-    };
-
-    let sky = {
-        let src = include_str!("../shaders/sky.comp");
-        let module = shader_compiler.create_shader_module_from_str(
-            device,
-            src,
-            shaderc::ShaderKind::Compute,
-            "gradient_color.comp",
-            "main",
-        )?;
-        ComputeEffect::new(
-            device,
-            draw_image,
-            "Sky",
-            module,
-            ComputePushConstants::new(Vec4::new(0.1, 0.2, 0.4, 0.97), BLACK, BLACK, BLACK),
-        )?
-    };
-    Ok(vec![gradient_effect, sky])
+    let gradient_parameters = ParameterSchema::new(vec![
+        EffectParameter::new("Top Color", ParameterType::Color),
+        EffectParameter::new("Bottom Color", ParameterType::Color),
+    ])?;
+    let gradient_effect = ComputeEffect::new(
+        device,
+        shader_compiler,
+        draw_image,
+        "Gradient Color",
+        "gradient_color.comp",
+        SpecializationConstants::new(),
+        ComputePushConstants::new(RED, BLUE, BLACK, BLACK),
+        descriptor_backend,
+        pipeline_cache,
+        shader_errors,
+    )?
+    .with_parameters(gradient_parameters.clone());
+
+    let sky = ComputeEffect::new(
+        device,
+        shader_compiler,
+        draw_image,
+        "Sky",
+        "sky.comp",
+        SpecializationConstants::new(),
+        ComputePushConstants::new(Vec4::new(0.1, 0.2, 0.4, 0.97), BLACK, BLACK, BLACK),
+        descriptor_backend,
+        pipeline_cache,
+        shader_errors,
+    )?
+    .with_parameters(ParameterSchema::new(vec![
+        EffectParameter::new("Sky Color", ParameterType::Vec3).range(0.0, 1.0),
+        EffectParameter::new("Star Threshold", ParameterType::Float).range(0.99, 0.9999),
+    ])?);
+
+    let hlsl_gradient = ComputeEffect::new(
+        device,
+        shader_compiler,
+        draw_image,
+        "Gradient (HLSL)",
+        ShaderSource::new("gradient.comp.hlsl").with_entry_point("CSMain"),
+        SpecializationConstants::new(),
+        ComputePushConstants::new(RED, BLUE, BLACK, BLACK),
+        descriptor_backend,
+        pipeline_cache,
+        shader_errors,
+    )?
+    .with_parameters(ParameterSchema::new(vec![
+        EffectParameter::new("Left Color", ParameterType::Color),
+        EffectParameter::new("Right Color", ParameterType::Color),
+    ])?);
+    // Same SPIR-V as "Gradient Color", with the grid specialized away.
+    let smooth_gradient = ComputeEffect::new(
+        device,
+        shader_compiler,
+        draw_image,
+        "Gradient Color (No Grid)",
+        "gradient_color.comp",
+        SpecializationConstants::new().set(0, false),
+        ComputePushConstants::new(RED, BLUE, BLACK, BLACK),
+        descriptor_backend,
+        pipeline_cache,
+        shader_errors,
+    )?
+    .with_parameters(gradient_parameters);
+    let mut effects = vec![gradient_effect, smooth_gradient, sky, hlsl_gradient];
+    effects.extend(load_effects(
+        device,
+        shader_compiler,
+        draw_image,
+        descriptor_backend,
+        pipeline_cache,
+        shader_errors,
+    ));
+    Ok(effects)
 }
 
 impl ComputeEffect {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
         draw_image: &DrawImage,
         name: impl Into<String>,
-        module: vk::ShaderModule,
+        shader: impl Into<ShaderSource>,
+        specialization: SpecializationConstants,
         data: ComputePushConstants,
+        descriptor_backend: DescriptorBackend,
+        pipeline_cache: vk::PipelineCache,
+        shader_errors: &mut ShaderErrors,
+    ) -> eyre::Result<Self> {
+        let name = name.into();
+        let presets = EffectPresets::load(&name).unwrap_or_else(|e| {
+            tracing::error!("could not load the presets of {name}: {e:?}");
+            shader_errors.report(&format!("Presets of {name}"), &e);
+            EffectPresets::empty(&name)
+        });
+        let pipeline = ComputePipeline::new(
+            device,
+            shader_compiler,
+            name,
+            shader,
+            specialization,
+            &[draw_image.descriptor_set_layout()],
+            descriptor_backend,
+            pipeline_cache,
+            Some((FALLBACK_SHADER, shader_errors)),
+        )?;
+        Ok(Self {
+            pipeline,
+            data,
+            parameters: ParameterSchema::untyped(),
+            presets,
+        })
+    }
+    /// Replaces the untyped `data1`..`data4` with `parameters`.
+    pub fn with_parameters(mut self, parameters: ParameterSchema) -> Self {
+        self.parameters = parameters;
+        self
+    }
+    /// Fills the first `extent` texels of `draw_image`, which must be in the `GENERAL` layout.
+    /// `uniforms` is the address of this frame's
+    /// [`BackgroundUniforms`](crate::effects::BackgroundUniforms).
+    pub fn draw(
+        &self,
+        device: &ash::Device,
+        descriptor_buffer: Option<&DescriptorBufferAllocator>,
+        cmd: vk::CommandBuffer,
+        draw_image: &DrawImage,
+        extent: vk::Extent2D,
+        uniforms: vk::DeviceAddress,
+    ) -> eyre::Result<()> {
+        let descriptors = [draw_image.descriptor()];
+        let resources = [ComputeResource::Image {
+            image: draw_image.image(),
+            layout: vk::ImageLayout::GENERAL,
+            access: ComputeAccess::Write,
+        }];
+        let dispatch = ComputeDispatch::builder()
+            .descriptors(&descriptors)
+            .push_constants(BackgroundPushConstants {
+                data: self.data,
+                uniforms,
+                _pad: 0,
+            })
+            .size(DispatchSize::covering(extent, [16, 16]))
+            .resources(&resources)
+            .build();
+        self.pipeline
+            .dispatch(device, descriptor_buffer, cmd, &dispatch)
+    }
+    pub fn reload(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        retire: impl FnOnce(vk::Pipeline),
+    ) -> eyre::Result<()> {
+        self.pipeline.reload(device, shader_compiler, retire)
+    }
+    pub fn destroy(&mut self, device: &ash::Device) {
+        self.pipeline.destroy(device);
+    }
+
+    pub fn name(&self) -> &str {
+        self.pipeline.name()
+    }
+
+    /// Edits the parameters, and saves them to or loads them from the presets of this effect.
+    /// `preset_name` is what the next save is called.
+    pub fn ui(&mut self, ui: &mut egui::Ui, preset_name: &mut String) -> eyre::Result<()> {
+        self.parameters.ui(ui, &mut self.data);
+        ui.separator();
+        let mut load = None;
+        egui::ComboBox::from_label("Preset")
+            .selected_text(preset_name.as_str())
+            .show_ui(ui, |ui| {
+                for name in self.presets.names() {
+                    if ui.selectable_label(name == preset_name, name).clicked() {
+                        load = Some(name.to_owned());
+                    }
+                }
+            });
+        let save = ui
+            .horizontal(|ui| {
+                ui.text_edit_singleline(preset_name);
+                let button = egui::Button::new("Save Preset");
+                ui.add_enabled(!preset_name.is_empty(), button).clicked()
+            })
+            .inner;
+        if let Some(name) = load {
+            if let Some(values) = self.presets.get(&name) {
+                self.parameters.apply(values, &mut self.data)?;
+            }
+            *preset_name = name;
+        }
+        if save {
+            self.presets
+                .save(preset_name, self.parameters.values(&self.data))?;
+        }
+        Ok(())
+    }
+
+    pub const fn dependencies(&self) -> &ShaderDependencies {
+        self.pipeline.dependencies()
+    }
+}
+
+/// A compute shader with any descriptor set layouts and push constants of type `P`, `()` for
+/// none.
+pub struct ComputePipeline<P> {
+    name: String,
+    shader: ShaderSource,
+    specialization: SpecializationConstants,
+    dependencies: ShaderDependencies,
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    descriptor_backend: DescriptorBackend,
+    pipeline_cache: vk::PipelineCache,
+    push_constants: PhantomData<P>,
+}
+
+impl<P: bytemuck::Pod> ComputePipeline<P> {
+    /// Builds the pipeline. With a `fallback`, a `shader` that does not compile is reported and
+    /// replaced by the fallback shader in dev builds, which has to fit the same `set_layouts`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        name: impl Into<String>,
+        shader: impl Into<ShaderSource>,
+        specialization: SpecializationConstants,
+        set_layouts: &[vk::DescriptorSetLayout],
+        descriptor_backend: DescriptorBackend,
+        pipeline_cache: vk::PipelineCache,
+        fallback: Option<(&str, &mut ShaderErrors)>,
     ) -> eyre::Result<Self> {
         let push_constant = vk::PushConstantRange::default()
             .offset(0)
-            .size(std::mem::size_of::<ComputePushConstants>() as u32)
+            .size(std::mem::size_of::<P>() as u32)
             .stage_flags(vk::ShaderStageFlags::COMPUTE);
-        let layouts = [draw_image.descriptor_set_layout()];
-        let push_constants = [push_constant];
+        let push_constants: &[_] = if std::mem::size_of::<P>() == 0 {
+            &[]
+        } else {
+            &[push_constant]
+        };
         let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
-            .set_layouts(&layouts)
-            .push_constant_ranges(&push_constants);
+            .set_layouts(set_layouts)
+            .push_constant_ranges(push_constants);
         let layout = unsafe { device.create_pipeline_layout(&pipeline_layout_info, None) }?;
 
+        let name = name.into();
+        let shader = shader.into();
+        let created = Self::create_pipeline(
+            device,
+            shader_compiler,
+            &shader,
+            &specialization,
+            layout,
+            descriptor_backend,
+            pipeline_cache,
+        );
+        let created = match fallback {
+            Some((fallback_shader, shader_errors)) => {
+                shader_errors.or_fallback(&name, created, || {
+                    let (pipeline, _) = Self::create_pipeline(
+                        device,
+                        shader_compiler,
+                        &ShaderSource::new(fallback_shader),
+                        &SpecializationConstants::new(),
+                        layout,
+                        descriptor_backend,
+                        pipeline_cache,
+                    )?;
+                    Ok((pipeline, ShaderDependencies::unknown()))
+                })
+            }
+            None => created,
+        };
+        let (pipeline, dependencies) = match created {
+            Ok(created) => created,
+            Err(e) => {
+                unsafe { device.destroy_pipeline_layout(layout, None) };
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            name,
+            shader,
+            specialization,
+            dependencies,
+            pipeline,
+            layout,
+            descriptor_backend,
+            pipeline_cache,
+            push_constants: PhantomData,
+        })
+    }
+    fn create_pipeline(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        shader: &ShaderSource,
+        specialization: &SpecializationConstants,
+        layout: vk::PipelineLayout,
+        descriptor_backend: DescriptorBackend,
+        pipeline_cache: vk::PipelineCache,
+    ) -> eyre::Result<(vk::Pipeline, ShaderDependencies)> {
+        let entry_point = shader.entry_point_c()?;
+        let (module, dependencies) =
+            shader_compiler.load_shader_module(device, shader.clone(), ShaderKind::Compute)?;
+        let specialization_info = specialization.info();
         let stage = vk::PipelineShaderStageCreateInfo::default()
             .module(module)
             .stage(vk::ShaderStageFlags::COMPUTE)
-            .name(c"main");
+            .name(&entry_point)
+            .specialization_info(&specialization_info);
 
         let info = vk::ComputePipelineCreateInfo::default()
+            .flags(descriptor_backend.pipeline_create_flags())
             .layout(layout)
             .stage(stage);
 
-        let pipeline = match unsafe {
-            device.create_compute_pipelines(vk::PipelineCache::null(), &[info], None)
-        } {
-            Ok(pipelines) => pipelines[0],
-            Err((_, e)) => return Err(eyre!("{e}")),
-        };
-
+        let pipeline = unsafe { device.create_compute_pipelines(pipeline_cache, &[info], None) };
         unsafe { device.destroy_shader_module(module, None) };
-        Ok(Self {
-            name: name.into(),
-            pipeline,
-            layout,
-            data,
-        })
+        match pipeline {
+            Ok(pipelines) => Ok((pipelines[0], dependencies)),
+            Err((_, e)) => Err(eyre!("{e}")),
+        }
+    }
+    /// Recompiles the shader and swaps in the new pipeline, handing the old one to `retire`. On
+    /// failure the current pipeline stays in use.
+    pub fn reload(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        retire: impl FnOnce(vk::Pipeline),
+    ) -> eyre::Result<()> {
+        let (pipeline, dependencies) = Self::create_pipeline(
+            device,
+            shader_compiler,
+            &self.shader,
+            &self.specialization,
+            self.layout,
+            self.descriptor_backend,
+            self.pipeline_cache,
+        )?;
+        retire(std::mem::replace(&mut self.pipeline, pipeline));
+        self.dependencies = dependencies;
+        Ok(())
+    }
+    /// Records `dispatch` between barriers making earlier writes to its resources visible to the
+    /// shader, and the shader writes visible to whatever comes next.
+    pub fn dispatch(
+        &self,
+        device: &ash::Device,
+        descriptor_buffer: Option<&DescriptorBufferAllocator>,
+        cmd: vk::CommandBuffer,
+        dispatch: &ComputeDispatch<P>,
+    ) -> eyre::Result<()> {
+        dispatch.barrier(device, cmd, Barrier::Before);
+        unsafe { device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline) };
+        for (descriptor, set) in dispatch.descriptors.iter().zip(0..) {
+            descriptor.bind(
+                device,
+                descriptor_buffer,
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.layout,
+                set,
+            )?;
+        }
+        if let Some(push_constants) = &dispatch.push_constants {
+            unsafe {
+                device.cmd_push_constants(
+                    cmd,
+                    self.layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    bytemuck::bytes_of(push_constants),
+                );
+            };
+        }
+        match dispatch.size {
+            DispatchSize::Direct([x, y, z]) => unsafe { device.cmd_dispatch(cmd, x, y, z) },
+            DispatchSize::Indirect { buffer, offset } => unsafe {
+                device.cmd_dispatch_indirect(cmd, buffer, offset);
+            },
+        }
+        dispatch.barrier(device, cmd, Barrier::After);
+        Ok(())
     }
     pub fn destroy(&mut self, device: &ash::Device) {
         unsafe { device.destroy_pipeline_layout(self.layout, None) };
         unsafe { device.destroy_pipeline(self.pipeline, None) };
     }
 
-    pub const fn pipeline(&self) -> vk::Pipeline {
-        self.pipeline
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    pub const fn layout(&self) -> vk::PipelineLayout {
-        self.layout
+    pub const fn dependencies(&self) -> &ShaderDependencies {
+        &self.dependencies
     }
+}
 
-    pub fn name(&self) -> &str {
-        &self.name
+/// Number of workgroups to run.
+#[derive(Debug, Clone, Copy)]
+pub enum DispatchSize {
+    Direct([u32; 3]),
+    /// Read from a `vk::DispatchIndirectCommand` in `buffer`, e.g. written by a culling pass.
+    Indirect {
+        buffer: vk::Buffer,
+        offset: u64,
+    },
+}
+
+impl DispatchSize {
+    /// Enough workgroups of `local_size` invocations to cover every texel of `extent`.
+    pub const fn covering(extent: vk::Extent2D, local_size: [u32; 2]) -> Self {
+        Self::Direct([
+            extent.width.div_ceil(local_size[0]),
+            extent.height.div_ceil(local_size[1]),
+            1,
+        ])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComputeAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl ComputeAccess {
+    fn shader_access(self) -> vk::AccessFlags2 {
+        match self {
+            Self::Read => vk::AccessFlags2::SHADER_READ,
+            Self::Write => vk::AccessFlags2::SHADER_STORAGE_WRITE,
+            Self::ReadWrite => {
+                vk::AccessFlags2::SHADER_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE
+            }
+        }
+    }
+    const fn written(self) -> vk::AccessFlags2 {
+        match self {
+            Self::Read => vk::AccessFlags2::NONE,
+            Self::Write | Self::ReadWrite => vk::AccessFlags2::SHADER_STORAGE_WRITE,
+        }
+    }
+}
+
+/// A buffer or image a dispatch accesses, synchronized with the commands around it.
+#[derive(Debug, Clone, Copy)]
+pub enum ComputeResource {
+    Buffer {
+        buffer: vk::Buffer,
+        access: ComputeAccess,
+    },
+    /// Stays in `layout`, transitions are up to the caller.
+    Image {
+        image: vk::Image,
+        layout: vk::ImageLayout,
+        access: ComputeAccess,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Barrier {
+    Before,
+    After,
+}
+
+/// Everything [`ComputePipeline::dispatch`] records besides the pipeline itself.
+#[derive(bon::Builder)]
+pub struct ComputeDispatch<'a, P> {
+    /// Bound to sets `0..`.
+    #[builder(default)]
+    descriptors: &'a [BoundDescriptor],
+    push_constants: Option<P>,
+    size: DispatchSize,
+    #[builder(default)]
+    resources: &'a [ComputeResource],
+}
+
+impl<P> ComputeDispatch<'_, P> {
+    fn barrier(&self, device: &ash::Device, cmd: vk::CommandBuffer, barrier: Barrier) {
+        // Anything may have written the resources before and may access them after.
+        let compute = vk::PipelineStageFlags2::COMPUTE_SHADER;
+        let all = vk::PipelineStageFlags2::ALL_COMMANDS;
+        let stages = |access: ComputeAccess| match barrier {
+            Barrier::Before => (
+                (all, vk::AccessFlags2::MEMORY_WRITE),
+                (compute, access.shader_access()),
+            ),
+            Barrier::After => (
+                (compute, access.written()),
+                (
+                    all,
+                    vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE,
+                ),
+            ),
+        };
+        let mut buffer_barriers = vec![];
+        let mut image_barriers = vec![];
+        for resource in self.resources {
+            match *resource {
+                ComputeResource::Buffer { buffer, access } => {
+                    let ((src_stage, src_access), (dst_stage, dst_access)) = stages(access);
+                    buffer_barriers.push(
+                        vk::BufferMemoryBarrier2::default()
+                            .src_stage_mask(src_stage)
+                            .src_access_mask(src_access)
+                            .dst_stage_mask(dst_stage)
+                            .dst_access_mask(dst_access)
+                            .buffer(buffer)
+                            .size(vk::WHOLE_SIZE),
+                    );
+                }
+                ComputeResource::Image {
+                    image,
+                    layout,
+                    access,
+                } => {
+                    let ((src_stage, src_access), (dst_stage, dst_access)) = stages(access);
+                    image_barriers.push(
+                        vk::ImageMemoryBarrier2::default()
+                            .src_stage_mask(src_stage)
+                            .src_access_mask(src_access)
+                            .dst_stage_mask(dst_stage)
+                            .dst_access_mask(dst_access)
+                            .old_layout(layout)
+                            .new_layout(layout)
+                            .image(image)
+                            .subresource_range(image_subresource_range(
+                                vk::ImageAspectFlags::COLOR,
+                            )),
+                    );
+                }
+            }
+        }
+        if let (Barrier::Before, DispatchSize::Indirect { buffer, .. }) = (barrier, self.size) {
+            buffer_barriers.push(
+                vk::BufferMemoryBarrier2::default()
+                    .src_stage_mask(all)
+                    .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2::DRAW_INDIRECT)
+                    .dst_access_mask(vk::AccessFlags2::INDIRECT_COMMAND_READ)
+                    .buffer(buffer)
+                    .size(vk::WHOLE_SIZE),
+            );
+        }
+        if buffer_barriers.is_empty() && image_barriers.is_empty() {
+            return;
+        }
+        let dependency = vk::DependencyInfo::default()
+            .buffer_memory_barriers(&buffer_barriers)
+            .image_memory_barriers(&image_barriers);
+        unsafe { device.cmd_pipeline_barrier2(cmd, &dependency) };
     }
 }
 
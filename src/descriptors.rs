@@ -1,8 +1,39 @@
 use ash::vk::{self, ExtendsDescriptorSetAllocateInfo, ExtendsDescriptorSetLayoutCreateInfo};
-use eyre::eyre;
+use eyre::{OptionExt, eyre};
+
+use crate::{buffer::AllocatedBuffer, vulkan::Vulkan};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorBackend {
+    Pool,
+    Buffer,
+}
+
+impl DescriptorBackend {
+    /// Descriptor buffers are opt-in through `VKGUIDE_DESCRIPTOR_BUFFER=1`.
+    pub fn preferred() -> Self {
+        match std::env::var("VKGUIDE_DESCRIPTOR_BUFFER").as_deref() {
+            Ok("1" | "true") => Self::Buffer,
+            _ => Self::Pool,
+        }
+    }
+    pub const fn layout_create_flags(self) -> vk::DescriptorSetLayoutCreateFlags {
+        match self {
+            Self::Pool => vk::DescriptorSetLayoutCreateFlags::empty(),
+            Self::Buffer => vk::DescriptorSetLayoutCreateFlags::DESCRIPTOR_BUFFER_EXT,
+        }
+    }
+    pub const fn pipeline_create_flags(self) -> vk::PipelineCreateFlags {
+        match self {
+            Self::Pool => vk::PipelineCreateFlags::empty(),
+            Self::Buffer => vk::PipelineCreateFlags::DESCRIPTOR_BUFFER_EXT,
+        }
+    }
+}
 
 pub struct DescriptorLayoutBuilder<'a, 'b> {
     bindings: Vec<vk::DescriptorSetLayoutBinding<'a>>,
+    flags: vk::DescriptorSetLayoutCreateFlags,
     next: Option<&'b mut dyn ExtendsDescriptorSetLayoutCreateInfo>,
 }
 
@@ -10,6 +41,7 @@ impl<'b> DescriptorLayoutBuilder<'_, 'b> {
     pub const fn new() -> Self {
         Self {
             bindings: vec![],
+            flags: vk::DescriptorSetLayoutCreateFlags::empty(),
             next: None,
         }
     }
@@ -23,6 +55,10 @@ impl<'b> DescriptorLayoutBuilder<'_, 'b> {
         );
         self
     }
+    pub const fn flags(mut self, flags: vk::DescriptorSetLayoutCreateFlags) -> Self {
+        self.flags = flags;
+        self
+    }
     pub fn push_next<T: ExtendsDescriptorSetLayoutCreateInfo + Sized>(
         mut self,
         next: &'b mut T,
@@ -42,7 +78,9 @@ impl<'b> DescriptorLayoutBuilder<'_, 'b> {
             b.stage_flags |= shader_stage;
         }
 
-        let mut info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&self.bindings);
+        let mut info = vk::DescriptorSetLayoutCreateInfo::default()
+            .flags(self.flags)
+            .bindings(&self.bindings);
         if let Some(next) = self.next {
             info = info.push_next(next);
         }
@@ -125,6 +163,49 @@ impl DescriptorWriter<'_> {
         }
         unsafe { device.update_descriptor_sets(&self.writes, &[]) };
     }
+    /// Writes the descriptors into `descriptor`, which lives in `descriptor_buffer` when it is a
+    /// [`BoundDescriptor::Buffer`]. Buffers written into a descriptor buffer need
+    /// `SHADER_DEVICE_ADDRESS` usage.
+    pub fn update(
+        self,
+        device: &ash::Device,
+        descriptor_buffer: Option<&DescriptorBufferAllocator>,
+        descriptor: BoundDescriptor,
+    ) -> eyre::Result<()> {
+        let set = match descriptor {
+            BoundDescriptor::Set(set) => {
+                self.update_set(device, set);
+                return Ok(());
+            }
+            BoundDescriptor::Buffer(set) => set,
+        };
+        let descriptor_buffer = descriptor_buffer.ok_or_eyre("missing descriptor buffer")?;
+        for write in &self.writes {
+            if write.p_image_info.is_null() {
+                let info = unsafe { &*write.p_buffer_info };
+                let address_info = vk::BufferDeviceAddressInfo::default().buffer(info.buffer);
+                let address = unsafe { device.get_buffer_device_address(&address_info) };
+                descriptor_buffer.write_buffer(
+                    set,
+                    write.dst_binding,
+                    address + info.offset,
+                    info.range,
+                    write.descriptor_type,
+                )?;
+            } else {
+                let info = unsafe { &*write.p_image_info };
+                descriptor_buffer.write_image(
+                    set,
+                    write.dst_binding,
+                    info.image_view,
+                    info.sampler,
+                    info.image_layout,
+                    write.descriptor_type,
+                )?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -338,3 +419,263 @@ impl DescriptorAllocator {
         unsafe { device.allocate_descriptor_sets(&info) }
     }
 }
+
+/// A descriptor set living inside a [`DescriptorBufferAllocator`].
+#[derive(Clone, Copy)]
+pub struct DescriptorBufferSet {
+    layout: vk::DescriptorSetLayout,
+    offset: u64,
+}
+
+/// Linear allocator writing descriptors straight into a host visible, device addressable
+/// buffer through `VK_EXT_descriptor_buffer`. Sets written every frame come from one region per
+/// frame in flight at the end of the buffer, reset once the frame that used them finished.
+pub struct DescriptorBufferAllocator {
+    loader: ash::ext::descriptor_buffer::Device,
+    props: vk::PhysicalDeviceDescriptorBufferPropertiesEXT<'static>,
+    buffer: AllocatedBuffer,
+    address: vk::DeviceAddress,
+    usage: vk::BufferUsageFlags,
+    /// End of the sets allocated with [`Self::allocate`], where the frame regions start.
+    size: u64,
+    head: u64,
+    frame_size: u64,
+    frame_heads: Vec<u64>,
+}
+
+impl DescriptorBufferAllocator {
+    pub fn new(
+        vulkan: &Vulkan,
+        allocator: &vk_mem::Allocator,
+        size: u64,
+        frame_size: u64,
+        frame_count: usize,
+        usage: vk::BufferUsageFlags,
+    ) -> eyre::Result<Self> {
+        let buffer = AllocatedBuffer::new(
+            allocator,
+            size + frame_size * frame_count as u64,
+            usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            vk_mem::MemoryUsage::AutoPreferDevice,
+        )?;
+        let address_info = vk::BufferDeviceAddressInfo::default().buffer(buffer.buffer());
+        let address = unsafe { vulkan.device().get_buffer_device_address(&address_info) };
+        let frame_heads = (0..frame_count as u64)
+            .map(|frame| size + frame * frame_size)
+            .collect();
+        Ok(Self {
+            loader: vulkan.descriptor_buffer_device(),
+            props: vulkan.descriptor_buffer_properties(),
+            buffer,
+            address,
+            usage,
+            size,
+            head: 0,
+            frame_size,
+            frame_heads,
+        })
+    }
+    pub fn destroy(&mut self, allocator: &vk_mem::Allocator) {
+        self.buffer.destroy(allocator);
+    }
+    /// Frees the sets of `frame`, which must not be used by the device anymore.
+    pub fn reset(&mut self, frame: usize) {
+        self.frame_heads[frame] = self.size + frame as u64 * self.frame_size;
+    }
+    /// Allocates a set living as long as the allocator.
+    pub fn allocate(
+        &mut self,
+        layout: vk::DescriptorSetLayout,
+    ) -> eyre::Result<DescriptorBufferSet> {
+        let (set, head) = self.allocate_range(layout, self.head, self.size)?;
+        self.head = head;
+        Ok(set)
+    }
+    /// Allocates a set living until `frame` is reset.
+    pub fn allocate_frame(
+        &mut self,
+        frame: usize,
+        layout: vk::DescriptorSetLayout,
+    ) -> eyre::Result<DescriptorBufferSet> {
+        let end = self.size + (frame as u64 + 1) * self.frame_size;
+        let (set, head) = self.allocate_range(layout, self.frame_heads[frame], end)?;
+        self.frame_heads[frame] = head;
+        Ok(set)
+    }
+    /// Places a set of `layout` at or after `head`, returning it and the new head.
+    fn allocate_range(
+        &self,
+        layout: vk::DescriptorSetLayout,
+        head: u64,
+        end: u64,
+    ) -> eyre::Result<(DescriptorBufferSet, u64)> {
+        let layout_size = unsafe { self.loader.get_descriptor_set_layout_size(layout) };
+        let offset = head.next_multiple_of(self.props.descriptor_buffer_offset_alignment);
+        if offset + layout_size > end {
+            return Err(eyre!("descriptor buffer out of memory"));
+        }
+        Ok((DescriptorBufferSet { layout, offset }, offset + layout_size))
+    }
+
+    fn descriptor_size(&self, descriptor_type: vk::DescriptorType) -> eyre::Result<usize> {
+        let size = match descriptor_type {
+            vk::DescriptorType::SAMPLER => self.props.sampler_descriptor_size,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER => {
+                self.props.combined_image_sampler_descriptor_size
+            }
+            vk::DescriptorType::SAMPLED_IMAGE => self.props.sampled_image_descriptor_size,
+            vk::DescriptorType::STORAGE_IMAGE => self.props.storage_image_descriptor_size,
+            vk::DescriptorType::UNIFORM_BUFFER => self.props.uniform_buffer_descriptor_size,
+            vk::DescriptorType::STORAGE_BUFFER => self.props.storage_buffer_descriptor_size,
+            _ => return Err(eyre!("unsupported descriptor type {descriptor_type:?}")),
+        };
+        Ok(size)
+    }
+    fn write(
+        &self,
+        set: DescriptorBufferSet,
+        binding: u32,
+        info: &vk::DescriptorGetInfoEXT<'_>,
+    ) -> eyre::Result<()> {
+        let size = self.descriptor_size(info.ty)?;
+        let binding_offset = unsafe {
+            self.loader
+                .get_descriptor_set_layout_binding_offset(set.layout, binding)
+        };
+        let mapped = self.buffer.alloc_info().mapped_data.cast::<u8>();
+        if mapped.is_null() {
+            return Err(eyre!("descriptor buffer is not host visible"));
+        }
+        let offset = usize::try_from(set.offset + binding_offset)?;
+        let descriptor = unsafe { std::slice::from_raw_parts_mut(mapped.add(offset), size) };
+        unsafe { self.loader.get_descriptor(info, descriptor) };
+        Ok(())
+    }
+    pub fn write_image(
+        &self,
+        set: DescriptorBufferSet,
+        binding: u32,
+        image_view: vk::ImageView,
+        sampler: vk::Sampler,
+        layout: vk::ImageLayout,
+        descriptor_type: vk::DescriptorType,
+    ) -> eyre::Result<()> {
+        let image_info = vk::DescriptorImageInfo::default()
+            .sampler(sampler)
+            .image_view(image_view)
+            .image_layout(layout);
+        let data = match descriptor_type {
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER => vk::DescriptorDataEXT {
+                p_combined_image_sampler: &raw const image_info,
+            },
+            vk::DescriptorType::SAMPLED_IMAGE => vk::DescriptorDataEXT {
+                p_sampled_image: &raw const image_info,
+            },
+            vk::DescriptorType::STORAGE_IMAGE => vk::DescriptorDataEXT {
+                p_storage_image: &raw const image_info,
+            },
+            _ => return Err(eyre!("{descriptor_type:?} is not an image descriptor")),
+        };
+        let info = vk::DescriptorGetInfoEXT::default()
+            .ty(descriptor_type)
+            .data(data);
+        self.write(set, binding, &info)
+    }
+    pub fn write_buffer(
+        &self,
+        set: DescriptorBufferSet,
+        binding: u32,
+        address: vk::DeviceAddress,
+        size: u64,
+        descriptor_type: vk::DescriptorType,
+    ) -> eyre::Result<()> {
+        let address_info = vk::DescriptorAddressInfoEXT::default()
+            .address(address)
+            .range(size);
+        let data = match descriptor_type {
+            vk::DescriptorType::UNIFORM_BUFFER => vk::DescriptorDataEXT {
+                p_uniform_buffer: &raw const address_info,
+            },
+            vk::DescriptorType::STORAGE_BUFFER => vk::DescriptorDataEXT {
+                p_storage_buffer: &raw const address_info,
+            },
+            _ => return Err(eyre!("{descriptor_type:?} is not a buffer descriptor")),
+        };
+        let info = vk::DescriptorGetInfoEXT::default()
+            .ty(descriptor_type)
+            .data(data);
+        self.write(set, binding, &info)
+    }
+    pub fn bind(
+        &self,
+        cmd: vk::CommandBuffer,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+        first_set: u32,
+        sets: &[DescriptorBufferSet],
+    ) {
+        let binding_info = vk::DescriptorBufferBindingInfoEXT::default()
+            .address(self.address)
+            .usage(self.usage);
+        unsafe {
+            self.loader
+                .cmd_bind_descriptor_buffers(cmd, &[binding_info]);
+        };
+        let buffer_indices = vec![0; sets.len()];
+        let offsets = sets.iter().map(|s| s.offset).collect::<Vec<_>>();
+        unsafe {
+            self.loader.cmd_set_descriptor_buffer_offsets(
+                cmd,
+                bind_point,
+                layout,
+                first_set,
+                &buffer_indices,
+                &offsets,
+            );
+        };
+    }
+}
+
+/// Where a set of descriptors lives, depending on the active [`DescriptorBackend`].
+#[derive(Clone, Copy)]
+pub enum BoundDescriptor {
+    Set(vk::DescriptorSet),
+    Buffer(DescriptorBufferSet),
+}
+
+impl BoundDescriptor {
+    /// Allocates a set living as long as the allocators, from `descriptor_buffer` when
+    /// descriptors are written into descriptor buffers.
+    pub fn allocate(
+        device: &ash::Device,
+        descriptor_allocator: &DescriptorAllocator,
+        descriptor_buffer: Option<&mut DescriptorBufferAllocator>,
+        layout: vk::DescriptorSetLayout,
+    ) -> eyre::Result<Self> {
+        Ok(match descriptor_buffer {
+            Some(descriptor_buffer) => Self::Buffer(descriptor_buffer.allocate(layout)?),
+            None => Self::Set(descriptor_allocator.allocate(device, layout)?[0]),
+        })
+    }
+    pub fn bind(
+        &self,
+        device: &ash::Device,
+        descriptor_buffer: Option<&DescriptorBufferAllocator>,
+        cmd: vk::CommandBuffer,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+        first_set: u32,
+    ) -> eyre::Result<()> {
+        match self {
+            Self::Set(set) => unsafe {
+                device.cmd_bind_descriptor_sets(cmd, bind_point, layout, first_set, &[*set], &[]);
+            },
+            Self::Buffer(set) => {
+                descriptor_buffer
+                    .ok_or_eyre("missing descriptor buffer")?
+                    .bind(cmd, bind_point, layout, first_set, &[*set]);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::ErrorKind,
+    ops::RangeInclusive,
+    path::PathBuf,
+};
+
+use egui::{DragValue, Ui};
+use eyre::{Context, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::{compute::ComputePushConstants, gui::components_drag_value};
+
+const COMPONENTS: usize = 16;
+const DATA_NAMES: [&str; 4] = ["data1", "data2", "data3", "data4"];
+const SWIZZLE: [char; 4] = ['x', 'y', 'z', 'w'];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParameterType {
+    Float,
+    Vec2,
+    Vec3,
+    #[default]
+    Vec4,
+    /// RGBA, edited with a color picker.
+    Color,
+    /// Stored as 0.0 or 1.0.
+    Bool,
+    /// Stored as a float holding a whole number.
+    Int,
+}
+
+impl ParameterType {
+    const fn components(self) -> usize {
+        match self {
+            Self::Float | Self::Bool | Self::Int => 1,
+            Self::Vec2 => 2,
+            Self::Vec3 => 3,
+            Self::Vec4 | Self::Color => 4,
+        }
+    }
+    /// Like std430, so vectors never straddle two of `data1`..`data4`.
+    const fn alignment(self) -> usize {
+        match self {
+            Self::Float | Self::Bool | Self::Int => 1,
+            Self::Vec2 => 2,
+            Self::Vec3 | Self::Vec4 | Self::Color => 4,
+        }
+    }
+}
+
+/// The value of one parameter, as written in effect metadata defaults and presets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParameterValue {
+    Bool(bool),
+    Int(i32),
+    Float(f32),
+    Vector(Vec<f32>),
+}
+
+/// A named slice of `data1`..`data4`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EffectParameter {
+    name: String,
+    #[serde(rename = "type", default)]
+    kind: ParameterType,
+    min: Option<f32>,
+    max: Option<f32>,
+    default: Option<ParameterValue>,
+    #[serde(skip)]
+    offset: usize,
+}
+
+impl EffectParameter {
+    pub fn new(name: impl Into<String>, kind: ParameterType) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            min: None,
+            max: None,
+            default: None,
+            offset: 0,
+        }
+    }
+    pub const fn range(mut self, min: f32, max: f32) -> Self {
+        self.min = Some(min);
+        self.max = Some(max);
+        self
+    }
+    fn slider_range(&self) -> Option<RangeInclusive<f32>> {
+        Some(self.min?..=self.max?)
+    }
+    /// Where the shader finds the parameter, such as `data1.xyz`.
+    fn location(&self) -> String {
+        let vector = DATA_NAMES[self.offset / 4];
+        let first = self.offset % 4;
+        let swizzle: String = SWIZZLE[first..first + self.kind.components()]
+            .iter()
+            .collect();
+        format!("{vector}.{swizzle}")
+    }
+    fn components<'a>(&self, data: &'a mut [f32; COMPONENTS]) -> &'a mut [f32] {
+        &mut data[self.offset..self.offset + self.kind.components()]
+    }
+    fn value(&self, components: &[f32]) -> ParameterValue {
+        match self.kind {
+            ParameterType::Float => ParameterValue::Float(components[0]),
+            ParameterType::Bool => ParameterValue::Bool(components[0] != 0.0),
+            ParameterType::Int => ParameterValue::Int(components[0].round() as i32),
+            _ => ParameterValue::Vector(components.to_vec()),
+        }
+    }
+    fn store(&self, value: &ParameterValue, components: &mut [f32]) -> eyre::Result<()> {
+        match (self.kind, value) {
+            (ParameterType::Float, ParameterValue::Float(v)) => components[0] = *v,
+            (ParameterType::Float | ParameterType::Int, ParameterValue::Int(v)) => {
+                components[0] = *v as f32;
+            }
+            (ParameterType::Bool, ParameterValue::Bool(v)) => {
+                components[0] = f32::from(u8::from(*v));
+            }
+            (_, ParameterValue::Vector(v)) if v.len() == components.len() && v.len() > 1 => {
+                components.copy_from_slice(v);
+            }
+            _ => {
+                return Err(eyre!(
+                    "{value:?} is not a valid {:?} for {}",
+                    self.kind,
+                    self.name
+                ));
+            }
+        }
+        Ok(())
+    }
+    fn ui(&self, ui: &mut Ui, components: &mut [f32]) {
+        let range = self.slider_range();
+        let response = match self.kind {
+            ParameterType::Float => match range {
+                Some(range) => {
+                    ui.add(egui::Slider::new(&mut components[0], range).text(&self.name))
+                }
+                None => {
+                    ui.horizontal(|ui| {
+                        ui.add(DragValue::new(&mut components[0]).speed(0.01));
+                        ui.label(&self.name)
+                    })
+                    .inner
+                }
+            },
+            ParameterType::Int => {
+                let mut value = components[0].round() as i32;
+                let response = match range {
+                    Some(range) => {
+                        let range = range.start().round() as i32..=range.end().round() as i32;
+                        ui.add(egui::Slider::new(&mut value, range).text(&self.name))
+                    }
+                    None => {
+                        ui.horizontal(|ui| {
+                            ui.add(DragValue::new(&mut value));
+                            ui.label(&self.name)
+                        })
+                        .inner
+                    }
+                };
+                components[0] = value as f32;
+                response
+            }
+            ParameterType::Bool => {
+                let mut value = components[0] != 0.0;
+                let response = ui.checkbox(&mut value, &self.name);
+                components[0] = f32::from(u8::from(value));
+                response
+            }
+            ParameterType::Color => {
+                ui.horizontal(|ui| {
+                    let rgba: &mut [f32; 4] =
+                        components.try_into().expect("colors have 4 components");
+                    ui.color_edit_button_rgba_unmultiplied(rgba);
+                    ui.label(&self.name)
+                })
+                .inner
+            }
+            ParameterType::Vec2 | ParameterType::Vec3 | ParameterType::Vec4 => {
+                components_drag_value(ui, components, &self.name, range.as_ref())
+            }
+        };
+        response.on_hover_text(self.location());
+    }
+}
+
+/// The parameters of an effect, packed in declaration order into `data1`..`data4`.
+#[derive(Debug, Clone)]
+pub struct ParameterSchema {
+    parameters: Vec<EffectParameter>,
+}
+
+impl ParameterSchema {
+    pub fn new(mut parameters: Vec<EffectParameter>) -> eyre::Result<Self> {
+        let mut names = HashSet::new();
+        let mut next = 0_usize;
+        for parameter in &mut parameters {
+            if !names.insert(parameter.name.clone()) {
+                return Err(eyre!("parameter {} is declared twice", parameter.name));
+            }
+            if let (Some(min), Some(max)) = (parameter.min, parameter.max)
+                && min > max
+            {
+                return Err(eyre!("the range of {} is empty", parameter.name));
+            }
+            let alignment = parameter.kind.alignment();
+            parameter.offset = next.next_multiple_of(alignment);
+            next = parameter.offset + parameter.kind.components();
+            if next > COMPONENTS {
+                return Err(eyre!(
+                    "{} does not fit in data1..data4 after the parameters before it",
+                    parameter.name
+                ));
+            }
+        }
+        Ok(Self { parameters })
+    }
+    /// `data1`..`data4` as unranged vectors, for effects that do not declare their parameters.
+    pub fn untyped() -> Self {
+        Self::new(
+            DATA_NAMES
+                .iter()
+                .map(|name| EffectParameter::new(*name, ParameterType::Vec4))
+                .collect(),
+        )
+        .expect("four vectors fit in the push constants")
+    }
+    /// The declared defaults, zero for everything else.
+    pub fn defaults(&self) -> eyre::Result<ComputePushConstants> {
+        let mut data = ComputePushConstants::default();
+        let components = bytemuck::cast_mut::<_, [f32; COMPONENTS]>(&mut data);
+        for parameter in &self.parameters {
+            if let Some(default) = &parameter.default {
+                parameter.store(default, parameter.components(components))?;
+            }
+        }
+        Ok(data)
+    }
+    pub fn values(&self, data: &ComputePushConstants) -> BTreeMap<String, ParameterValue> {
+        let mut data = *data;
+        let components = bytemuck::cast_mut::<_, [f32; COMPONENTS]>(&mut data);
+        self.parameters
+            .iter()
+            .map(|p| (p.name.clone(), p.value(p.components(components))))
+            .collect()
+    }
+    /// Sets the parameters named in `values`, leaving the others alone. Values of parameters the
+    /// effect no longer has are skipped.
+    pub fn apply(
+        &self,
+        values: &BTreeMap<String, ParameterValue>,
+        data: &mut ComputePushConstants,
+    ) -> eyre::Result<()> {
+        let mut updated = *data;
+        let components = bytemuck::cast_mut::<_, [f32; COMPONENTS]>(&mut updated);
+        for (name, value) in values {
+            if let Some(parameter) = self.parameters.iter().find(|p| &p.name == name) {
+                parameter.store(value, parameter.components(components))?;
+            } else {
+                tracing::warn!("skipping unknown parameter {name}");
+            }
+        }
+        *data = updated;
+        Ok(())
+    }
+    pub fn ui(&self, ui: &mut Ui, data: &mut ComputePushConstants) {
+        let components = bytemuck::cast_mut::<_, [f32; COMPONENTS]>(data);
+        for parameter in &self.parameters {
+            parameter.ui(ui, parameter.components(components));
+        }
+    }
+}
+
+/// `<preset dir>/<effect>.toml`, where the preset dir is `VKGUIDE_PRESET_DIR` if set and `presets`
+/// in the working directory otherwise.
+fn preset_path(effect_name: &str) -> PathBuf {
+    let dir = std::env::var_os("VKGUIDE_PRESET_DIR")
+        .map_or_else(|| PathBuf::from("presets"), PathBuf::from);
+    let file_name: String = effect_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(format!("{file_name}.toml"))
+}
+
+/// Named parameter values saved for one effect, see [`preset_path`].
+pub struct EffectPresets {
+    path: PathBuf,
+    presets: BTreeMap<String, BTreeMap<String, ParameterValue>>,
+}
+
+impl EffectPresets {
+    pub fn load(effect_name: &str) -> eyre::Result<Self> {
+        let path = preset_path(effect_name);
+        let presets = match fs::read_to_string(&path) {
+            Ok(source) => toml::from_str(&source)
+                .wrap_err_with(|| format!("invalid presets {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).wrap_err_with(|| format!("could not read {}", path.display())),
+        };
+        Ok(Self { path, presets })
+    }
+    /// Presets that could not be read start out empty, and are overwritten by the next save.
+    pub fn empty(effect_name: &str) -> Self {
+        Self {
+            path: preset_path(effect_name),
+            presets: BTreeMap::new(),
+        }
+    }
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.presets.keys().map(String::as_str)
+    }
+    pub fn get(&self, name: &str) -> Option<&BTreeMap<String, ParameterValue>> {
+        self.presets.get(name)
+    }
+    /// Adds or replaces the preset `name` and writes every preset of the effect.
+    pub fn save(
+        &mut self,
+        name: &str,
+        values: BTreeMap<String, ParameterValue>,
+    ) -> eyre::Result<()> {
+        self.presets.insert(name.to_owned(), values);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        }
+        let source = toml::to_string(&self.presets)?;
+        fs::write(&self.path, source)
+            .wrap_err_with(|| format!("could not write {}", self.path.display()))
+    }
+}
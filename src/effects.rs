@@ -0,0 +1,185 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use ash::vk;
+use eyre::{Context, eyre};
+use glam::{Mat4, Vec2, Vec4};
+use serde::Deserialize;
+
+use crate::{
+    buffer::AllocatedBuffer,
+    compute::ComputeEffect,
+    descriptors::DescriptorBackend,
+    effect_parameters::{EffectParameter, ParameterSchema},
+    frames::FRAMES_IN_FLIGHT,
+    shader::{SHADER_DIR, ShaderCompiler},
+    shader_errors::ShaderErrors,
+    specialization::SpecializationConstants,
+    texture::DrawImage,
+    utils::memcopy,
+};
+
+pub const EFFECT_DIR: &str = "shaders/effects";
+
+/// `VKGUIDE_EFFECT_DIR` if set, [`EFFECT_DIR`] otherwise.
+pub fn effect_dir() -> PathBuf {
+    std::env::var_os("VKGUIDE_EFFECT_DIR").map_or_else(|| PathBuf::from(EFFECT_DIR), PathBuf::from)
+}
+
+/// The optional `<effect>.toml` next to an effect shader.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EffectMetadata {
+    name: Option<String>,
+    #[serde(default)]
+    parameters: Vec<EffectParameter>,
+}
+
+impl EffectMetadata {
+    fn read(shader_path: &Path) -> eyre::Result<Self> {
+        let path = shader_path.with_extension("toml");
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).wrap_err_with(|| format!("could not read {}", path.display())),
+        };
+        toml::from_str(&source)
+            .wrap_err_with(|| format!("invalid effect metadata {}", path.display()))
+    }
+}
+
+/// Creates a [`ComputeEffect`] for every `*.comp` in [`effect_dir`], in file name order. Effects
+/// that cannot be loaded are reported and left out.
+pub fn load_effects(
+    device: &ash::Device,
+    shader_compiler: &ShaderCompiler,
+    draw_image: &DrawImage,
+    descriptor_backend: DescriptorBackend,
+    pipeline_cache: vk::PipelineCache,
+    shader_errors: &mut ShaderErrors,
+) -> Vec<ComputeEffect> {
+    let dir = effect_dir();
+    let mut paths = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|e| e == "comp"))
+            .collect::<Vec<_>>(),
+        Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => {
+            tracing::warn!("could not read {}: {e}", dir.display());
+            vec![]
+        }
+    };
+    paths.sort();
+
+    let mut effects = vec![];
+    for path in paths {
+        let owner = format!("Effect {}", path.display());
+        let effect = EffectMetadata::read(&path).and_then(|metadata| {
+            let schema = ParameterSchema::new(metadata.parameters)
+                .wrap_err_with(|| format!("invalid parameters in {}", path.display()))?;
+            let name = metadata.name.unwrap_or_else(|| {
+                path.file_stem()
+                    .map_or_else(String::new, |s| s.to_string_lossy().into_owned())
+            });
+            let effect = ComputeEffect::new(
+                device,
+                shader_compiler,
+                draw_image,
+                name,
+                shader_name(&path)?,
+                SpecializationConstants::new(),
+                schema.defaults()?,
+                descriptor_backend,
+                pipeline_cache,
+                shader_errors,
+            )?;
+            Ok(effect.with_parameters(schema))
+        });
+        match effect {
+            Ok(effect) => effects.push(effect),
+            Err(e) => {
+                tracing::error!("{owner} could not be loaded: {e:?}");
+                shader_errors.report(&owner, &e);
+            }
+        }
+    }
+    effects
+}
+
+/// Shader names are relative to [`SHADER_DIR`], so effects outside of it are named by their
+/// absolute path.
+fn shader_name(path: &Path) -> eyre::Result<String> {
+    let name = match path.strip_prefix(SHADER_DIR) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => fs::canonicalize(path)
+            .wrap_err_with(|| format!("could not resolve {}", path.display()))?,
+    };
+    // `build.rs` names precompiled effects with forward slashes on every platform.
+    name.to_str()
+        .map(|name| name.replace('\\', "/"))
+        .ok_or_else(|| eyre!("{} is not valid UTF-8", name.display()))
+}
+
+/// Inputs every background effect gets besides its parameters, read through
+/// `PushConstants.uniforms` in `shaders/background.glsl`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct BackgroundUniforms {
+    pub view: Mat4,
+    pub proj: Mat4,
+    pub inverse_view_proj: Mat4,
+    pub resolution: Vec2,
+    /// Seconds since startup.
+    pub time: f32,
+    pub frame: u32,
+    /// Cursor position in draw image texels, `z` is 1 while the left button is held.
+    pub mouse: Vec4,
+}
+
+/// One [`BackgroundUniforms`] buffer per frame in flight, so a frame can be written while the
+/// previous one is still being drawn.
+pub struct BackgroundUniformBuffers {
+    buffers: Vec<(AllocatedBuffer, vk::DeviceAddress)>,
+}
+
+impl BackgroundUniformBuffers {
+    pub fn new(device: &ash::Device, allocator: &vk_mem::Allocator) -> eyre::Result<Self> {
+        let buffers = (0..FRAMES_IN_FLIGHT)
+            .map(|_| {
+                let buffer = AllocatedBuffer::new(
+                    allocator,
+                    std::mem::size_of::<BackgroundUniforms>() as u64,
+                    vk::BufferUsageFlags::STORAGE_BUFFER
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                    vk_mem::MemoryUsage::Auto,
+                )?;
+                let address_info = vk::BufferDeviceAddressInfo::default().buffer(buffer.buffer());
+                let address = unsafe { device.get_buffer_device_address(&address_info) };
+                Ok((buffer, address))
+            })
+            .collect::<eyre::Result<_>>()?;
+        Ok(Self { buffers })
+    }
+    /// Writes the uniforms of frame slot `frame` and returns the address to pass to the effect.
+    pub fn write(
+        &self,
+        allocator: &vk_mem::Allocator,
+        frame: usize,
+        uniforms: &BackgroundUniforms,
+    ) -> eyre::Result<vk::DeviceAddress> {
+        let (buffer, address) = &self.buffers[frame];
+        let memory = buffer.alloc_info().mapped_data.cast::<u8>();
+        unsafe { memcopy(std::slice::from_ref(uniforms), memory) };
+        allocator.flush_allocation(&buffer.allocation(), 0, vk::WHOLE_SIZE)?;
+        Ok(*address)
+    }
+    pub fn destroy(&mut self, allocator: &vk_mem::Allocator) {
+        for (buffer, _) in &mut self.buffers {
+            buffer.destroy(allocator);
+        }
+    }
+}
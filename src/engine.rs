@@ -1,28 +1,48 @@
-use std::{mem::ManuallyDrop, sync::Arc};
+use std::{fs, mem::ManuallyDrop, path::Path, sync::Arc, time::Instant};
 
 use ash::vk::{self};
 use eyre::eyre;
-use glam::{Affine3A, Mat4, Vec3, Vec4};
-use winit::{dpi::PhysicalSize, event::WindowEvent, window::Window};
+use glam::{Affine3A, Mat4, Vec2, Vec3, Vec4};
+use winit::{
+    dpi::PhysicalSize,
+    event::{ElementState, MouseButton, WindowEvent},
+    window::Window,
+};
 
 use crate::{
+    async_compute::AsyncCompute,
     buffer::AllocatedBuffer,
     compute::{ComputeEffect, create_compute_effects},
-    descriptors::{DescriptorAllocator, DescriptorLayoutBuilder, DescriptorWriter, PoolSizeRatio},
-    frames::Frames,
-    graphics::MeshPipeline,
-    gui::{Gui, affine_ui, vec4_drag_value},
-    immediate::ImmediateSubmit,
+    descriptors::{
+        BoundDescriptor, DescriptorAllocator, DescriptorBackend, DescriptorBufferAllocator,
+        DescriptorLayoutBuilder, DescriptorWriter, PoolSizeRatio,
+    },
+    effects::{BackgroundUniformBuffers, BackgroundUniforms, effect_dir},
+    frames::{FRAMES_IN_FLIGHT, Frames},
+    graphics::{
+        Blending, DrawState, MESH_PIPELINE_NAME, MSAA_BACKGROUND_PIPELINE_NAME, MeshPipeline,
+        MsaaBackgroundPipeline, VertexFetch,
+    },
+    gui::{Gui, affine_ui},
+    hot_reload::ShaderWatcher,
     material::{
-        GLTFMetallicRoughness, MaterialConstants, MaterialInstance, MaterialPass, MaterialResources,
+        GLTFMetallicRoughness, MATERIAL_NAME, MaterialConstants, MaterialFeatures,
+        MaterialInstance, MaterialPass, MaterialResources,
     },
     mesh::{GPUDrawPushConstants, GPUSceneData, Mesh, load_gltf_from_path},
-    shader::ShaderCompiler,
+    msaa::MsaaLevel,
+    particles::{EmitterConfig, PARTICLES_NAME, ParticleSystem},
+    pipeline_builder::PipelineBuilder,
+    pipeline_cache::PipelineCache,
+    pipeline_description::{LayoutKind, PIPELINE_DIR, PipelineDescription},
+    shader::{HOT_RELOAD_ENABLED, ShaderCompiler},
+    shader_errors::ShaderErrors,
     swapchain::{self, Swapchain},
     texture::{AllocatedImage, DefaultSamplers, DrawImage, EngineImages, copy_image_to_image},
+    upload::{STAGING_RING_SIZE, UploadManager, UploadSharing},
     utils::{
-        color_attachment_info, depth_attachment_info, memcopy, semaphore_submit_info,
-        transition_image,
+        color_attachment_info, depth_attachment_info, depth_attachment_layout, format_aspect,
+        image_subresource_range, memcopy, semaphore_submit_info, transition_image,
     },
     vulkan::Vulkan,
 };
@@ -31,32 +51,168 @@ pub struct Engine {
     window: Arc<Window>,
     pub render: bool,
     vulkan: Vulkan,
+    dynamic_state3: Option<ash::ext::extended_dynamic_state3::Device>,
     allocator: ManuallyDrop<vk_mem::Allocator>,
     swapchain: Swapchain,
     render_semaphores: Vec<vk::Semaphore>,
     frames: Frames,
+    /// `None` without a dedicated compute queue, compute work is then recorded with the frame.
+    async_compute: Option<AsyncCompute>,
+    pipeline_cache: PipelineCache,
+    pipeline_builder: PipelineBuilder,
     shader_compiler: ShaderCompiler,
+    shader_watcher: Option<ShaderWatcher>,
+    shader_errors: ShaderErrors,
     descriptor_allocator: DescriptorAllocator,
+    descriptor_buffer: Option<DescriptorBufferAllocator>,
     draw_image: DrawImage,
     render_scale: f32,
     depth_image: AllocatedImage,
-    immediate_transfer: ImmediateSubmit,
-    immediate_graphics: ImmediateSubmit,
-    background_effects: Vec<ComputeEffect>,
-    current_background_effect: usize,
+    msaa: MsaaLevel,
+    msaa_color_image: Option<AllocatedImage>,
+    msaa_background_pipeline: MsaaBackgroundPipeline,
+    uploads: UploadManager,
+    background: Background,
+    frame_number: u32,
     mesh_pipeline: MeshPipeline,
+    /// Valid pipeline descriptions the mesh pipeline can be switched to.
+    mesh_descriptions: Vec<String>,
     mesh_matrix: Affine3A,
     meshes: Vec<Mesh>,
+    particles: ParticleSystem,
     resize_swapchain: bool,
-    scene_data: GPUSceneData,
-    scene_data_layout: vk::DescriptorSetLayout,
-    scene_data_buffer: AllocatedBuffer,
+    scene: Scene,
     engine_images: EngineImages,
     default_samplers: DefaultSamplers,
     single_image_layout: vk::DescriptorSetLayout,
-    default_data: MaterialInstance,
+    /// Depth bias of the wireframe drawn over material draws, `None` to draw none.
+    wireframe_bias: Option<(f32, f32)>,
+}
+/// A mesh drawn with a material, placed relative to the "Mesh Matrix".
+struct MaterialDraw {
+    mesh: usize,
+    offset: Vec3,
+    material: MaterialInstance,
+}
+/// Background compute effects, and the inputs of their uniforms.
+struct Background {
+    effects: Vec<ComputeEffect>,
+    current: usize,
+    uniforms: BackgroundUniformBuffers,
+    start_time: Instant,
+    /// Cursor position in window pixels.
+    cursor_position: Vec2,
+    cursor_pressed: bool,
+    /// Name of the next background preset to save.
+    preset_name: String,
+}
+impl Background {
+    fn new(
+        vulkan: &Vulkan,
+        allocator: &vk_mem::Allocator,
+        draw_image: &DrawImage,
+        shader_compiler: &ShaderCompiler,
+        pipeline_cache: vk::PipelineCache,
+        shader_errors: &mut ShaderErrors,
+    ) -> eyre::Result<Self> {
+        let effects = create_compute_effects(
+            vulkan.device(),
+            draw_image,
+            shader_compiler,
+            vulkan.descriptor_backend(),
+            pipeline_cache,
+            shader_errors,
+        )?;
+        Ok(Self {
+            effects,
+            current: 0,
+            uniforms: BackgroundUniformBuffers::new(vulkan.device(), allocator)?,
+            start_time: Instant::now(),
+            cursor_position: Vec2::ZERO,
+            cursor_pressed: false,
+            preset_name: String::new(),
+        })
+    }
+    fn destroy(&mut self, device: &ash::Device, allocator: &vk_mem::Allocator) {
+        for e in &mut self.effects {
+            e.destroy(device);
+        }
+        self.uniforms.destroy(allocator);
+    }
+}
+/// Scene data, and the meshes drawn with the glTF metallic-roughness material it lights.
+struct Scene {
+    data: GPUSceneData,
+    layout: vk::DescriptorSetLayout,
+    buffer: AllocatedBuffer,
+    metal_rough: GLTFMetallicRoughness,
     material_constants: AllocatedBuffer,
-    metal_rough_material: GLTFMetallicRoughness,
+    draws: Vec<MaterialDraw>,
+}
+impl Scene {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        vulkan: &Vulkan,
+        allocator: &vk_mem::Allocator,
+        shader_compiler: &ShaderCompiler,
+        pipeline_builder: &mut PipelineBuilder,
+        draw_image: &DrawImage,
+        depth_image: &AllocatedImage,
+        pipeline_cache: vk::PipelineCache,
+        engine_images: &EngineImages,
+        samplers: &DefaultSamplers,
+        descriptor_allocator: &DescriptorAllocator,
+        descriptor_buffer: Option<&mut DescriptorBufferAllocator>,
+        data: GPUSceneData,
+        shader_errors: &mut ShaderErrors,
+    ) -> eyre::Result<Self> {
+        let device = vulkan.device();
+        let layout = DescriptorLayoutBuilder::new()
+            .add_binding(0, vk::DescriptorType::UNIFORM_BUFFER)
+            .flags(vulkan.descriptor_backend().layout_create_flags())
+            .build(
+                device,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            )?;
+        let buffer = create_scene_data_buffer(allocator, data)?;
+        let material_constants = create_material_constants(allocator)?;
+        let mut metal_rough = GLTFMetallicRoughness::new(
+            vulkan,
+            shader_compiler,
+            layout,
+            &geometry_color_formats(draw_image),
+            depth_image,
+            pipeline_cache,
+            vulkan.extended_dynamic_state3_device().is_some(),
+            shader_errors,
+        )?;
+        let draws = create_material_draws(
+            device,
+            shader_compiler,
+            pipeline_builder,
+            &mut metal_rough,
+            &default_material_resources(engine_images, samplers, &material_constants),
+            descriptor_allocator,
+            descriptor_buffer,
+            shader_errors,
+        )?;
+        Ok(Self {
+            data,
+            layout,
+            buffer,
+            metal_rough,
+            material_constants,
+            draws,
+        })
+    }
+    /// Must be called after the pipeline builder is destroyed, as queued jobs use the material
+    /// pipeline layout.
+    fn destroy(&mut self, device: &ash::Device, allocator: &vk_mem::Allocator) {
+        self.material_constants.destroy(allocator);
+        self.metal_rough.destroy(device);
+        unsafe { device.destroy_descriptor_set_layout(self.layout, None) };
+        self.buffer.destroy(allocator);
+    }
 }
 fn create_scene_data_buffer(
     allocator: &vk_mem::Allocator,
@@ -66,7 +222,8 @@ fn create_scene_data_buffer(
     let scene_data_buffer = AllocatedBuffer::new(
         allocator,
         scene_data_size,
-        vk::BufferUsageFlags::UNIFORM_BUFFER,
+        // Written into descriptor buffers by address.
+        vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
         vk_mem::MemoryUsage::Auto,
     )?;
     let memory = unsafe { allocator.map_memory(&mut scene_data_buffer.allocation()) }?;
@@ -76,32 +233,358 @@ fn create_scene_data_buffer(
     };
     Ok(scene_data_buffer)
 }
+/// Reports the pipeline descriptions that do not parse or validate, and returns the names of the
+/// valid ones the mesh pipeline can use.
+fn validate_pipeline_descriptions(shader_errors: &mut ShaderErrors) -> Vec<String> {
+    let mut mesh_descriptions = vec![];
+    for (name, result) in PipelineDescription::validate_all() {
+        let owner = format!("Pipeline description {name}");
+        match result {
+            Ok(layout) => {
+                shader_errors.clear(&owner);
+                if layout == LayoutKind::Mesh {
+                    mesh_descriptions.push(name);
+                }
+            }
+            Err(e) => {
+                tracing::error!("{owner} is invalid: {e:?}");
+                shader_errors.report(&owner, &e);
+            }
+        }
+    }
+    mesh_descriptions
+}
+/// Formats of the geometry pass color attachments, in the order of the fragment shader outputs.
+fn geometry_color_formats(draw_image: &DrawImage) -> Vec<vk::Format> {
+    vec![draw_image.format()]
+}
+/// Projection of the geometry pass, with reversed depth.
+fn geometry_projection(draw_extent: vk::Extent2D) -> Mat4 {
+    let aspect_ratio = draw_extent.width as f32 / draw_extent.height as f32;
+    let mut projection = Mat4::perspective_rh(f32::to_radians(70.0), aspect_ratio, 10000.0, 0.1);
+    projection.y_axis.y *= -1.0;
+    projection
+}
+fn create_swapchain(vulkan: &Vulkan, size: PhysicalSize<u32>) -> eyre::Result<Swapchain> {
+    Swapchain::new(
+        size.width,
+        size.height,
+        vulkan,
+        swapchain::IMAGE_FORMAT,
+        swapchain::COLOR_SPACE,
+        vk::PresentModeKHR::FIFO,
+        vk::ImageUsageFlags::TRANSFER_DST,
+    )
+}
+/// Creates the draw image, sized for the largest expected monitor, and a depth image matching it
+/// with `msaa` samples.
+fn create_render_targets(
+    vulkan: &Vulkan,
+    allocator: &vk_mem::Allocator,
+    descriptor_allocator: &DescriptorAllocator,
+    descriptor_buffer: Option<&mut DescriptorBufferAllocator>,
+    msaa: MsaaLevel,
+) -> eyre::Result<(DrawImage, AllocatedImage)> {
+    const MONITOR_WIDTH: u32 = 1980;
+    const MONITOR_HEIGHT: u32 = 1080;
+    let draw_image = DrawImage::new(
+        MONITOR_WIDTH,
+        MONITOR_HEIGHT,
+        vulkan.device(),
+        allocator,
+        descriptor_allocator,
+        descriptor_buffer,
+    )?;
+    let depth_image = AllocatedImage::create_depth_image(
+        vulkan.device(),
+        allocator,
+        &draw_image,
+        AllocatedImage::depth_format(vulkan),
+        msaa.samples(),
+    )?;
+    Ok((draw_image, depth_image))
+}
+/// Creates the mesh pipeline and the pipeline drawing the background into the MSAA color image,
+/// which share the layout of a single sampled image.
+fn create_geometry_pipelines(
+    vulkan: &Vulkan,
+    shader_compiler: &ShaderCompiler,
+    draw_image: &DrawImage,
+    depth_image: &AllocatedImage,
+    msaa: MsaaLevel,
+    pipeline_cache: vk::PipelineCache,
+    shader_errors: &mut ShaderErrors,
+) -> eyre::Result<(
+    vk::DescriptorSetLayout,
+    MeshPipeline,
+    MsaaBackgroundPipeline,
+)> {
+    let device = vulkan.device();
+    let single_image_layout = DescriptorLayoutBuilder::new()
+        .add_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .flags(vulkan.descriptor_backend().layout_create_flags())
+        .build(device, vk::ShaderStageFlags::FRAGMENT)?;
+    let mesh_pipeline = MeshPipeline::new(
+        vulkan,
+        shader_compiler,
+        &geometry_color_formats(draw_image),
+        depth_image,
+        single_image_layout,
+        pipeline_cache,
+        shader_errors,
+    )?;
+    let msaa_background_pipeline = MsaaBackgroundPipeline::new(
+        device,
+        shader_compiler,
+        draw_image,
+        msaa.samples(),
+        single_image_layout,
+        vulkan.descriptor_backend(),
+        pipeline_cache,
+        shader_errors,
+    )?;
+    Ok((single_image_layout, mesh_pipeline, msaa_background_pipeline))
+}
+fn create_allocator(vulkan: &Vulkan) -> eyre::Result<vk_mem::Allocator> {
+    let mut allocator_info = vk_mem::AllocatorCreateInfo::new(
+        vulkan.instance(),
+        vulkan.device(),
+        vulkan.physical_device(),
+    );
+    allocator_info.flags = vk_mem::AllocatorCreateFlags::BUFFER_DEVICE_ADDRESS;
+    Ok(unsafe { vk_mem::Allocator::new(allocator_info) }?)
+}
+/// Creates the descriptor pool allocator, and the descriptor buffer allocator when descriptors are
+/// written into descriptor buffers.
+fn create_descriptor_allocators(
+    vulkan: &Vulkan,
+    allocator: &vk_mem::Allocator,
+) -> eyre::Result<(DescriptorAllocator, Option<DescriptorBufferAllocator>)> {
+    let descriptor_allocator = DescriptorAllocator::new(
+        vulkan.device(),
+        10,
+        &[PoolSizeRatio::new(vk::DescriptorType::STORAGE_IMAGE, 1.0)],
+    )?;
+    let descriptor_buffer = match vulkan.descriptor_backend() {
+        DescriptorBackend::Pool => None,
+        DescriptorBackend::Buffer => Some(DescriptorBufferAllocator::new(
+            vulkan,
+            allocator,
+            64 * 1024,
+            16 * 1024,
+            FRAMES_IN_FLIGHT,
+            vk::BufferUsageFlags::RESOURCE_DESCRIPTOR_BUFFER_EXT
+                | vk::BufferUsageFlags::SAMPLER_DESCRIPTOR_BUFFER_EXT,
+        )?),
+    };
+    Ok((descriptor_allocator, descriptor_buffer))
+}
+/// Starts the uploads of the meshes and default images. The default images are sampled from the
+/// first frame on, which acquires them, while meshes are drawn once their upload is done.
+fn upload_assets(
+    vulkan: &Vulkan,
+    allocator: &vk_mem::Allocator,
+) -> eyre::Result<(UploadManager, Vec<Mesh>, EngineImages)> {
+    let device = vulkan.device();
+    let mut uploads = UploadManager::new(
+        vulkan,
+        allocator,
+        UploadSharing::preferred(),
+        STAGING_RING_SIZE,
+    )?;
+    let meshes = load_gltf_from_path("assets/basicmesh.glb", device, allocator, &mut uploads)?;
+    let engine_images = EngineImages::new(device, allocator, &mut uploads)?;
+    uploads.finish(device)?;
+    Ok((uploads, meshes, engine_images))
+}
+fn initial_scene_data(extent: vk::Extent2D) -> GPUSceneData {
+    let view = Mat4::from_translation(Vec3::new(0.0, 0.0, -5.0));
+    let aspect_ratio = extent.width as f32 / extent.height as f32;
+    let mut proj = Mat4::perspective_rh(f32::to_radians(70.0), aspect_ratio, 10000.0, 0.1);
+    proj.y_axis.y *= -1.0;
+    GPUSceneData::new(
+        view,
+        proj,
+        Vec4::splat(0.1),
+        Vec3::new(0.0, 1.0, 0.5).normalize().extend(0.0),
+        Vec4::ONE,
+    )
+}
+/// Creates the shader compiler, and in dev builds a watcher over its include paths and the
+/// pipeline and effect directories.
+fn create_shader_compiler() -> eyre::Result<(ShaderCompiler, Option<ShaderWatcher>)> {
+    let shader_compiler = ShaderCompiler::new()?;
+    if !HOT_RELOAD_ENABLED {
+        return Ok((shader_compiler, None));
+    }
+    let mut roots = shader_compiler.include_paths().to_vec();
+    if Path::new(PIPELINE_DIR).is_dir() {
+        roots.push(PIPELINE_DIR.into());
+    }
+    let effect_dir = effect_dir();
+    if effect_dir.is_dir() {
+        roots.push(effect_dir);
+    }
+    let watcher = ShaderWatcher::new(&roots);
+    Ok((shader_compiler, Some(watcher)))
+}
+/// Creates the particle system with its "Sparks" emitter, and the async compute queue it is
+/// simulated on when the device has one.
+fn create_particles(
+    vulkan: &Vulkan,
+    allocator: &vk_mem::Allocator,
+    shader_compiler: &ShaderCompiler,
+    draw_image: &DrawImage,
+    depth_image: &AllocatedImage,
+    pipeline_cache: vk::PipelineCache,
+    shader_errors: &mut ShaderErrors,
+) -> eyre::Result<(Option<AsyncCompute>, ParticleSystem)> {
+    let device = vulkan.device();
+    let async_compute = AsyncCompute::new(vulkan)?;
+    let mut particles = ParticleSystem::new(
+        device,
+        shader_compiler,
+        &geometry_color_formats(draw_image),
+        depth_image,
+        vulkan.descriptor_backend(),
+        pipeline_cache,
+        async_compute.as_ref().map(AsyncCompute::transfer),
+        shader_errors,
+    )?;
+    particles.add_emitter(
+        device,
+        allocator,
+        EmitterConfig {
+            name: "Sparks".to_owned(),
+            node: MESH_NODE.to_owned(),
+            offset: Vec3::new(0.0, 1.2, 0.0),
+            ..EmitterConfig::default()
+        },
+    )?;
+    Ok((async_compute, particles))
+}
+fn create_material_constants(allocator: &vk_mem::Allocator) -> eyre::Result<AllocatedBuffer> {
+    let material_constants = AllocatedBuffer::new(
+        allocator,
+        std::mem::size_of::<MaterialConstants>() as u64,
+        vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+        vk_mem::MemoryUsage::Auto,
+    )?;
+    let mem = unsafe { allocator.map_memory(&mut material_constants.allocation()) }?;
+    unsafe {
+        memcopy(
+            &[MaterialConstants::new(
+                Vec4::ONE,
+                Vec4::new(1.0, 0.5, 0.0, 0.0),
+            )],
+            mem,
+        );
+    };
+    unsafe { allocator.unmap_memory(&mut material_constants.allocation()) };
+    Ok(material_constants)
+}
+/// Resources of a white material, with its constants in `material_constants`.
+const fn default_material_resources(
+    engine_images: &EngineImages,
+    samplers: &DefaultSamplers,
+    material_constants: &AllocatedBuffer,
+) -> MaterialResources {
+    MaterialResources {
+        color_image_view: engine_images.white.image_view(),
+        color_sampler: samplers.linear,
+        metal_rough_image_vew: engine_images.white.image_view(),
+        metal_rough_sampler: samplers.linear,
+        data_buffer: material_constants.buffer(),
+        data_buffer_offset: 0,
+    }
+}
+#[allow(clippy::too_many_arguments)]
+fn create_material_draws(
+    device: &ash::Device,
+    shader_compiler: &ShaderCompiler,
+    pipeline_builder: &mut PipelineBuilder,
+    metal_rough_material: &mut GLTFMetallicRoughness,
+    resources: &MaterialResources,
+    descriptor_allocator: &DescriptorAllocator,
+    mut descriptor_buffer: Option<&mut DescriptorBufferAllocator>,
+    shader_errors: &mut ShaderErrors,
+) -> eyre::Result<Vec<MaterialDraw>> {
+    let unlit = MaterialFeatures {
+        unlit: true,
+        ..MaterialFeatures::default()
+    };
+    // Opaque draws first, transparent ones blend over them. The unlit sphere draws with the
+    // default variant until its own is built on the pipeline builder.
+    [
+        (
+            1,
+            Vec3::new(-3.0, 0.0, 0.0),
+            MaterialPass::MainColor,
+            MaterialFeatures::default(),
+        ),
+        (1, Vec3::new(0.0, 3.0, 0.0), MaterialPass::MainColor, unlit),
+        (
+            0,
+            Vec3::new(3.0, 0.0, 0.0),
+            MaterialPass::Transparent,
+            MaterialFeatures::default(),
+        ),
+    ]
+    .into_iter()
+    .map(|(mesh, offset, pass, features)| {
+        let material = metal_rough_material.write_material(
+            device,
+            shader_compiler,
+            pipeline_builder,
+            pass,
+            features,
+            resources,
+            descriptor_allocator,
+            descriptor_buffer.as_deref_mut(),
+            shader_errors,
+        )?;
+        Ok(MaterialDraw {
+            mesh,
+            offset,
+            material,
+        })
+    })
+    .collect()
+}
+/// Scene node emitters can follow, placed by the "Mesh Matrix".
+const MESH_NODE: &str = "Mesh";
 impl Engine {
     pub fn destroy(&mut self, gui: &mut ManuallyDrop<Gui>) {
         unsafe { self.vulkan.device().device_wait_idle() }.unwrap();
         let device = self.vulkan.device();
         let allocator = &mut self.allocator;
         self.frames.destroy(device);
-        //
-        self.material_constants.destroy(allocator);
-        self.metal_rough_material.destroy(device);
+        if let Some(async_compute) = &self.async_compute {
+            async_compute.destroy(device);
+        }
+        // Queued jobs still use the material pipeline layout.
+        self.pipeline_builder.destroy(device);
+        self.scene.destroy(device, allocator);
         unsafe { device.destroy_descriptor_set_layout(self.single_image_layout, None) };
         self.default_samplers.destroy(device);
         self.engine_images.destroy(device, allocator);
-        unsafe { device.destroy_descriptor_set_layout(self.scene_data_layout, None) };
-        self.scene_data_buffer.destroy(allocator);
         for mesh in &mut self.meshes {
             mesh.mesh_buffers_mut().destroy(allocator);
         }
+        self.particles.destroy(device, allocator);
         self.mesh_pipeline.destroy(device);
+        self.msaa_background_pipeline.destroy(device);
         unsafe { ManuallyDrop::drop(gui) };
-        self.immediate_graphics.destroy(device);
-        self.immediate_transfer.destroy(device);
-        for e in &mut self.background_effects {
-            e.destroy(device);
-        }
+        self.uploads.destroy(device, allocator);
+        self.background.destroy(device, allocator);
         self.descriptor_allocator.destroy_pool(device);
+        if let Some(descriptor_buffer) = &mut self.descriptor_buffer {
+            descriptor_buffer.destroy(allocator);
+        }
         self.depth_image.destroy(device, allocator);
+        if let Some(image) = &mut self.msaa_color_image {
+            image.destroy(device, allocator);
+        }
         self.draw_image.destroy(device, allocator);
 
         unsafe { ManuallyDrop::drop(allocator) };
@@ -112,6 +595,10 @@ impl Engine {
         for s in &self.render_semaphores {
             unsafe { device.destroy_semaphore(*s, None) };
         }
+        if let Err(e) = self.pipeline_cache.save(device) {
+            tracing::warn!("could not save the pipeline cache: {e:?}");
+        }
+        self.pipeline_cache.destroy(device);
         unsafe { device.destroy_device(None) };
 
         let surface_instance = self.vulkan.surface_instance();
@@ -126,159 +613,266 @@ impl Engine {
         unsafe { instance.destroy_instance(None) };
     }
     pub fn new(window: Arc<Window>) -> eyre::Result<Self> {
-        let vulkan = Vulkan::new(&window)?;
-        let PhysicalSize { width, height } = window.inner_size();
-
-        let swapchain = Swapchain::new(
-            width,
-            height,
-            &vulkan,
-            swapchain::IMAGE_FORMAT,
-            swapchain::COLOR_SPACE,
-            vk::PresentModeKHR::FIFO,
-            vk::ImageUsageFlags::TRANSFER_DST,
-        )?;
-        let device = vulkan.device();
-        let render_semaphores = swapchain.create_render_semaphores(device)?;
-        let frames = Frames::new(&vulkan)?;
+        let vulkan = Vulkan::new(&window, DescriptorBackend::preferred())?;
+        let swapchain = create_swapchain(&vulkan, window.inner_size())?;
+        let pipeline_cache = PipelineCache::new(&vulkan, PipelineCache::default_path())?;
 
-        let mut allocator_info =
-            vk_mem::AllocatorCreateInfo::new(vulkan.instance(), device, vulkan.physical_device());
-        allocator_info.flags = vk_mem::AllocatorCreateFlags::BUFFER_DEVICE_ADDRESS;
-        let allocator = unsafe { vk_mem::Allocator::new(allocator_info) }?;
-        let shader_compiler = ShaderCompiler::new()?;
+        let allocator = create_allocator(&vulkan)?;
+        let (shader_compiler, shader_watcher) = create_shader_compiler()?;
+        let mut shader_errors = ShaderErrors::default();
+        let mesh_descriptions = validate_pipeline_descriptions(&mut shader_errors);
+        let mut pipeline_builder = PipelineBuilder::new(vulkan.device())?;
 
-        let descriptor_allocator = DescriptorAllocator::new(
-            device,
-            10,
-            &[PoolSizeRatio::new(vk::DescriptorType::STORAGE_IMAGE, 1.0)],
-        )?;
-        const MONITOR_WIDTH: u32 = 1980;
-        const MONITOR_HEIGHT: u32 = 1080;
-        let draw_image = DrawImage::new(
-            MONITOR_WIDTH,
-            MONITOR_HEIGHT,
-            device,
+        let (descriptor_allocator, mut descriptor_buffer) =
+            create_descriptor_allocators(&vulkan, &allocator)?;
+        let msaa = MsaaLevel::default();
+        let (draw_image, depth_image) = create_render_targets(
+            &vulkan,
             &allocator,
             &descriptor_allocator,
+            descriptor_buffer.as_mut(),
+            msaa,
         )?;
-        let depth_image = AllocatedImage::create_depth_image(device, &allocator, &draw_image)?;
-        let immediate_graphics =
-            ImmediateSubmit::new(device, vulkan.queue_family_indices().graphics)?;
-        let immediate_transfer =
-            ImmediateSubmit::new(device, vulkan.queue_family_indices().transfer)?;
-        let background_effects = create_compute_effects(device, &draw_image, &shader_compiler)?;
-
-        let single_image_layout = DescriptorLayoutBuilder::new()
-            .add_binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-            .build(device, vk::ShaderStageFlags::FRAGMENT)?;
-        let mesh_pipeline = MeshPipeline::new(
-            device,
-            &shader_compiler,
+        let background = Background::new(
+            &vulkan,
+            &allocator,
             &draw_image,
-            &depth_image,
-            single_image_layout,
+            &shader_compiler,
+            pipeline_cache.cache(),
+            &mut shader_errors,
         )?;
 
-        let meshes = load_gltf_from_path(
-            "assets/basicmesh.glb",
-            device,
-            &allocator,
-            vulkan.transfer_queue(),
-            &immediate_transfer,
-        )?;
-        let view = Mat4::from_translation(Vec3::new(0.0, 0.0, -5.0));
-        let aspect_ratio = width as f32 / height as f32;
-        let mut proj = Mat4::perspective_rh(f32::to_radians(70.0), aspect_ratio, 10000.0, 0.1);
-        proj.y_axis.y *= -1.0;
-        let scene_data = GPUSceneData::new(view, proj, Vec4::ZERO, Vec4::ZERO, Vec4::ZERO);
-        let scene_data_layout = DescriptorLayoutBuilder::new()
-            .add_binding(0, vk::DescriptorType::UNIFORM_BUFFER)
-            .build(
-                device,
-                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+        let (single_image_layout, mesh_pipeline, msaa_background_pipeline) =
+            create_geometry_pipelines(
+                &vulkan,
+                &shader_compiler,
+                &draw_image,
+                &depth_image,
+                msaa,
+                pipeline_cache.cache(),
+                &mut shader_errors,
             )?;
-        let scene_data_buffer = create_scene_data_buffer(&allocator, scene_data)?;
-        let engine_images = EngineImages::new(
-            device,
+        let (async_compute, particles) = create_particles(
+            &vulkan,
             &allocator,
-            &immediate_graphics,
-            vulkan.graphics_queue(),
-        )?;
-        let samplers = DefaultSamplers::new(device)?;
-
-        let metal_rough_material = GLTFMetallicRoughness::new(
-            device,
             &shader_compiler,
-            scene_data_layout,
             &draw_image,
             &depth_image,
+            pipeline_cache.cache(),
+            &mut shader_errors,
         )?;
 
-        let material_constants = AllocatedBuffer::new(
+        let (uploads, meshes, engine_images) = upload_assets(&vulkan, &allocator)?;
+        let samplers = DefaultSamplers::new(vulkan.device())?;
+        let scene = Scene::new(
+            &vulkan,
             &allocator,
-            std::mem::size_of::<MaterialConstants>() as u64,
-            vk::BufferUsageFlags::UNIFORM_BUFFER,
-            vk_mem::MemoryUsage::Auto,
-        )?;
-
-        let mem = unsafe { allocator.map_memory(&mut material_constants.allocation()) }?;
-        unsafe {
-            memcopy(
-                &[MaterialConstants::new(
-                    Vec4::ONE,
-                    Vec4::new(1.0, 0.5, 0.0, 0.0),
-                )],
-                mem,
-            );
-        };
-        unsafe { allocator.unmap_memory(&mut material_constants.allocation()) };
-        let resources = MaterialResources {
-            color_image_view: engine_images.white.image_view(),
-            color_sampler: samplers.linear,
-            metal_rough_image_vew: engine_images.white.image_view(),
-            metal_rough_sampler: samplers.linear,
-            data_buffer: material_constants.buffer(),
-            data_buffer_offset: 0,
-        };
-        let default_data = metal_rough_material.write_material(
-            device,
-            MaterialPass::MainColor,
-            &resources,
+            &shader_compiler,
+            &mut pipeline_builder,
+            &draw_image,
+            &depth_image,
+            pipeline_cache.cache(),
+            &engine_images,
+            &samplers,
             &descriptor_allocator,
+            descriptor_buffer.as_mut(),
+            initial_scene_data(swapchain.extent()),
+            &mut shader_errors,
         )?;
         Ok(Self {
             window,
             render: true,
+            dynamic_state3: vulkan.extended_dynamic_state3_device(),
+            render_semaphores: swapchain.create_render_semaphores(vulkan.device())?,
+            frames: Frames::new(&vulkan)?,
             vulkan,
             swapchain,
-            render_semaphores,
-            frames,
+            async_compute,
+            pipeline_cache,
+            pipeline_builder,
             allocator: ManuallyDrop::new(allocator),
             draw_image,
             depth_image,
+            msaa,
+            msaa_color_image: None,
+            msaa_background_pipeline,
             shader_compiler,
+            shader_watcher,
+            shader_errors,
             descriptor_allocator,
-            background_effects,
-            current_background_effect: 0,
-            immediate_transfer,
-            immediate_graphics,
+            descriptor_buffer,
+            background,
+            frame_number: 0,
+            uploads,
             mesh_pipeline,
+            mesh_descriptions,
             mesh_matrix: Affine3A::from_translation(Vec3::new(0.0, 0.0, -5.0)),
             meshes,
+            particles,
             render_scale: 1.0,
             resize_swapchain: false,
-            scene_data,
-            scene_data_layout,
-            scene_data_buffer,
+            scene,
             engine_images,
             default_samplers: samplers,
             single_image_layout,
-            default_data,
-            material_constants,
-            metal_rough_material,
+            wireframe_bias: None,
         })
     }
+    /// Rebuilds every pipeline whose shader sources or includes changed on disk. Replaced
+    /// pipelines are retired through the frame deletion queue, and pipelines that fail to compile
+    /// keep their last good version while the error is shown in the shader error overlay.
+    fn reload_shaders(&mut self) {
+        let Some(watcher) = &mut self.shader_watcher else {
+            return;
+        };
+        let changed = watcher.poll();
+        if changed.is_empty() {
+            return;
+        }
+        if let Ok(pipeline_dir) = fs::canonicalize(PIPELINE_DIR)
+            && changed.iter().any(|path| path.starts_with(&pipeline_dir))
+        {
+            self.mesh_descriptions = validate_pipeline_descriptions(&mut self.shader_errors);
+        }
+        let device = self.vulkan.device();
+        let frames = &mut self.frames;
+        let errors = &mut self.shader_errors;
+        let mut report = |name: &str, result: eyre::Result<()>| match result {
+            Ok(()) => {
+                tracing::info!("reloaded {name}");
+                errors.clear(name);
+            }
+            Err(e) => {
+                tracing::error!("could not reload {name}: {e:?}");
+                errors.report(name, &e);
+            }
+        };
+        for effect in &mut self.background.effects {
+            if effect.dependencies().affected_by(&changed) {
+                let result = effect.reload(device, &self.shader_compiler, |p| {
+                    frames.retire_pipeline(p);
+                });
+                report(effect.name(), result);
+            }
+        }
+        if self.mesh_pipeline.dependencies().affected_by(&changed) {
+            let result = self.mesh_pipeline.reload(
+                device,
+                &self.shader_compiler,
+                &geometry_color_formats(&self.draw_image),
+                &self.depth_image,
+                |p| frames.retire_pipeline(p),
+            );
+            report(MESH_PIPELINE_NAME, result);
+        }
+        if self
+            .msaa_background_pipeline
+            .dependencies()
+            .affected_by(&changed)
+        {
+            let result = self.msaa_background_pipeline.reload(
+                device,
+                &self.shader_compiler,
+                &self.draw_image,
+                self.msaa.samples(),
+                |p| frames.retire_pipeline(p),
+            );
+            report(MSAA_BACKGROUND_PIPELINE_NAME, result);
+        }
+        if self.particles.affected_by(&changed) {
+            let result = self.particles.reload(
+                device,
+                &self.shader_compiler,
+                &geometry_color_formats(&self.draw_image),
+                &self.depth_image,
+                |p| frames.retire_pipeline(p),
+            );
+            report(PARTICLES_NAME, result);
+        }
+        if self.scene.metal_rough.dependencies().affected_by(&changed) {
+            let result = self.scene.metal_rough.reload(
+                device,
+                &self.shader_compiler,
+                self.msaa.samples(),
+                |p| frames.retire_pipeline(p),
+            );
+            report(MATERIAL_NAME, result);
+        }
+    }
+    /// Recreates the render targets with `level` samples and rebuilds every pipeline that draws
+    /// into them. Pipelines that fail to compile for the new sample count use the error shaders.
+    fn set_msaa(&mut self, level: MsaaLevel) -> eyre::Result<()> {
+        let level = level.clamp(&self.vulkan);
+        if level == self.msaa {
+            return Ok(());
+        }
+        let device = self.vulkan.device();
+        unsafe { device.device_wait_idle() }?;
+        let samples = level.samples();
+        let depth_image = AllocatedImage::create_depth_image(
+            device,
+            &self.allocator,
+            &self.draw_image,
+            self.depth_image.format(),
+            samples,
+        )?;
+        self.depth_image.destroy(device, &self.allocator);
+        self.depth_image = depth_image;
+        if let Some(image) = &mut self.msaa_color_image {
+            image.destroy(device, &self.allocator);
+        }
+        self.msaa_color_image = None;
+        if level.enabled() {
+            self.msaa_color_image = Some(AllocatedImage::create_msaa_color_image(
+                device,
+                &self.allocator,
+                &self.draw_image,
+                samples,
+            )?);
+        }
+        self.msaa = level;
+
+        // Nothing is in flight anymore, so the old pipelines can go right away.
+        let retire = |p| unsafe { device.destroy_pipeline(p, None) };
+        let errors = &mut self.shader_errors;
+        let mut report = |name: &str, result: eyre::Result<()>| match result {
+            Ok(()) => errors.clear(name),
+            Err(e) => {
+                tracing::error!("could not rebuild {name} for {}: {e:?}", level.label());
+                errors.report(name, &e);
+            }
+        };
+        let result = self.mesh_pipeline.reload(
+            device,
+            &self.shader_compiler,
+            &geometry_color_formats(&self.draw_image),
+            &self.depth_image,
+            retire,
+        );
+        report(MESH_PIPELINE_NAME, result);
+        let result = self.msaa_background_pipeline.reload(
+            device,
+            &self.shader_compiler,
+            &self.draw_image,
+            samples,
+            retire,
+        );
+        report(MSAA_BACKGROUND_PIPELINE_NAME, result);
+        let result = self.particles.reload(
+            device,
+            &self.shader_compiler,
+            &geometry_color_formats(&self.draw_image),
+            &self.depth_image,
+            retire,
+        );
+        report(PARTICLES_NAME, result);
+        let result = self
+            .scene
+            .metal_rough
+            .reload(device, &self.shader_compiler, samples, retire);
+        report(MATERIAL_NAME, result);
+        Ok(())
+    }
     fn draw_extent(&self) -> vk::Extent2D {
         let draw_extent = self.draw_image.extent();
         let swapchain_extent = self.swapchain.extent();
@@ -290,78 +884,243 @@ impl Engine {
         }
     }
 
-    fn draw_background(&self, cmd: vk::CommandBuffer) {
-        let device = self.vulkan.device();
-        let background_effect = &self.background_effects[self.current_background_effect];
-        unsafe {
-            device.cmd_bind_pipeline(
-                cmd,
-                vk::PipelineBindPoint::COMPUTE,
-                background_effect.pipeline(),
-            );
-        };
-        unsafe {
-            device.cmd_bind_descriptor_sets(
-                cmd,
-                vk::PipelineBindPoint::COMPUTE,
-                background_effect.layout(),
-                0,
-                &[self.draw_image.descriptor_set()],
-                &[],
-            );
+    fn draw_background(&self, cmd: vk::CommandBuffer) -> eyre::Result<()> {
+        let extent = self.draw_extent();
+        let resolution = Vec2::new(extent.width as f32, extent.height as f32);
+        let swapchain_extent = self.swapchain.extent();
+        // The draw extent is stretched over the whole window.
+        let window_size = Vec2::new(
+            swapchain_extent.width as f32,
+            swapchain_extent.height as f32,
+        );
+        let mouse = self.background.cursor_position * resolution / window_size.max(Vec2::ONE);
+        let view = self.scene.data.view();
+        let proj = self.scene.data.proj();
+        let uniforms = BackgroundUniforms {
+            view,
+            proj,
+            inverse_view_proj: (proj * view).inverse(),
+            resolution,
+            time: self.background.start_time.elapsed().as_secs_f32(),
+            frame: self.frame_number,
+            mouse: mouse
+                .extend(f32::from(u8::from(self.background.cursor_pressed)))
+                .extend(0.0),
         };
+        let uniforms = self.background.uniforms.write(
+            &self.allocator,
+            self.frames.current_index(),
+            &uniforms,
+        )?;
+        self.background.effects[self.background.current].draw(
+            self.vulkan.device(),
+            self.descriptor_buffer.as_ref(),
+            cmd,
+            &self.draw_image,
+            extent,
+            uniforms,
+        )
+    }
 
-        let push_constant = background_effect.data;
-        let push_constants_bytes = bytemuck::bytes_of(&push_constant);
-        unsafe {
-            device.cmd_push_constants(
-                cmd,
-                background_effect.layout(),
-                vk::ShaderStageFlags::COMPUTE,
-                0,
-                push_constants_bytes,
+    pub(crate) fn build_ui(&mut self, ctx: &egui::Context) {
+        let mut mesh_description = self.mesh_pipeline.description().to_owned();
+        egui::Window::new("Background").show(ctx, |ui| {
+            let background = &mut self.background;
+            egui::ComboBox::from_label("Effect")
+                .selected_text(background.effects[background.current].name())
+                .show_ui(ui, |ui| {
+                    for (i, effect) in background.effects.iter().enumerate() {
+                        ui.selectable_value(&mut background.current, i, effect.name());
+                    }
+                });
+            let selected = &mut background.effects[background.current];
+            if let Err(e) = selected.ui(ui, &mut background.preset_name) {
+                tracing::error!("{} preset: {e:?}", selected.name());
+                self.shader_errors
+                    .report(&format!("Presets of {}", selected.name()), &e);
+            }
+
+            affine_ui(ui, &mut self.mesh_matrix, "Mesh Matrix");
+            egui::ComboBox::from_label("Mesh Pipeline")
+                .selected_text(&mesh_description)
+                .show_ui(ui, |ui| {
+                    for name in &self.mesh_descriptions {
+                        ui.selectable_value(&mut mesh_description, name.clone(), name);
+                    }
+                });
+            let building = self.scene.metal_rough.building();
+            if building > 0 {
+                ui.label(format!("Building {building} material variants"));
+            }
+            // Polygon mode is only dynamic with `VK_EXT_extended_dynamic_state3`.
+            let mut wireframe = self.wireframe_bias.is_some();
+            ui.add_enabled(
+                self.dynamic_state3.is_some(),
+                egui::Checkbox::new(&mut wireframe, "Material Wireframe"),
+            );
+            self.wireframe_bias = wireframe.then_some((1.0, 1.0));
+            ui.add(egui::Slider::new(&mut self.render_scale, 0.3..=1.0));
+        });
+        if mesh_description != self.mesh_pipeline.description() {
+            let frames = &mut self.frames;
+            let result = self.mesh_pipeline.set_description(
+                self.vulkan.device(),
+                &self.shader_compiler,
+                &mesh_description,
+                &geometry_color_formats(&self.draw_image),
+                &self.depth_image,
+                |p| frames.retire_pipeline(p),
             );
+            if let Err(e) = result {
+                tracing::error!("could not switch to {mesh_description}: {e:?}");
+                self.shader_errors.report(MESH_PIPELINE_NAME, &e);
+            }
+        }
+        egui::Window::new(PARTICLES_NAME).show(ctx, |ui| {
+            if let Err(e) =
+                self.particles
+                    .ui(ui, self.vulkan.device(), &self.allocator, &[MESH_NODE])
+            {
+                tracing::error!("could not update the particle emitters: {e:?}");
+            }
+        });
+        let mut msaa = self.msaa;
+        egui::Window::new("Anti-aliasing").show(ctx, |ui| {
+            egui::ComboBox::from_label("MSAA")
+                .selected_text(msaa.label())
+                .show_ui(ui, |ui| {
+                    for level in MsaaLevel::ALL {
+                        ui.selectable_value(&mut msaa, level, level.label());
+                    }
+                });
+        });
+        if msaa != self.msaa
+            && let Err(e) = self.set_msaa(msaa)
+        {
+            tracing::error!("could not switch to {} MSAA: {e:?}", msaa.label());
+        }
+        self.shader_errors.ui(ctx);
+    }
+
+    /// Copies the computed background into the multisampled color target, since the resolve at
+    /// the end of the geometry pass overwrites the draw image.
+    fn draw_msaa_background(&mut self, cmd: vk::CommandBuffer) -> eyre::Result<()> {
+        let Some(msaa_color_image) = &self.msaa_color_image else {
+            return Ok(());
         };
+        let device = self.vulkan.device();
+        let color_attachments = [color_attachment_info()
+            .view(msaa_color_image.image_view())
+            .call()];
         let draw_extent = self.draw_extent();
+        let rendering_info = vk::RenderingInfo::default()
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent: draw_extent,
+            })
+            .color_attachments(&color_attachments)
+            .layer_count(1);
+        unsafe { device.cmd_begin_rendering(cmd, &rendering_info) };
         unsafe {
-            device.cmd_dispatch(
+            device.cmd_bind_pipeline(
                 cmd,
-                draw_extent.width.div_ceil(16),
-                draw_extent.height.div_ceil(16),
-                1,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.msaa_background_pipeline.pipeline(),
             );
         };
+        let viewport = vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: draw_extent.width as f32,
+            height: draw_extent.height as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        };
+        unsafe { device.cmd_set_viewport(cmd, 0, &[viewport]) };
+        let scissor = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: draw_extent,
+        };
+        unsafe { device.cmd_set_scissor(cmd, 0, &[scissor]) };
+
+        let image_set = self.frames.allocate_frame_descriptor_set(
+            device,
+            self.descriptor_buffer.as_mut(),
+            self.single_image_layout,
+        )?;
+        DescriptorWriter::new()
+            .write_image(
+                0,
+                self.draw_image.image_view(),
+                self.default_samplers.nearest,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            )
+            .update(device, self.descriptor_buffer.as_ref(), image_set)?;
+        image_set.bind(
+            device,
+            self.descriptor_buffer.as_ref(),
+            cmd,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.msaa_background_pipeline.layout(),
+            0,
+        )?;
+        unsafe { device.cmd_draw(cmd, 3, 1, 0, 0) };
+        unsafe { device.cmd_end_rendering(cmd) };
+        Ok(())
     }
 
-    pub(crate) fn build_ui(&mut self, ctx: &egui::Context) {
-        let background_effects_len = self.background_effects.len();
-        let selected = &mut self.background_effects[self.current_background_effect];
-        egui::Window::new("Background").show(ctx, |ui| {
-            ui.label(selected.name());
-            let slider = egui::Slider::new(
-                &mut self.current_background_effect,
-                0..=background_effects_len - 1,
-            );
-            ui.add(slider.text("Effect Index"));
-            vec4_drag_value(ui, &mut selected.data.data1, "data1");
-            vec4_drag_value(ui, &mut selected.data.data2, "data2");
-            vec4_drag_value(ui, &mut selected.data.data3, "data3");
-            vec4_drag_value(ui, &mut selected.data.data4, "data4");
+    /// One attachment per format of [`geometry_color_formats`].
+    fn geometry_color_attachments(&self) -> Vec<vk::RenderingAttachmentInfo<'static>> {
+        let draw_attachment = self.msaa_color_image.as_ref().map_or_else(
+            || {
+                color_attachment_info()
+                    .view(self.draw_image.image_view())
+                    .call()
+            },
+            |msaa_color_image| {
+                color_attachment_info()
+                    .view(msaa_color_image.image_view())
+                    .resolve(self.draw_image.image_view())
+                    .call()
+            },
+        );
+        vec![draw_attachment]
+    }
 
-            affine_ui(ui, &mut self.mesh_matrix, "Mesh Matrix");
-            ui.add(egui::Slider::new(&mut self.render_scale, 0.3..=1.0))
-        });
+    /// Records compute work other than the background, submitting it on the compute queue if
+    /// there is one and into the frame's `cmd` otherwise.
+    fn simulate(&mut self, cmd: vk::CommandBuffer) -> eyre::Result<()> {
+        let device = self.vulkan.device();
+        let frame = self.frames.current_index();
+        let compute_cmd = match &self.async_compute {
+            Some(async_compute) => async_compute.begin(device, frame)?,
+            None => cmd,
+        };
+        self.particles.simulate(
+            device,
+            self.descriptor_buffer.as_ref(),
+            compute_cmd,
+            &[(MESH_NODE, self.mesh_matrix)],
+        )?;
+        if let Some(async_compute) = &mut self.async_compute {
+            // The last frame submitted has released the resources it took over.
+            let timeline = self.frames.timeline();
+            let graphics =
+                timeline.wait_info(vk::PipelineStageFlags2::ALL_COMMANDS, timeline.last());
+            async_compute.submit(device, frame, graphics)?;
+        }
+        Ok(())
     }
 
     fn draw_geometry(&mut self, cmd: vk::CommandBuffer) -> eyre::Result<()> {
         let device = self.vulkan.device();
-        let color_attachment_info = color_attachment_info()
-            .view(self.draw_image.image_view())
-            .call();
+        let color_attachments = self.geometry_color_attachments();
+        let depth_format = self.depth_image.format();
         let depth_attachment = depth_attachment_info()
             .view(self.depth_image.image_view())
+            .layout(depth_attachment_layout(depth_format))
             .call();
-        let color_attachments = [color_attachment_info];
         let draw_extent = self.draw_extent();
         let rendering_info = vk::RenderingInfo::default()
             .render_area(vk::Rect2D {
@@ -371,15 +1130,14 @@ impl Engine {
             .color_attachments(&color_attachments)
             .depth_attachment(&depth_attachment)
             .layer_count(1);
-        unsafe { device.cmd_begin_rendering(cmd, &rendering_info) };
-
-        unsafe {
-            device.cmd_bind_pipeline(
-                cmd,
-                vk::PipelineBindPoint::GRAPHICS,
-                self.mesh_pipeline.pipeline(),
-            );
+        let rendering_info = if format_aspect(depth_format).contains(vk::ImageAspectFlags::STENCIL)
+        {
+            rendering_info.stencil_attachment(&depth_attachment)
+        } else {
+            rendering_info
         };
+        self.particles.acquire(device, cmd);
+        unsafe { device.cmd_begin_rendering(cmd, &rendering_info) };
 
         let viewport = vk::Viewport {
             x: 0.0,
@@ -397,9 +1155,47 @@ impl Engine {
         };
         unsafe { device.cmd_set_scissor(cmd, 0, &[scissor]) };
 
-        let image_set = self
-            .frames
-            .allocate_frame_descriptor_set(device, self.single_image_layout)?;
+        let projection = geometry_projection(draw_extent);
+        self.draw_mesh(cmd, projection, color_attachments.len())?;
+        let global_descriptor = self.write_scene_data(projection)?;
+        self.draw_materials(cmd, global_descriptor, color_attachments.len())?;
+        let device = self.vulkan.device();
+        // Meshes are placed in view space by their matrix, so the particles are too.
+        self.particles.draw(device, cmd, Mat4::IDENTITY, projection);
+        unsafe { device.cmd_end_rendering(cmd) };
+        self.particles.release(device, cmd);
+
+        Ok(())
+    }
+
+    /// Draws the "Mesh Matrix" mesh with the mesh pipeline.
+    fn draw_mesh(
+        &mut self,
+        cmd: vk::CommandBuffer,
+        projection: Mat4,
+        color_attachment_count: usize,
+    ) -> eyre::Result<()> {
+        let device = self.vulkan.device();
+        unsafe {
+            device.cmd_bind_pipeline(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.mesh_pipeline.pipeline(),
+            );
+        };
+        DrawState::default().record(
+            device,
+            self.dynamic_state3.as_ref(),
+            cmd,
+            MeshPipeline::DYNAMIC_STATE,
+            color_attachment_count,
+        )?;
+
+        let image_set = self.frames.allocate_frame_descriptor_set(
+            device,
+            self.descriptor_buffer.as_mut(),
+            self.single_image_layout,
+        )?;
         DescriptorWriter::new()
             .write_image(
                 0,
@@ -408,71 +1204,181 @@ impl Engine {
                 vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
                 vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
             )
-            .update_set(device, image_set);
-        unsafe {
-            device.cmd_bind_descriptor_sets(
-                cmd,
-                vk::PipelineBindPoint::GRAPHICS,
-                self.mesh_pipeline.layout(),
-                0,
-                &[image_set],
-                &[],
-            );
-        };
-        let aspect_ratio = draw_extent.width as f32 / draw_extent.height as f32;
-        let mut projection =
-            Mat4::perspective_rh(f32::to_radians(70.0), aspect_ratio, 10000.0, 0.1);
-        projection.y_axis.y *= -1.0;
+            .update(device, self.descriptor_buffer.as_ref(), image_set)?;
+        image_set.bind(
+            device,
+            self.descriptor_buffer.as_ref(),
+            cmd,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.mesh_pipeline.layout(),
+            0,
+        )?;
         let matrix = projection * self.mesh_matrix;
         let susanne = &self.meshes[2];
-        let push_constants =
-            GPUDrawPushConstants::new(matrix, susanne.mesh_buffers().vertex_buffer_addr());
+        // Drawn from the frame that acquires it on, never waiting for its upload.
+        if self.uploads.is_ready(susanne.mesh_buffers().upload()) {
+            let push_constants =
+                GPUDrawPushConstants::new(matrix, susanne.mesh_buffers().vertex_buffer_addr());
 
-        unsafe {
-            device.cmd_push_constants(
-                cmd,
-                self.mesh_pipeline.layout(),
-                vk::ShaderStageFlags::VERTEX,
-                0,
-                bytemuck::bytes_of(&push_constants),
-            );
-        };
+            unsafe {
+                device.cmd_push_constants(
+                    cmd,
+                    self.mesh_pipeline.layout(),
+                    vk::ShaderStageFlags::VERTEX,
+                    0,
+                    bytemuck::bytes_of(&push_constants),
+                );
+            };
 
-        unsafe {
-            device.cmd_bind_index_buffer(
-                cmd,
-                susanne.mesh_buffers().index_buffer().buffer(),
-                0,
-                vk::IndexType::UINT32,
-            );
-        };
+            if self.mesh_pipeline.vertex_fetch() == VertexFetch::Attributes {
+                unsafe {
+                    device.cmd_bind_vertex_buffers(
+                        cmd,
+                        0,
+                        &[susanne.mesh_buffers().vertex_buffer().buffer()],
+                        &[0],
+                    );
+                };
+            }
+            unsafe {
+                device.cmd_bind_index_buffer(
+                    cmd,
+                    susanne.mesh_buffers().index_buffer().buffer(),
+                    0,
+                    vk::IndexType::UINT32,
+                );
+            };
 
-        unsafe {
-            device.cmd_draw_indexed(
-                cmd,
-                susanne.surfaces()[0].count(),
-                1,
-                susanne.surfaces()[0].start_index(),
-                0,
-                0,
-            );
-        };
-        self.scene_data_buffer = create_scene_data_buffer(&self.allocator, self.scene_data)?;
-        let global_descriptor = self
-            .frames
-            .allocate_frame_descriptor_set(device, self.scene_data_layout)?;
+            unsafe {
+                device.cmd_draw_indexed(
+                    cmd,
+                    susanne.surfaces()[0].count(),
+                    1,
+                    susanne.surfaces()[0].start_index(),
+                    0,
+                    0,
+                );
+            };
+        }
+        Ok(())
+    }
+
+    /// Uploads the scene data for `projection` and returns the frame descriptor set it is bound
+    /// through. Like the mesh pipeline, materials are placed in view space by their matrix.
+    fn write_scene_data(&mut self, projection: Mat4) -> eyre::Result<BoundDescriptor> {
+        let device = self.vulkan.device();
+        self.scene.data.set_camera(Mat4::IDENTITY, projection);
+        self.scene.buffer = create_scene_data_buffer(&self.allocator, self.scene.data)?;
+        let global_descriptor = self.frames.allocate_frame_descriptor_set(
+            device,
+            self.descriptor_buffer.as_mut(),
+            self.scene.layout,
+        )?;
 
         DescriptorWriter::new()
             .write_buffer(
                 0,
-                self.scene_data_buffer.buffer(),
+                self.scene.buffer.buffer(),
                 0,
                 std::mem::size_of::<GPUSceneData>() as u64,
                 vk::DescriptorType::UNIFORM_BUFFER,
             )
-            .update_set(device, global_descriptor);
-        unsafe { device.cmd_end_rendering(cmd) };
+            .update(device, self.descriptor_buffer.as_ref(), global_descriptor)?;
+        Ok(global_descriptor)
+    }
 
+    /// Draws the scene's material draws with the pipeline of their material, which is the default
+    /// variant of their pass while their own builds, and the state of their pass.
+    fn draw_materials(
+        &self,
+        cmd: vk::CommandBuffer,
+        scene_set: BoundDescriptor,
+        color_attachment_count: usize,
+    ) -> eyre::Result<()> {
+        let device = self.vulkan.device();
+        let dynamic_state = self.scene.metal_rough.dynamic_state();
+        let mut bound = vk::Pipeline::null();
+        for draw in &self.scene.draws {
+            let mesh_buffers = self.meshes[draw.mesh].mesh_buffers();
+            let (pipeline, layout) = self.scene.metal_rough.pipeline(&draw.material);
+            if pipeline == vk::Pipeline::null() || !self.uploads.is_ready(mesh_buffers.upload()) {
+                continue;
+            }
+            // Opaque and transparent draws share a pipeline when blending is dynamic.
+            if pipeline != bound {
+                unsafe {
+                    device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, pipeline);
+                };
+                scene_set.bind(
+                    device,
+                    self.descriptor_buffer.as_ref(),
+                    cmd,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    layout,
+                    0,
+                )?;
+                bound = pipeline;
+            }
+            let world_matrix = self.mesh_matrix * Affine3A::from_translation(draw.offset);
+            let push_constants =
+                GPUDrawPushConstants::new(world_matrix.into(), mesh_buffers.vertex_buffer_addr());
+            draw.material.set().bind(
+                device,
+                self.descriptor_buffer.as_ref(),
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                layout,
+                1,
+            )?;
+            unsafe {
+                device.cmd_push_constants(
+                    cmd,
+                    layout,
+                    vk::ShaderStageFlags::VERTEX,
+                    0,
+                    bytemuck::bytes_of(&push_constants),
+                );
+                device.cmd_bind_index_buffer(
+                    cmd,
+                    mesh_buffers.index_buffer().buffer(),
+                    0,
+                    vk::IndexType::UINT32,
+                );
+            };
+            let draw_state = draw.material.pass().draw_state();
+            let mut states = vec![draw_state];
+            if let Some(depth_bias) = self.wireframe_bias {
+                // Edges brighten the surface they are drawn over, pulled in front of it.
+                states.push(DrawState {
+                    polygon_mode: vk::PolygonMode::LINE,
+                    depth_write: false,
+                    blending: Some(Blending::Additive),
+                    depth_bias: Some(depth_bias),
+                    ..draw_state
+                });
+            }
+            for state in states {
+                state.record(
+                    device,
+                    self.dynamic_state3.as_ref(),
+                    cmd,
+                    dynamic_state,
+                    color_attachment_count,
+                )?;
+                for surface in self.meshes[draw.mesh].surfaces() {
+                    unsafe {
+                        device.cmd_draw_indexed(
+                            cmd,
+                            surface.count(),
+                            1,
+                            surface.start_index(),
+                            0,
+                            0,
+                        );
+                    };
+                }
+            }
+        }
         Ok(())
     }
 
@@ -480,17 +1386,7 @@ impl Engine {
         self.swapchain
             .destroy(self.vulkan.device(), &self.vulkan.swapchain_device());
 
-        let PhysicalSize { width, height } = self.window.inner_size();
-
-        self.swapchain = Swapchain::new(
-            width,
-            height,
-            &self.vulkan,
-            swapchain::IMAGE_FORMAT,
-            swapchain::COLOR_SPACE,
-            vk::PresentModeKHR::FIFO,
-            vk::ImageUsageFlags::TRANSFER_DST,
-        )?;
+        self.swapchain = create_swapchain(&self.vulkan, self.window.inner_size())?;
         self.resize_swapchain = false;
         Ok(())
     }
@@ -499,19 +1395,23 @@ impl Engine {
             self.resize_swapchain()?;
         }
         let device = self.vulkan.device();
-        unsafe {
-            device.wait_for_fences(
-                &[self.frames.get_current_frame().render_fence()],
-                true,
-                u64::MAX,
-            )
-        }?;
+        self.frames.wait_current(device)?;
+        self.uploads.flush(device)?;
+        self.uploads.retire(device, &self.allocator)?;
 
-        self.scene_data_buffer.destroy(&self.allocator);
+        self.scene.buffer.destroy(&self.allocator);
 
-        self.frames.clear_frame_descriptor_sets(device)?;
+        self.frames.flush_deletion_queue(device);
+        self.frames
+            .clear_frame_descriptor_sets(device, self.descriptor_buffer.as_mut())?;
+        self.reload_shaders();
+        let device = self.vulkan.device();
+        self.scene.metal_rough.poll_pipelines(
+            device,
+            &mut self.pipeline_builder,
+            &mut self.shader_errors,
+        );
 
-        unsafe { device.reset_fences(&[self.frames.get_current_frame().render_fence()]) }?;
         gui.free_textures()?;
 
         let (primitives, pixels_per_point) = gui.generate_ui(self)?;
@@ -546,7 +1446,72 @@ impl Engine {
         self.present(&swapchain_device, image_index, render_semaphore)?;
 
         self.frames.advance();
+        self.frame_number = self.frame_number.wrapping_add(1);
+
+        Ok(())
+    }
 
+    /// Moves the draw image from the background compute pass to the geometry pass, drawing it
+    /// into the MSAA color image first when multisampling is on, and clears the depth image.
+    fn prepare_geometry_attachments(&mut self, cmd: vk::CommandBuffer) -> eyre::Result<()> {
+        let draw_image = self.draw_image.image();
+        if let Some(msaa_image) = self.msaa_color_image.as_ref().map(AllocatedImage::image) {
+            transition_image(
+                self.vulkan.device(),
+                cmd,
+                draw_image,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+            transition_image(
+                self.vulkan.device(),
+                cmd,
+                msaa_image,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            );
+            self.draw_msaa_background(cmd)?;
+            // The geometry pass loads what the background pass stored.
+            let msaa_barrier = vk::ImageMemoryBarrier2::default()
+                .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+                .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+                .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+                .dst_access_mask(
+                    vk::AccessFlags2::COLOR_ATTACHMENT_READ
+                        | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE,
+                )
+                .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .image(msaa_image)
+                .subresource_range(image_subresource_range(vk::ImageAspectFlags::COLOR));
+            let image_barriers = [msaa_barrier];
+            let dependency = vk::DependencyInfo::default().image_memory_barriers(&image_barriers);
+            unsafe {
+                self.vulkan.device().cmd_pipeline_barrier2(cmd, &dependency);
+            };
+            transition_image(
+                self.vulkan.device(),
+                cmd,
+                draw_image,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            );
+        } else {
+            transition_image(
+                self.vulkan.device(),
+                cmd,
+                draw_image,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            );
+        }
+        transition_image(
+            self.vulkan.device(),
+            cmd,
+            self.depth_image.image(),
+            vk::ImageLayout::UNDEFINED,
+            depth_attachment_layout(self.depth_image.format()),
+        );
         Ok(())
     }
 
@@ -566,6 +1531,7 @@ impl Engine {
         let begin_info = vk::CommandBufferBeginInfo::default()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
         unsafe { self.vulkan.device().begin_command_buffer(cmd, &begin_info) }?;
+        self.uploads.acquire(self.vulkan.device(), cmd)?;
         let draw_image = self.draw_image.image();
         transition_image(
             self.vulkan.device(),
@@ -574,22 +1540,9 @@ impl Engine {
             vk::ImageLayout::UNDEFINED,
             vk::ImageLayout::GENERAL,
         );
-        self.draw_background(cmd);
-        transition_image(
-            self.vulkan.device(),
-            cmd,
-            draw_image,
-            vk::ImageLayout::GENERAL,
-            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-        );
-        transition_image(
-            self.vulkan.device(),
-            cmd,
-            self.depth_image.image(),
-            vk::ImageLayout::UNDEFINED,
-            vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL,
-        );
-
+        self.draw_background(cmd)?;
+        self.simulate(cmd)?;
+        self.prepare_geometry_attachments(cmd)?;
         self.draw_geometry(cmd)?;
         transition_image(
             self.vulkan.device(),
@@ -643,11 +1596,12 @@ impl Engine {
     }
 
     fn submit(
-        &self,
+        &mut self,
         image_index: u32,
         cmd: vk::CommandBuffer,
     ) -> Result<vk::Semaphore, eyre::Error> {
         let device = self.vulkan.device();
+        let value = self.frames.next_submission();
         let current_frame = self.frames.get_current_frame();
         let cmd_info = vk::CommandBufferSubmitInfo::default()
             .command_buffer(cmd)
@@ -659,17 +1613,26 @@ impl Engine {
         );
         let signal_info =
             semaphore_submit_info(vk::PipelineStageFlags2::ALL_GRAPHICS, render_semaphore);
-        let wait_infos = [wait_info];
-        let signal_infos = [signal_info];
+        let mut wait_infos = vec![wait_info];
+        if let Some(async_compute) = &self.async_compute {
+            // Particles are first read, and acquired, by the vertex shader.
+            wait_infos.push(async_compute.wait_info(vk::PipelineStageFlags2::VERTEX_SHADER));
+        }
+        // Orders the acquires recorded this frame after the releases on the transfer queue.
+        wait_infos.extend(self.uploads.wait_info());
+        let signal_infos = [
+            signal_info,
+            self.frames
+                .timeline()
+                .signal_info(vk::PipelineStageFlags2::ALL_COMMANDS, value),
+        ];
         let cmd_infos = [cmd_info];
         let submit_info = vk::SubmitInfo2::default()
             .wait_semaphore_infos(&wait_infos)
             .signal_semaphore_infos(&signal_infos)
             .command_buffer_infos(&cmd_infos);
         let graphics_queue = self.vulkan.graphics_queue();
-        unsafe {
-            device.queue_submit2(graphics_queue, &[submit_info], current_frame.render_fence())
-        }?;
+        unsafe { device.queue_submit2(graphics_queue, &[submit_info], vk::Fence::null()) }?;
         Ok(render_semaphore)
     }
 
@@ -708,9 +1671,16 @@ impl Engine {
 
     pub fn window_event(&mut self, event: &WindowEvent, gui: &mut Gui) {
         let _ = gui.winit_mut().on_window_event(&self.window, event);
-        #[allow(clippy::single_match)]
         match event {
             WindowEvent::Occluded(occluded) => self.render = !occluded,
+            WindowEvent::CursorMoved { position, .. } => {
+                self.background.cursor_position = Vec2::new(position.x as f32, position.y as f32);
+            }
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Left,
+                ..
+            } => self.background.cursor_pressed = *state == ElementState::Pressed,
             _ => {}
         }
     }
@@ -727,7 +1697,9 @@ impl Engine {
         &self.swapchain
     }
 
-    pub const fn immediate_graphics(&self) -> &ImmediateSubmit {
-        &self.immediate_graphics
+    /// Command pool of the current frame, on the graphics queue family. Its command buffer is
+    /// idle until [`Self::render`] records it.
+    pub const fn cmd_pool(&self) -> vk::CommandPool {
+        self.frames.get_current_frame().cmd_pool()
     }
 }
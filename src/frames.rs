@@ -1,7 +1,10 @@
 use ash::vk;
 
 use crate::{
-    descriptors::{DescriptorAllocatorGrowable, PoolSizeRatio},
+    descriptors::{
+        BoundDescriptor, DescriptorAllocatorGrowable, DescriptorBufferAllocator, PoolSizeRatio,
+    },
+    timeline::Timeline,
     utils,
     vulkan::Vulkan,
 };
@@ -11,6 +14,8 @@ pub const FRAMES_IN_FLIGHT: usize = 2;
 pub struct Frames {
     frames: [FrameData; FRAMES_IN_FLIGHT],
     frame_index: usize,
+    /// Signaled by every graphics submission of a frame.
+    timeline: Timeline,
 }
 
 impl Frames {
@@ -21,7 +26,6 @@ impl Frames {
 
         let mut frames = [const { FrameData::uninit() }; FRAMES_IN_FLIGHT];
         let device = vulkan.device();
-        let fence_info = vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
         let semaphore_info = vk::SemaphoreCreateInfo::default();
         for frame_data in &mut frames {
             let pool = unsafe { device.create_command_pool(&pool_info, None) }?;
@@ -30,7 +34,6 @@ impl Frames {
             let buffer = unsafe { device.allocate_command_buffers(&buffer_info) }?;
             frame_data.cmd_pool = pool;
             frame_data.cmd_buffer = buffer[0];
-            frame_data.render_fence = unsafe { device.create_fence(&fence_info, None) }?;
             frame_data.swapchain_semaphore =
                 unsafe { device.create_semaphore(&semaphore_info, None) }?;
 
@@ -45,9 +48,30 @@ impl Frames {
         Ok(Self {
             frames,
             frame_index: 0,
+            timeline: Timeline::new(device)?,
         })
     }
+    /// Blocks until the last frame submitted from the current slot finished, so its resources can
+    /// be reused.
+    pub fn wait_current(&self, device: &ash::Device) -> eyre::Result<()> {
+        self.timeline
+            .wait(device, self.get_current_frame().submitted)
+    }
+    /// Reserves the timeline value the current frame's submission signals.
+    pub fn next_submission(&mut self) -> u64 {
+        let value = self.timeline.next();
+        self.get_current_frame_mut().submitted = value;
+        value
+    }
+    /// Frames are numbered by the value they signal on it, in submission order.
+    pub const fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
 
+    /// Slot of the current frame, in `0..FRAMES_IN_FLIGHT`.
+    pub const fn current_index(&self) -> usize {
+        self.frame_index % FRAMES_IN_FLIGHT
+    }
     pub const fn get_current_frame(&self) -> &FrameData {
         &self.frames[self.frame_index % FRAMES_IN_FLIGHT]
     }
@@ -61,29 +85,71 @@ impl Frames {
         for f in &mut self.frames {
             f.destroy(device);
         }
+        self.timeline.destroy(device);
+    }
+    /// Queues `pipeline` for destruction once the current frame slot comes around again, by which
+    /// point every frame that could have used it has finished.
+    pub fn retire_pipeline(&mut self, pipeline: vk::Pipeline) {
+        self.get_current_frame_mut()
+            .deletion_queue
+            .pipelines
+            .push(pipeline);
     }
+    pub fn flush_deletion_queue(&mut self, device: &ash::Device) {
+        self.get_current_frame_mut().deletion_queue.flush(device);
+    }
+    /// Allocates a set living until the current frame slot comes around again, from
+    /// `descriptor_buffer` when descriptors are written into descriptor buffers.
     pub fn allocate_frame_descriptor_set(
         &mut self,
         device: &ash::Device,
+        descriptor_buffer: Option<&mut DescriptorBufferAllocator>,
         layout: vk::DescriptorSetLayout,
-    ) -> eyre::Result<ash::vk::DescriptorSet> {
-        self.frames[self.frame_index % FRAMES_IN_FLIGHT]
+    ) -> eyre::Result<BoundDescriptor> {
+        if let Some(descriptor_buffer) = descriptor_buffer {
+            let set = descriptor_buffer.allocate_frame(self.current_index(), layout)?;
+            return Ok(BoundDescriptor::Buffer(set));
+        }
+        let set = self.frames[self.frame_index % FRAMES_IN_FLIGHT]
             .frame_descriptors
-            .allocate(device, layout)
+            .allocate(device, layout)?;
+        Ok(BoundDescriptor::Set(set))
     }
-    pub fn clear_frame_descriptor_sets(&mut self, device: &ash::Device) -> eyre::Result<()> {
+    pub fn clear_frame_descriptor_sets(
+        &mut self,
+        device: &ash::Device,
+        descriptor_buffer: Option<&mut DescriptorBufferAllocator>,
+    ) -> eyre::Result<()> {
+        if let Some(descriptor_buffer) = descriptor_buffer {
+            descriptor_buffer.reset(self.current_index());
+        }
         self.frames[self.frame_index % FRAMES_IN_FLIGHT]
             .frame_descriptors
             .clear_pools(device)
     }
 }
 
+#[derive(Default)]
+pub struct DeletionQueue {
+    pipelines: Vec<vk::Pipeline>,
+}
+
+impl DeletionQueue {
+    pub fn flush(&mut self, device: &ash::Device) {
+        for pipeline in self.pipelines.drain(..) {
+            unsafe { device.destroy_pipeline(pipeline, None) };
+        }
+    }
+}
+
 pub struct FrameData {
     cmd_pool: vk::CommandPool,
     cmd_buffer: vk::CommandBuffer,
-    render_fence: vk::Fence,
+    /// Timeline value of the last submission from this slot, 0 before the first one.
+    submitted: u64,
     swapchain_semaphore: vk::Semaphore,
     frame_descriptors: DescriptorAllocatorGrowable,
+    deletion_queue: DeletionQueue,
 }
 
 impl FrameData {
@@ -91,17 +157,20 @@ impl FrameData {
         Self {
             cmd_pool: vk::CommandPool::null(),
             cmd_buffer: vk::CommandBuffer::null(),
-            render_fence: vk::Fence::null(),
+            submitted: 0,
             swapchain_semaphore: vk::Semaphore::null(),
             frame_descriptors: DescriptorAllocatorGrowable::uninit(),
+            deletion_queue: DeletionQueue {
+                pipelines: Vec::new(),
+            },
         }
     }
 
     pub fn destroy(&mut self, device: &ash::Device) {
         unsafe { device.destroy_command_pool(self.cmd_pool, None) };
-        unsafe { device.destroy_fence(self.render_fence, None) };
         unsafe { device.destroy_semaphore(self.swapchain_semaphore, None) };
         self.frame_descriptors.destroy_pools(device);
+        self.deletion_queue.flush(device);
     }
 
     pub const fn cmd_pool(&self) -> vk::CommandPool {
@@ -112,10 +181,6 @@ impl FrameData {
         self.cmd_buffer
     }
 
-    pub const fn render_fence(&self) -> vk::Fence {
-        self.render_fence
-    }
-
     pub const fn swapchain_semaphore(&self) -> vk::Semaphore {
         self.swapchain_semaphore
     }
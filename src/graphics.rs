@@ -1,43 +1,80 @@
+use std::ffi::CStr;
+
 use ash::vk::{self};
-use eyre::eyre;
+use eyre::{OptionExt, eyre};
 
 use crate::{
-    descriptors::DescriptorLayoutBuilder,
+    descriptors::DescriptorBackend,
     mesh::GPUDrawPushConstants,
-    shader::ShaderCompiler,
+    pipeline_description::{LayoutKind, PipelineDescription, RenderTargets},
+    shader::{ShaderCompiler, ShaderDefines, ShaderDependencies, ShaderKind},
+    shader_errors::ShaderErrors,
+    specialization::SpecializationConstants,
     texture::{AllocatedImage, DrawImage},
+    utils::format_aspect,
+    vulkan::Vulkan,
 };
 
+pub const MESH_PIPELINE_NAME: &str = "Mesh Pipeline";
+
+fn fallback_description() -> PipelineDescription {
+    PipelineDescription::fallback(LayoutKind::Mesh, "error.vert", "error.frag")
+}
+
+/// How [`MeshPipeline`] reads vertices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VertexFetch {
+    /// From `vertex_buffer_addr` in the push constants.
+    Pulling,
+    /// From vertex input bindings, for devices and tools where buffer device address is slow.
+    Attributes,
+}
+
+impl VertexFetch {
+    /// Vertex input bindings are opt-in through `VKGUIDE_VERTEX_FETCH=attributes`.
+    pub fn preferred() -> Self {
+        match std::env::var("VKGUIDE_VERTEX_FETCH").as_deref() {
+            Ok("attributes") => Self::Attributes,
+            _ => Self::Pulling,
+        }
+    }
+    /// The [`PipelineDescription`] [`MeshPipeline`] starts with.
+    const fn description(self) -> &'static str {
+        match self {
+            Self::Pulling => "mesh",
+            Self::Attributes => "mesh_attributes",
+        }
+    }
+}
+
 pub struct MeshPipeline {
     pipeline: vk::Pipeline,
     layout: vk::PipelineLayout,
+    pipeline_cache: vk::PipelineCache,
+    samples: vk::SampleCountFlags,
+    description: String,
+    vertex_fetch: VertexFetch,
+    descriptor_backend: DescriptorBackend,
+    enabled_stages: vk::ShaderStageFlags,
+    dependencies: ShaderDependencies,
 }
 
 impl MeshPipeline {
+    /// Set per draw with [`DrawState::record`].
+    pub const DYNAMIC_STATE: &[DynamicGroup] = &[DynamicGroup::Rasterization, DynamicGroup::Depth];
+
     pub fn new(
-        device: &ash::Device,
+        vulkan: &Vulkan,
         shader_compiler: &ShaderCompiler,
-        draw_image: &DrawImage,
+        color_formats: &[vk::Format],
         depth_image: &AllocatedImage,
         image_layout: vk::DescriptorSetLayout,
+        pipeline_cache: vk::PipelineCache,
+        shader_errors: &mut ShaderErrors,
     ) -> eyre::Result<Self> {
-        let vertex_src = include_str!("../shaders/colored_triangle_mesh.vert");
-        let vertex_shader = shader_compiler.create_shader_module_from_str(
-            device,
-            vertex_src,
-            shaderc::ShaderKind::Vertex,
-            "colored_triangle_mesh.vert",
-            "main",
-        )?;
-
-        let frag_src = include_str!("../shaders/tex_image.frag");
-        let frag_shader = shader_compiler.create_shader_module_from_str(
-            device,
-            frag_src,
-            shaderc::ShaderKind::Fragment,
-            "tex_image.frag",
-            "main",
-        )?;
+        let device = vulkan.device();
+        let descriptor_backend = vulkan.descriptor_backend();
+        let enabled_stages = vulkan.graphics_stages();
         let push_constant = vk::PushConstantRange::default()
             .size(std::mem::size_of::<GPUDrawPushConstants>() as u32)
             .stage_flags(vk::ShaderStageFlags::VERTEX);
@@ -49,23 +86,321 @@ impl MeshPipeline {
             .set_layouts(&set_layouts);
         let layout = unsafe { device.create_pipeline_layout(&layout_info, None) }?;
 
+        let description = VertexFetch::preferred().description().to_owned();
+        let create = |description: &PipelineDescription| {
+            Self::create_pipeline(
+                device,
+                shader_compiler,
+                layout,
+                pipeline_cache,
+                descriptor_backend,
+                enabled_stages,
+                description,
+                color_formats,
+                depth_image,
+            )
+        };
+        let created = PipelineDescription::load(&description, LayoutKind::Mesh).and_then(
+            |(loaded, mut files)| {
+                let (pipeline, dependencies) = create(&loaded)?;
+                files.extend(&dependencies);
+                Ok((pipeline, files, loaded.vertex_fetch()))
+            },
+        );
+        let created = shader_errors.or_fallback(MESH_PIPELINE_NAME, created, || {
+            let (pipeline, _) = create(&fallback_description())?;
+            Ok((
+                pipeline,
+                ShaderDependencies::unknown(),
+                VertexFetch::Pulling,
+            ))
+        });
+        let (pipeline, dependencies, vertex_fetch) = match created {
+            Ok(created) => created,
+            Err(e) => {
+                unsafe { device.destroy_pipeline_layout(layout, None) };
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            pipeline,
+            layout,
+            pipeline_cache,
+            samples: depth_image.samples(),
+            description,
+            vertex_fetch,
+            descriptor_backend,
+            enabled_stages,
+            dependencies,
+        })
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn create_pipeline(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        layout: vk::PipelineLayout,
+        pipeline_cache: vk::PipelineCache,
+        descriptor_backend: DescriptorBackend,
+        enabled_stages: vk::ShaderStageFlags,
+        description: &PipelineDescription,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+    ) -> eyre::Result<(vk::Pipeline, ShaderDependencies)> {
+        let targets = RenderTargets {
+            color_formats,
+            depth_format: depth_image.format(),
+            samples: depth_image.samples(),
+        };
+        description.create(
+            device,
+            shader_compiler,
+            layout,
+            pipeline_cache,
+            descriptor_backend,
+            enabled_stages,
+            &targets,
+            Self::DYNAMIC_STATE,
+            &|_| ShaderDefines::new(),
+        )
+    }
+    /// Rebuilds the pipeline from the current shader sources, handing the old one to `retire`.
+    /// On failure the current pipeline stays in use, unless the render targets changed sample
+    /// count, in which case the fallback shaders are used and the error is still returned.
+    pub fn reload(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+        retire: impl FnOnce(vk::Pipeline),
+    ) -> eyre::Result<()> {
+        let create = |description: &PipelineDescription| {
+            Self::create_pipeline(
+                device,
+                shader_compiler,
+                self.layout,
+                self.pipeline_cache,
+                self.descriptor_backend,
+                self.enabled_stages,
+                description,
+                color_formats,
+                depth_image,
+            )
+        };
+        let created = PipelineDescription::load(&self.description, LayoutKind::Mesh).and_then(
+            |(loaded, mut files)| {
+                let (pipeline, dependencies) = create(&loaded)?;
+                files.extend(&dependencies);
+                Ok((pipeline, files, loaded.vertex_fetch()))
+            },
+        );
+        let (result, (pipeline, dependencies, vertex_fetch)) = match created {
+            Ok(created) => (Ok(()), created),
+            Err(e) if depth_image.samples() != self.samples => {
+                let (pipeline, _) = create(&fallback_description())?;
+                (
+                    Err(e),
+                    (
+                        pipeline,
+                        ShaderDependencies::unknown(),
+                        VertexFetch::Pulling,
+                    ),
+                )
+            }
+            Err(e) => return Err(e),
+        };
+        retire(std::mem::replace(&mut self.pipeline, pipeline));
+        self.samples = depth_image.samples();
+        self.vertex_fetch = vertex_fetch;
+        self.dependencies = dependencies;
+        result
+    }
+    /// Switches to the [`PipelineDescription`] `name`, keeping the current one if it fails to
+    /// build.
+    pub fn set_description(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        name: &str,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+        retire: impl FnOnce(vk::Pipeline),
+    ) -> eyre::Result<()> {
+        let previous = std::mem::replace(&mut self.description, name.to_owned());
+        self.reload(device, shader_compiler, color_formats, depth_image, retire)
+            .inspect_err(|_| self.description = previous)
+    }
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+    pub fn destroy(&mut self, device: &ash::Device) {
+        unsafe { device.destroy_pipeline_layout(self.layout, None) };
+        unsafe { device.destroy_pipeline(self.pipeline, None) };
+    }
+
+    pub const fn pipeline(&self) -> vk::Pipeline {
+        self.pipeline
+    }
+
+    pub const fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+
+    pub const fn dependencies(&self) -> &ShaderDependencies {
+        &self.dependencies
+    }
+
+    pub const fn vertex_fetch(&self) -> VertexFetch {
+        self.vertex_fetch
+    }
+}
+
+pub const MSAA_BACKGROUND_PIPELINE_NAME: &str = "MSAA Background Pipeline";
+const MSAA_BACKGROUND_SHADERS: [&str; 2] = ["fullscreen.vert", "copy_image.frag"];
+const MSAA_BACKGROUND_FALLBACK_SHADERS: [&str; 2] = ["fullscreen.vert", "error.frag"];
+
+/// Draws the background computed into the [`DrawImage`] into the multisampled color target, since
+/// compute shaders cannot write to it and the resolve would otherwise overwrite the background.
+pub struct MsaaBackgroundPipeline {
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    pipeline_cache: vk::PipelineCache,
+    samples: vk::SampleCountFlags,
+    descriptor_backend: DescriptorBackend,
+    dependencies: ShaderDependencies,
+}
+
+impl MsaaBackgroundPipeline {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        draw_image: &DrawImage,
+        samples: vk::SampleCountFlags,
+        image_layout: vk::DescriptorSetLayout,
+        descriptor_backend: DescriptorBackend,
+        pipeline_cache: vk::PipelineCache,
+        shader_errors: &mut ShaderErrors,
+    ) -> eyre::Result<Self> {
+        let set_layouts = [image_layout];
+        let layout_info = vk::PipelineLayoutCreateInfo::default().set_layouts(&set_layouts);
+        let layout = unsafe { device.create_pipeline_layout(&layout_info, None) }?;
+
+        let create = |shaders| {
+            Self::create_pipeline(
+                device,
+                shader_compiler,
+                layout,
+                pipeline_cache,
+                descriptor_backend,
+                shaders,
+                draw_image,
+                samples,
+            )
+        };
+        let created = shader_errors.or_fallback(
+            MSAA_BACKGROUND_PIPELINE_NAME,
+            create(MSAA_BACKGROUND_SHADERS),
+            || {
+                let (pipeline, _) = create(MSAA_BACKGROUND_FALLBACK_SHADERS)?;
+                Ok((pipeline, ShaderDependencies::unknown()))
+            },
+        );
+        let (pipeline, dependencies) = match created {
+            Ok(created) => created,
+            Err(e) => {
+                unsafe { device.destroy_pipeline_layout(layout, None) };
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            pipeline,
+            layout,
+            pipeline_cache,
+            samples,
+            descriptor_backend,
+            dependencies,
+        })
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn create_pipeline(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        layout: vk::PipelineLayout,
+        pipeline_cache: vk::PipelineCache,
+        descriptor_backend: DescriptorBackend,
+        [vertex, fragment]: [&str; 2],
+        draw_image: &DrawImage,
+        samples: vk::SampleCountFlags,
+    ) -> eyre::Result<(vk::Pipeline, ShaderDependencies)> {
+        let (vertex_shader, mut dependencies) =
+            shader_compiler.load_shader_module(device, vertex, ShaderKind::Vertex)?;
+        let (frag_shader, frag_dependencies) =
+            match shader_compiler.load_shader_module(device, fragment, ShaderKind::Fragment) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    unsafe { device.destroy_shader_module(vertex_shader, None) };
+                    return Err(e);
+                }
+            };
+        dependencies.extend(&frag_dependencies);
+
         let pipeline = GraphicsPipelineInfo::builder()
             .layout(layout)
-            .shaders([vertex_shader, frag_shader])
+            .stages(&[
+                ShaderStage::new(ShaderKind::Vertex, vertex_shader),
+                ShaderStage::new(ShaderKind::Fragment, frag_shader),
+            ])
             .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
             .polygon_mode(vk::PolygonMode::FILL)
             .cull_mode(vk::CullModeFlags::NONE)
             .front_face(vk::FrontFace::CLOCKWISE)
-            .color_attachment_format(draw_image.format())
-            .depth_format(depth_image.format())
-            .depth_enabled(true)
-            .blending(Blending::Alpha)
+            .color_attachments(&[ColorAttachment::new(draw_image.format())])
+            .depth_format(vk::Format::UNDEFINED)
+            .depth_enabled(false)
+            .samples(samples)
+            .pipeline_cache(pipeline_cache)
+            .flags(descriptor_backend.pipeline_create_flags())
             .build()
-            .create(device)?;
+            .create(device);
 
         unsafe { device.destroy_shader_module(vertex_shader, None) };
         unsafe { device.destroy_shader_module(frag_shader, None) };
-        Ok(Self { pipeline, layout })
+        Ok((pipeline?, dependencies))
+    }
+    /// Same as [`MeshPipeline::reload`], for a new sample count or changed shader sources.
+    pub fn reload(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        draw_image: &DrawImage,
+        samples: vk::SampleCountFlags,
+        retire: impl FnOnce(vk::Pipeline),
+    ) -> eyre::Result<()> {
+        let create = |shaders| {
+            Self::create_pipeline(
+                device,
+                shader_compiler,
+                self.layout,
+                self.pipeline_cache,
+                self.descriptor_backend,
+                shaders,
+                draw_image,
+                samples,
+            )
+        };
+        let (result, (pipeline, dependencies)) = match create(MSAA_BACKGROUND_SHADERS) {
+            Ok(created) => (Ok(()), created),
+            Err(e) if samples != self.samples => {
+                let (pipeline, _) = create(MSAA_BACKGROUND_FALLBACK_SHADERS)?;
+                (Err(e), (pipeline, ShaderDependencies::unknown()))
+            }
+            Err(e) => return Err(e),
+        };
+        retire(std::mem::replace(&mut self.pipeline, pipeline));
+        self.samples = samples;
+        self.dependencies = dependencies;
+        result
     }
     pub fn destroy(&mut self, device: &ash::Device) {
         unsafe { device.destroy_pipeline_layout(self.layout, None) };
@@ -79,42 +414,429 @@ impl MeshPipeline {
     pub const fn layout(&self) -> vk::PipelineLayout {
         self.layout
     }
+
+    pub const fn dependencies(&self) -> &ShaderDependencies {
+        &self.dependencies
+    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Blending {
     Additive,
     Alpha,
 }
 
+impl Blending {
+    const fn equation(self) -> vk::ColorBlendEquationEXT {
+        let dst_color_blend_factor = match self {
+            // src.rgb * src.a + dst.rgb * 1.0
+            Self::Additive => vk::BlendFactor::ONE,
+            // src.rgb * src.a + dst.rgb * (1.0 - dst.a)
+            Self::Alpha => vk::BlendFactor::ONE_MINUS_DST_ALPHA,
+        };
+        vk::ColorBlendEquationEXT {
+            src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+            dst_color_blend_factor,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+            alpha_blend_op: vk::BlendOp::ADD,
+        }
+    }
+    fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        let equation = self.equation();
+        vk::PipelineColorBlendAttachmentState::default()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .blend_enable(true)
+            .src_color_blend_factor(equation.src_color_blend_factor)
+            .dst_color_blend_factor(equation.dst_color_blend_factor)
+            .color_blend_op(equation.color_blend_op)
+            .src_alpha_blend_factor(equation.src_alpha_blend_factor)
+            .dst_alpha_blend_factor(equation.dst_alpha_blend_factor)
+            .alpha_blend_op(equation.alpha_blend_op)
+    }
+}
+
+/// Pipeline state left out of a pipeline and set per draw through [`DrawState::record`], so that
+/// one pipeline serves draws that would otherwise need their own permutation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicGroup {
+    /// Cull mode and front face.
+    Rasterization,
+    /// Depth test, depth write and compare op.
+    Depth,
+    /// Depth bias enable, core since Vulkan 1.3 (`VK_EXT_extended_dynamic_state2`), and factors.
+    DepthBias,
+    /// Blend enable and equation of every color attachment. Needs
+    /// `VK_EXT_extended_dynamic_state3`.
+    Blending,
+    /// Needs `VK_EXT_extended_dynamic_state3`.
+    PolygonMode,
+}
+
+impl DynamicGroup {
+    const fn states(self) -> &'static [vk::DynamicState] {
+        match self {
+            Self::Rasterization => &[vk::DynamicState::CULL_MODE, vk::DynamicState::FRONT_FACE],
+            Self::Depth => &[
+                vk::DynamicState::DEPTH_TEST_ENABLE,
+                vk::DynamicState::DEPTH_WRITE_ENABLE,
+                vk::DynamicState::DEPTH_COMPARE_OP,
+            ],
+            Self::DepthBias => &[
+                vk::DynamicState::DEPTH_BIAS_ENABLE,
+                vk::DynamicState::DEPTH_BIAS,
+            ],
+            Self::Blending => &[
+                vk::DynamicState::COLOR_BLEND_ENABLE_EXT,
+                vk::DynamicState::COLOR_BLEND_EQUATION_EXT,
+            ],
+            Self::PolygonMode => &[vk::DynamicState::POLYGON_MODE_EXT],
+        }
+    }
+}
+
+/// Values for the [`DynamicGroup`]s of the bound pipeline. Blending applies to the first color
+/// attachment, like [`ColorAttachment::with_formats`].
+#[derive(Debug, Clone, Copy)]
+pub struct DrawState {
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare_op: vk::CompareOp,
+    pub blending: Option<Blending>,
+    pub polygon_mode: vk::PolygonMode,
+    /// Constant and slope factors, added to the depth of every fragment.
+    pub depth_bias: Option<(f32, f32)>,
+}
+
+impl Default for DrawState {
+    fn default() -> Self {
+        Self {
+            cull_mode: vk::CullModeFlags::NONE,
+            front_face: vk::FrontFace::CLOCKWISE,
+            depth_test: true,
+            depth_write: true,
+            depth_compare_op: vk::CompareOp::GREATER_OR_EQUAL,
+            blending: None,
+            polygon_mode: vk::PolygonMode::FILL,
+            depth_bias: None,
+        }
+    }
+}
+
+impl DrawState {
+    /// Sets the state of each of `groups` on `cmd`. `dynamic_state3` is required for
+    /// [`DynamicGroup::Blending`] and [`DynamicGroup::PolygonMode`].
+    pub fn record(
+        &self,
+        device: &ash::Device,
+        dynamic_state3: Option<&ash::ext::extended_dynamic_state3::Device>,
+        cmd: vk::CommandBuffer,
+        groups: &[DynamicGroup],
+        color_attachment_count: usize,
+    ) -> eyre::Result<()> {
+        for group in groups {
+            match group {
+                DynamicGroup::Rasterization => unsafe {
+                    device.cmd_set_cull_mode(cmd, self.cull_mode);
+                    device.cmd_set_front_face(cmd, self.front_face);
+                },
+                DynamicGroup::Depth => unsafe {
+                    device.cmd_set_depth_test_enable(cmd, self.depth_test);
+                    device.cmd_set_depth_write_enable(cmd, self.depth_write);
+                    device.cmd_set_depth_compare_op(cmd, self.depth_compare_op);
+                },
+                DynamicGroup::DepthBias => unsafe {
+                    let (constant, slope) = self.depth_bias.unwrap_or_default();
+                    device.cmd_set_depth_bias_enable(cmd, self.depth_bias.is_some());
+                    device.cmd_set_depth_bias(cmd, constant, 0.0, slope);
+                },
+                DynamicGroup::Blending => {
+                    let dynamic_state3 = dynamic_state3
+                        .ok_or_eyre("dynamic blending needs VK_EXT_extended_dynamic_state3")?;
+                    let enables: Vec<_> = (0..color_attachment_count)
+                        .map(|i| vk::Bool32::from(i == 0 && self.blending.is_some()))
+                        .collect();
+                    let equation = self.blending.unwrap_or(Blending::Alpha).equation();
+                    let equations = vec![equation; color_attachment_count];
+                    unsafe {
+                        dynamic_state3.cmd_set_color_blend_enable(cmd, 0, &enables);
+                        dynamic_state3.cmd_set_color_blend_equation(cmd, 0, &equations);
+                    };
+                }
+                DynamicGroup::PolygonMode => {
+                    let dynamic_state3 = dynamic_state3
+                        .ok_or_eyre("dynamic polygon mode needs VK_EXT_extended_dynamic_state3")?;
+                    unsafe { dynamic_state3.cmd_set_polygon_mode(cmd, self.polygon_mode) };
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format and blend mode of one color attachment, in the order of the fragment shader outputs.
+#[derive(Clone, Copy)]
+pub struct ColorAttachment {
+    format: vk::Format,
+    blending: Option<Blending>,
+}
+
+impl ColorAttachment {
+    pub const fn new(format: vk::Format) -> Self {
+        Self {
+            format,
+            blending: None,
+        }
+    }
+    pub const fn blending(self, blending: Blending) -> Self {
+        Self {
+            blending: Some(blending),
+            ..self
+        }
+    }
+    /// Blends into the first attachment only, the others (ids, normals...) are overwritten.
+    pub fn with_formats(formats: &[vk::Format], blending: Blending) -> Vec<Self> {
+        formats
+            .iter()
+            .enumerate()
+            .map(|(i, format)| {
+                let attachment = Self::new(*format);
+                if i == 0 {
+                    attachment.blending(blending)
+                } else {
+                    attachment
+                }
+            })
+            .collect()
+    }
+    fn blend_state(self) -> vk::PipelineColorBlendAttachmentState {
+        self.blending
+            .map_or_else(disable_blending, Blending::attachment_state)
+    }
+}
+
+/// A vertex struct read through a vertex input binding, one attribute per location.
+pub trait VertexLayout: Sized {
+    /// Format and byte offset of each attribute, in location order.
+    const ATTRIBUTES: &'static [(vk::Format, u32)];
+}
+
+/// Vertex input bindings and attributes, for pipelines fed with `cmd_bind_vertex_buffers` rather
+/// than pulling vertices through a buffer device address.
+#[derive(Debug, Default, Clone)]
+pub struct VertexInput {
+    bindings: Vec<vk::VertexInputBindingDescription>,
+    attributes: Vec<vk::VertexInputAttributeDescription>,
+}
+
+impl VertexInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn binding(mut self, binding: u32, stride: u32, input_rate: vk::VertexInputRate) -> Self {
+        self.bindings.push(
+            vk::VertexInputBindingDescription::default()
+                .binding(binding)
+                .stride(stride)
+                .input_rate(input_rate),
+        );
+        self
+    }
+    pub fn attribute(
+        mut self,
+        location: u32,
+        binding: u32,
+        format: vk::Format,
+        offset: u32,
+    ) -> Self {
+        self.attributes.push(
+            vk::VertexInputAttributeDescription::default()
+                .location(location)
+                .binding(binding)
+                .format(format)
+                .offset(offset),
+        );
+        self
+    }
+    /// Adds a per-vertex `binding` for `V`, its attributes taking the locations after the ones
+    /// already added.
+    pub fn layout<V: VertexLayout>(self, binding: u32) -> Self {
+        let first_location = self.attributes.len() as u32;
+        let input = self.binding(
+            binding,
+            std::mem::size_of::<V>() as u32,
+            vk::VertexInputRate::VERTEX,
+        );
+        V::ATTRIBUTES.iter().zip(first_location..).fold(
+            input,
+            |input, ((format, offset), location)| {
+                input.attribute(location, binding, *format, *offset)
+            },
+        )
+    }
+    fn create_info(&self) -> vk::PipelineVertexInputStateCreateInfo<'_> {
+        vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_binding_descriptions(&self.bindings)
+            .vertex_attribute_descriptions(&self.attributes)
+    }
+}
+
+/// Stencil test and ops for front and back facing primitives, e.g. to mark an object for an outline
+/// or mask a portal. Needs a depth format with a stencil aspect.
+#[derive(Clone, Copy)]
+pub struct StencilOps {
+    pub front: vk::StencilOpState,
+    pub back: vk::StencilOpState,
+}
+
+/// A shader module bound to one stage of a graphics pipeline, with its entry point and
+/// specialization constants.
+#[derive(Clone, Copy)]
+pub struct ShaderStage<'a> {
+    kind: ShaderKind,
+    module: vk::ShaderModule,
+    entry_point: &'a CStr,
+    specialization: Option<&'a SpecializationConstants>,
+}
+
+impl<'a> ShaderStage<'a> {
+    pub const fn new(kind: ShaderKind, module: vk::ShaderModule) -> Self {
+        Self {
+            kind,
+            module,
+            entry_point: c"main",
+            specialization: None,
+        }
+    }
+    pub const fn entry_point(self, entry_point: &'a CStr) -> Self {
+        Self {
+            entry_point,
+            ..self
+        }
+    }
+    pub const fn specialization(self, specialization: &'a SpecializationConstants) -> Self {
+        Self {
+            specialization: Some(specialization),
+            ..self
+        }
+    }
+    fn create_info<'b>(
+        &self,
+        specialization: Option<&'b vk::SpecializationInfo<'b>>,
+    ) -> vk::PipelineShaderStageCreateInfo<'b>
+    where
+        'a: 'b,
+    {
+        let info = vk::PipelineShaderStageCreateInfo::default()
+            .module(self.module)
+            .name(self.entry_point)
+            .stage(self.kind.stage());
+        specialization.map_or(info, |specialization| {
+            info.specialization_info(specialization)
+        })
+    }
+}
+
+/// Checks that shaders of `kinds` form a pipeline Vulkan accepts: either vertex (with optional
+/// tessellation and geometry) or mesh (with optional task) geometry, each stage at most once, and
+/// an optional fragment stage, e.g. none for a depth prepass. Every stage must be in
+/// `enabled_stages`, the stages the device was created with.
+pub fn validate_stages(
+    kinds: &[ShaderKind],
+    topology: vk::PrimitiveTopology,
+    patch_control_points: Option<u32>,
+    enabled_stages: vk::ShaderStageFlags,
+) -> eyre::Result<()> {
+    let mut present = vk::ShaderStageFlags::empty();
+    for kind in kinds {
+        let flag = kind.stage();
+        if flag == vk::ShaderStageFlags::COMPUTE {
+            return Err(eyre!(
+                "compute shaders cannot be part of a graphics pipeline"
+            ));
+        }
+        if !enabled_stages.contains(flag) {
+            return Err(eyre!("{kind:?} shaders are not supported by the device"));
+        }
+        if present.contains(flag) {
+            return Err(eyre!("{kind:?} stage given more than once"));
+        }
+        present |= flag;
+    }
+    let vertex = present.contains(vk::ShaderStageFlags::VERTEX);
+    let mesh = present.contains(vk::ShaderStageFlags::MESH_EXT);
+    if vertex == mesh {
+        return Err(eyre!(
+            "a graphics pipeline needs either a vertex or a mesh shader"
+        ));
+    }
+    let vertex_only = vk::ShaderStageFlags::TESSELLATION_CONTROL
+        | vk::ShaderStageFlags::TESSELLATION_EVALUATION
+        | vk::ShaderStageFlags::GEOMETRY;
+    if mesh && present.intersects(vertex_only) {
+        return Err(eyre!(
+            "mesh shaders cannot be combined with tessellation or geometry"
+        ));
+    }
+    if vertex && present.contains(vk::ShaderStageFlags::TASK_EXT) {
+        return Err(eyre!("task shaders need a mesh shader"));
+    }
+    let tessellation =
+        vk::ShaderStageFlags::TESSELLATION_CONTROL | vk::ShaderStageFlags::TESSELLATION_EVALUATION;
+    if present.intersects(tessellation) {
+        if !present.contains(tessellation) {
+            return Err(eyre!(
+                "tessellation needs both control and evaluation shaders"
+            ));
+        }
+        if topology != vk::PrimitiveTopology::PATCH_LIST || patch_control_points.is_none() {
+            return Err(eyre!(
+                "tessellation needs the patch list topology and patch control points"
+            ));
+        }
+    }
+    Ok(())
+}
+
 #[derive(Clone, Copy, bon::Builder)]
-pub struct GraphicsPipelineInfo {
-    shaders: [vk::ShaderModule; 2],
+pub struct GraphicsPipelineInfo<'a> {
+    stages: &'a [ShaderStage<'a>],
+    /// Vertices are pulled through buffer device addresses when unset.
+    vertex_input: Option<&'a VertexInput>,
     topology: vk::PrimitiveTopology,
+    /// Vertices per patch, required with tessellation shaders.
+    patch_control_points: Option<u32>,
     polygon_mode: vk::PolygonMode,
     cull_mode: vk::CullModeFlags,
     front_face: vk::FrontFace,
-    color_attachment_format: vk::Format,
+    color_attachments: &'a [ColorAttachment],
     depth_format: vk::Format,
     layout: vk::PipelineLayout,
     depth_enabled: bool,
     depth_write_enabled: Option<bool>,
     depth_compare_op: Option<vk::CompareOp>,
-    blending: Option<Blending>,
+    stencil: Option<StencilOps>,
+    /// State set per draw with [`DrawState::record`] instead of baked in.
+    #[builder(default)]
+    dynamic: &'a [DynamicGroup],
+    #[builder(default = vk::SampleCountFlags::TYPE_1)]
+    samples: vk::SampleCountFlags,
+    #[builder(default)]
+    pipeline_cache: vk::PipelineCache,
+    /// [`DescriptorBackend::pipeline_create_flags`] of the backend the layout's sets live in.
+    #[builder(default)]
+    flags: vk::PipelineCreateFlags,
+    /// [`Vulkan::graphics_stages`](crate::vulkan::Vulkan::graphics_stages), only needed for
+    /// stages other than vertex and fragment.
+    #[builder(default = vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)]
+    enabled_stages: vk::ShaderStageFlags,
 }
 
-impl GraphicsPipelineInfo {
-    fn shader_stages(&self) -> [vk::PipelineShaderStageCreateInfo<'_>; 2] {
-        let vertex = vk::PipelineShaderStageCreateInfo::default()
-            .module(self.shaders[0])
-            .name(c"main")
-            .stage(vk::ShaderStageFlags::VERTEX);
-        let fragment = vk::PipelineShaderStageCreateInfo::default()
-            .module(self.shaders[1])
-            .name(c"main")
-            .stage(vk::ShaderStageFlags::FRAGMENT);
-        [vertex, fragment]
-    }
+impl GraphicsPipelineInfo<'_> {
     fn input_assembly(&self) -> vk::PipelineInputAssemblyStateCreateInfo<'_> {
         vk::PipelineInputAssemblyStateCreateInfo::default()
             .topology(self.topology)
@@ -127,49 +849,96 @@ impl GraphicsPipelineInfo {
             .cull_mode(self.cull_mode)
             .front_face(self.front_face)
     }
+    fn depth_stencil(&self) -> vk::PipelineDepthStencilStateCreateInfo<'_> {
+        let depth_stencil_state = if self.depth_enabled {
+            enable_depth_test(
+                self.depth_write_enabled.unwrap_or(true),
+                self.depth_compare_op
+                    .unwrap_or(vk::CompareOp::GREATER_OR_EQUAL),
+            )
+        } else {
+            disable_depth_test()
+        };
+        self.stencil.map_or(depth_stencil_state, |stencil| {
+            depth_stencil_state
+                .stencil_test_enable(true)
+                .front(stencil.front)
+                .back(stencil.back)
+        })
+    }
 
     pub fn create(&self, device: &ash::Device) -> eyre::Result<vk::Pipeline> {
+        let kinds: Vec<_> = self.stages.iter().map(|stage| stage.kind).collect();
+        validate_stages(
+            &kinds,
+            self.topology,
+            self.patch_control_points,
+            self.enabled_stages,
+        )?;
         let viewport_state = vk::PipelineViewportStateCreateInfo::default()
             .scissor_count(1)
             .viewport_count(1);
 
-        let color_attachment =
-            self.blending
-                .map_or_else(disable_blending, |blending| match blending {
-                    Blending::Additive => additive_blending(),
-                    Blending::Alpha => alpha_blending(),
-                });
-        let attachments = [color_attachment];
+        let attachments: Vec<_> = self
+            .color_attachments
+            .iter()
+            .map(|attachment| attachment.blend_state())
+            .collect();
         let color_blend = vk::PipelineColorBlendStateCreateInfo::default()
             .logic_op_enable(false)
             .logic_op(vk::LogicOp::COPY)
             .attachments(&attachments);
-        let vertex_info = vk::PipelineVertexInputStateCreateInfo::default();
+        let vertex_info = self
+            .vertex_input
+            .map(VertexInput::create_info)
+            .unwrap_or_default();
 
-        let color_attachment_formats = [self.color_attachment_format];
+        let color_attachment_formats: Vec<_> = self
+            .color_attachments
+            .iter()
+            .map(|attachment| attachment.format)
+            .collect();
+        // A depth format with a stencil aspect is also bound as the stencil attachment.
+        let stencil_format =
+            if format_aspect(self.depth_format).contains(vk::ImageAspectFlags::STENCIL) {
+                self.depth_format
+            } else {
+                vk::Format::UNDEFINED
+            };
         let mut render_info = vk::PipelineRenderingCreateInfo::default()
             .color_attachment_formats(&color_attachment_formats)
-            .depth_attachment_format(self.depth_format);
+            .depth_attachment_format(self.depth_format)
+            .stencil_attachment_format(stencil_format);
 
-        let shader_stages = self.shader_stages();
+        let specializations: Vec<_> = self
+            .stages
+            .iter()
+            .map(|stage| stage.specialization.map(SpecializationConstants::info))
+            .collect();
+        let shader_stages: Vec<_> = self
+            .stages
+            .iter()
+            .zip(&specializations)
+            .map(|(stage, specialization)| stage.create_info(specialization.as_ref()))
+            .collect();
         let input_assembly = self.input_assembly();
         let rasterizer = self.rasterizer();
-        let multisampling = disable_multisampling();
-        let depth_stencil_state = if self.depth_enabled {
-            enable_depth_test(
-                self.depth_write_enabled.unwrap_or(true),
-                self.depth_compare_op
-                    .unwrap_or(vk::CompareOp::GREATER_OR_EQUAL),
-            )
-        } else {
-            disable_depth_test()
-        };
+        let multisampling = multisampling(self.samples);
+        let depth_stencil_state = self.depth_stencil();
 
-        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_states: Vec<_> = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR]
+            .into_iter()
+            .chain(
+                self.dynamic
+                    .iter()
+                    .flat_map(|group| group.states().iter().copied()),
+            )
+            .collect();
         let dynamic_state =
             vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_states);
 
         let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+            .flags(self.flags)
             .push_next(&mut render_info)
             .stages(&shader_stages)
             .vertex_input_state(&vertex_info)
@@ -181,8 +950,14 @@ impl GraphicsPipelineInfo {
             .depth_stencil_state(&depth_stencil_state)
             .layout(self.layout)
             .dynamic_state(&dynamic_state);
+        let tessellation = self.patch_control_points.map(|points| {
+            vk::PipelineTessellationStateCreateInfo::default().patch_control_points(points)
+        });
+        let pipeline_info = tessellation.as_ref().map_or(pipeline_info, |tessellation| {
+            pipeline_info.tessellation_state(tessellation)
+        });
         let pipeline = match unsafe {
-            device.create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+            device.create_graphics_pipelines(self.pipeline_cache, &[pipeline_info], None)
         } {
             Ok(pipelines) => pipelines[0],
             Err((_, e)) => return Err(eyre!("{e}")),
@@ -191,10 +966,10 @@ impl GraphicsPipelineInfo {
     }
 }
 
-fn disable_multisampling<'a>() -> vk::PipelineMultisampleStateCreateInfo<'a> {
+fn multisampling<'a>(samples: vk::SampleCountFlags) -> vk::PipelineMultisampleStateCreateInfo<'a> {
     vk::PipelineMultisampleStateCreateInfo::default()
         .sample_shading_enable(false)
-        .rasterization_samples(vk::SampleCountFlags::TYPE_1)
+        .rasterization_samples(samples)
         .min_sample_shading(1.0)
         .sample_mask(&[])
         .alpha_to_coverage_enable(false)
@@ -207,31 +982,6 @@ fn disable_blending() -> vk::PipelineColorBlendAttachmentState {
         .blend_enable(false)
 }
 
-fn additive_blending() -> vk::PipelineColorBlendAttachmentState {
-    // src.rgb * src.a + dst.rgc * 1.0
-    vk::PipelineColorBlendAttachmentState::default()
-        .color_write_mask(vk::ColorComponentFlags::RGBA)
-        .blend_enable(true)
-        .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-        .dst_color_blend_factor(vk::BlendFactor::ONE)
-        .color_blend_op(vk::BlendOp::ADD)
-        .src_alpha_blend_factor(vk::BlendFactor::ONE)
-        .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
-        .alpha_blend_op(vk::BlendOp::ADD)
-}
-fn alpha_blending() -> vk::PipelineColorBlendAttachmentState {
-    // src.rgb * src.a + dst.rgb * (1.0 - dst.a)
-    vk::PipelineColorBlendAttachmentState::default()
-        .color_write_mask(vk::ColorComponentFlags::RGBA)
-        .blend_enable(true)
-        .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-        .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_DST_ALPHA)
-        .color_blend_op(vk::BlendOp::ADD)
-        .src_alpha_blend_factor(vk::BlendFactor::ONE)
-        .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
-        .alpha_blend_op(vk::BlendOp::ADD)
-}
-
 fn disable_depth_test<'a>() -> vk::PipelineDepthStencilStateCreateInfo<'a> {
     vk::PipelineDepthStencilStateCreateInfo::default()
         .depth_test_enable(false)
@@ -254,3 +1004,86 @@ fn enable_depth_test<'a>(
         .min_depth_bounds(0.0)
         .max_depth_bounds(1.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_STAGES: vk::ShaderStageFlags = vk::ShaderStageFlags::from_raw(
+        vk::ShaderStageFlags::ALL_GRAPHICS.as_raw()
+            | vk::ShaderStageFlags::TASK_EXT.as_raw()
+            | vk::ShaderStageFlags::MESH_EXT.as_raw(),
+    );
+
+    fn validate(kinds: &[ShaderKind]) -> eyre::Result<()> {
+        validate_stages(
+            kinds,
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            None,
+            ALL_STAGES,
+        )
+    }
+
+    #[test]
+    fn valid_stages() {
+        use ShaderKind::*;
+        validate(&[Vertex, Fragment]).unwrap();
+        validate(&[Vertex]).unwrap();
+        validate(&[Vertex, Geometry, Fragment]).unwrap();
+        validate(&[Mesh, Fragment]).unwrap();
+        validate(&[Task, Mesh, Fragment]).unwrap();
+        validate_stages(
+            &[Vertex, TessControl, TessEvaluation, Fragment],
+            vk::PrimitiveTopology::PATCH_LIST,
+            Some(3),
+            ALL_STAGES,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn needs_one_geometry_source() {
+        use ShaderKind::*;
+        assert!(validate(&[Fragment]).is_err());
+        assert!(validate(&[Vertex, Mesh, Fragment]).is_err());
+        assert!(validate(&[Vertex, Vertex, Fragment]).is_err());
+        assert!(validate(&[Vertex, Compute]).is_err());
+    }
+
+    #[test]
+    fn task_and_mesh_stages() {
+        use ShaderKind::*;
+        assert!(validate(&[Task, Fragment]).is_err());
+        assert!(validate(&[Vertex, Task, Fragment]).is_err());
+        assert!(validate(&[Mesh, Geometry, Fragment]).is_err());
+    }
+
+    #[test]
+    fn tessellation_stages() {
+        use ShaderKind::*;
+        let patches = |kinds: &[ShaderKind], patch_control_points| {
+            validate_stages(
+                kinds,
+                vk::PrimitiveTopology::PATCH_LIST,
+                patch_control_points,
+                ALL_STAGES,
+            )
+        };
+        assert!(patches(&[Vertex, TessControl, Fragment], Some(3)).is_err());
+        assert!(patches(&[Vertex, TessEvaluation, Fragment], Some(3)).is_err());
+        assert!(patches(&[Vertex, TessControl, TessEvaluation, Fragment], None).is_err());
+        assert!(validate(&[Vertex, TessControl, TessEvaluation, Fragment]).is_err());
+    }
+
+    #[test]
+    fn disabled_stages() {
+        use ShaderKind::*;
+        let enabled = vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT;
+        let validate = |kinds: &[ShaderKind]| {
+            validate_stages(kinds, vk::PrimitiveTopology::TRIANGLE_LIST, None, enabled)
+        };
+        validate(&[Vertex, Fragment]).unwrap();
+        assert!(validate(&[Mesh, Fragment]).is_err());
+        assert!(validate(&[Vertex, Geometry, Fragment]).is_err());
+    }
+}
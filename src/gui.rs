@@ -1,9 +1,9 @@
-use std::sync::Arc;
+use std::{ops::RangeInclusive, sync::Arc};
 
 use ash::vk;
-use egui::{DragValue, Ui, vec2};
+use egui::{DragValue, Response, Ui, vec2};
 use eyre::Ok;
-use glam::{Affine3A, Quat, Vec3, Vec4};
+use glam::{Affine3A, Quat, Vec3};
 use winit::window::Window;
 
 use crate::{
@@ -39,15 +39,27 @@ pub fn affine_ui(ui: &mut Ui, affine: &mut Affine3A, label: &str) {
     *affine = Affine3A::from_scale_rotation_translation(scale, rotation, translation);
 }
 
-pub fn vec4_drag_value(ui: &mut Ui, v: &mut Vec4, label: &str) {
+/// One drag value per component, clamped to `range` if there is one.
+pub fn components_drag_value(
+    ui: &mut Ui,
+    components: &mut [f32],
+    label: &str,
+    range: Option<&RangeInclusive<f32>>,
+) -> Response {
     const SIZE: egui::Vec2 = vec2(48.0, 20.0);
-    ui.label(label);
-    ui.columns(4, |ui| {
-        ui[0].add_sized(SIZE, DragValue::new(&mut v.x).speed(0.01));
-        ui[1].add_sized(SIZE, DragValue::new(&mut v.y).speed(0.01));
-        ui[2].add_sized(SIZE, DragValue::new(&mut v.z).speed(0.01));
-        ui[3].add_sized(SIZE, DragValue::new(&mut v.w).speed(0.01));
+    let response = ui.label(label);
+    ui.columns(components.len(), |ui| {
+        for (ui, value) in ui.iter_mut().zip(components.iter_mut()) {
+            let drag = match range {
+                Some(range) => DragValue::new(value)
+                    .speed((range.end() - range.start()) / 100.0)
+                    .range(range.clone()),
+                None => DragValue::new(value).speed(0.01),
+            };
+            ui.add_sized(SIZE, drag);
+        }
     });
+    response
 }
 pub fn vec3_drag_value(ui: &mut Ui, v: &mut Vec3, label: &str) {
     const SIZE: egui::Vec2 = vec2(48.0, 20.0);
@@ -129,7 +141,7 @@ impl Gui {
         if !textures_delta.set.is_empty() {
             self.renderer.set_textures(
                 engine.vulkan().graphics_queue(),
-                engine.immediate_graphics().pool(),
+                engine.cmd_pool(),
                 &textures_delta.set,
             )?;
         }
@@ -0,0 +1,84 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Polls the modification times of every file under a set of directories.
+pub struct ShaderWatcher {
+    roots: Vec<PathBuf>,
+    modified: HashMap<PathBuf, SystemTime>,
+    last_poll: Instant,
+}
+
+/// Only fails when `dir` itself cannot be read. Entries that cannot be, e.g. editor swap files that
+/// vanish mid-scan, are skipped.
+fn scan(dir: &Path, files: &mut HashMap<PathBuf, SystemTime>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            // A subdirectory removed mid-scan is picked up again by the next poll.
+            let _ = scan(&path, files);
+        } else if let (Ok(path), Ok(modified)) = (fs::canonicalize(&path), metadata.modified()) {
+            files.insert(path, modified);
+        }
+    }
+    Ok(())
+}
+
+impl ShaderWatcher {
+    /// Roots that are not directories, e.g. a stale include path, are skipped with a warning.
+    pub fn new(roots: &[PathBuf]) -> Self {
+        let roots = roots
+            .iter()
+            .filter(|root| {
+                let is_dir = root.is_dir();
+                if !is_dir {
+                    tracing::warn!("not watching {}: not a directory", root.display());
+                }
+                is_dir
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut modified = HashMap::new();
+        for root in &roots {
+            if let Err(e) = scan(root, &mut modified) {
+                tracing::warn!("could not scan {}: {e}", root.display());
+            }
+        }
+        Self {
+            roots,
+            modified,
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// Returns the canonical paths of the files that were created or modified since the last poll.
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return vec![];
+        }
+        self.last_poll = Instant::now();
+
+        let mut modified = HashMap::with_capacity(self.modified.len());
+        for root in &self.roots {
+            if let Err(e) = scan(root, &mut modified) {
+                tracing::warn!("could not scan {}: {e}", root.display());
+                return vec![];
+            }
+        }
+        let changed = modified
+            .iter()
+            .filter(|(path, time)| self.modified.get(*path) != Some(*time))
+            .map(|(path, _)| path.clone())
+            .collect();
+        self.modified = modified;
+        changed
+    }
+}
@@ -1,17 +1,31 @@
 pub mod app;
+mod async_compute;
 mod buffer;
 mod compute;
 mod descriptors;
+mod effect_parameters;
+mod effects;
 mod engine;
 mod frames;
 mod graphics;
 mod gui;
-mod immediate;
+mod hot_reload;
 mod material;
 mod mesh;
+mod msaa;
+mod particles;
+mod pipeline_builder;
+mod pipeline_cache;
+mod pipeline_description;
 mod render_objects;
 mod shader;
+#[cfg(feature = "runtime-shaders")]
+mod shader_cache;
+mod shader_errors;
+mod specialization;
 mod swapchain;
 mod texture;
+mod timeline;
+mod upload;
 mod utils;
 mod vulkan;
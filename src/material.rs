@@ -1,13 +1,23 @@
+use std::collections::HashMap;
+
 use ash::vk;
 use eyre::Ok;
 use glam::Vec4;
 
 use crate::{
-    descriptors::{DescriptorAllocator, DescriptorLayoutBuilder, DescriptorWriter},
-    graphics::{Blending, GraphicsPipelineInfo},
+    descriptors::{
+        BoundDescriptor, DescriptorAllocator, DescriptorBackend, DescriptorBufferAllocator,
+        DescriptorLayoutBuilder, DescriptorWriter,
+    },
+    graphics::{Blending, DrawState, DynamicGroup},
     mesh::GPUDrawPushConstants,
-    shader::ShaderCompiler,
-    texture::{AllocatedImage, DrawImage},
+    pipeline_builder::{BuiltPipeline, PipelineBuilder, PipelineTicket},
+    pipeline_description::{LayoutKind, PipelineDescription, RenderTargets},
+    shader::{ShaderCompiler, ShaderDefines, ShaderDependencies, ShaderKind},
+    shader_errors::ShaderErrors,
+    specialization::SpecializationConstants,
+    texture::AllocatedImage,
+    vulkan::Vulkan,
 };
 
 pub struct MaterialPipeline {
@@ -24,15 +34,38 @@ impl MaterialPipeline {
 
 pub struct MaterialInstance {
     pipeline_handle: MaterialHandle,
-    set: vk::DescriptorSet,
+    set: BoundDescriptor,
     pass: MaterialPass,
 }
 
+impl MaterialInstance {
+    pub const fn set(&self) -> BoundDescriptor {
+        self.set
+    }
+    pub const fn pass(&self) -> MaterialPass {
+        self.pass
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum MaterialPass {
     MainColor,
     Transparent,
-    Other,
+}
+
+impl MaterialPass {
+    /// Transparent surfaces are blended additively without touching the depth buffer.
+    pub fn draw_state(self) -> DrawState {
+        match self {
+            Self::Transparent => DrawState {
+                depth_test: false,
+                depth_write: false,
+                blending: Some(Blending::Additive),
+                ..DrawState::default()
+            },
+            Self::MainColor => DrawState::default(),
+        }
+    }
 }
 
 #[derive(Default, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
@@ -63,38 +96,92 @@ pub struct MaterialResources {
 }
 
 pub struct GLTFMetallicRoughness {
-    opaque_handle: MaterialHandle,
-    transparent_handle: MaterialHandle,
-    pipeline_layout: vk::PipelineLayout,
+    variants: HashMap<MaterialVariant, MaterialHandle>,
     material_map: MaterialMap,
     material_layout: vk::DescriptorSetLayout,
+    config: PipelineConfig,
+    /// Variants being built on the [`PipelineBuilder`], drawn with the default variant meanwhile.
+    pending: HashMap<PipelineTicket, PendingVariant>,
+    /// Bumped by every [`Self::reload`], so variants that started building before it are rebuilt.
+    generation: u64,
+    dependencies: ShaderDependencies,
+}
+
+/// Everything needed to build a material pipeline, cloned into [`PipelineBuilder`] jobs.
+#[derive(Clone)]
+struct PipelineConfig {
+    layout: vk::PipelineLayout,
+    color_formats: Vec<vk::Format>,
+    depth_format: vk::Format,
+    samples: vk::SampleCountFlags,
+    /// Opaque and transparent passes share pipelines, blending and polygon mode being set per draw.
+    dynamic_blending: bool,
+    pipeline_cache: vk::PipelineCache,
+    descriptor_backend: DescriptorBackend,
+    enabled_stages: vk::ShaderStageFlags,
+}
+
+struct PendingVariant {
+    handle: MaterialHandle,
+    variant: MaterialVariant,
+    generation: u64,
 }
 slotmap::new_key_type! { struct MaterialHandle; }
+
+pub const MATERIAL_NAME: &str = "Metallic Roughness Material";
+const MATERIAL_DESCRIPTION: &str = "material";
 type MaterialMap = slotmap::SlotMap<MaterialHandle, MaterialPipeline>;
+
+/// Optional shading features of `mesh.frag`. Alpha masking is compiled into its own permutation,
+/// unlit shading specializes the lit SPIR-V.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MaterialFeatures {
+    /// Discard fragments whose alpha is below the cutoff.
+    pub alpha_mask: bool,
+    /// Skip lighting and output the base color as is.
+    pub unlit: bool,
+}
+
+impl MaterialFeatures {
+    /// `constant_id` of `UNLIT` in `mesh.frag`.
+    const UNLIT_CONSTANT: u32 = 0;
+    /// Alpha below which masked fragments are discarded.
+    const ALPHA_CUTOFF: &str = "0.5";
+
+    fn defines(self) -> ShaderDefines {
+        let mut defines = ShaderDefines::new();
+        if self.alpha_mask {
+            defines = defines
+                .define("ALPHA_MASK")
+                .define_value("ALPHA_CUTOFF", Self::ALPHA_CUTOFF);
+        }
+        defines
+    }
+    fn specialization(self) -> SpecializationConstants {
+        SpecializationConstants::new().set(Self::UNLIT_CONSTANT, self.unlit)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct MaterialVariant {
+    transparent: bool,
+    features: MaterialFeatures,
+}
+
 impl GLTFMetallicRoughness {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        device: &ash::Device,
+        vulkan: &Vulkan,
         shader_compiler: &ShaderCompiler,
         scene_data_layout: vk::DescriptorSetLayout,
-        draw_image: &DrawImage,
+        color_formats: &[vk::Format],
         depth_image: &AllocatedImage,
+        pipeline_cache: vk::PipelineCache,
+        dynamic_blending: bool,
+        shader_errors: &mut ShaderErrors,
     ) -> eyre::Result<Self> {
-        let shader_src = include_str!("../shaders/mesh.vert");
-        let vert_shader = shader_compiler.create_shader_module_from_str(
-            device,
-            shader_src,
-            shaderc::ShaderKind::Vertex,
-            "mesh.vert",
-            "main",
-        )?;
-        let shader_src = include_str!("../shaders/mesh.frag");
-        let frag_shader = shader_compiler.create_shader_module_from_str(
-            device,
-            shader_src,
-            shaderc::ShaderKind::Fragment,
-            "mesh.frag",
-            "main",
-        )?;
+        let device = vulkan.device();
+        let descriptor_backend = vulkan.descriptor_backend();
         let push_constants_range = vk::PushConstantRange::default()
             .offset(0)
             .size(std::mem::size_of::<GPUDrawPushConstants>() as u32)
@@ -103,6 +190,7 @@ impl GLTFMetallicRoughness {
             .add_binding(0, vk::DescriptorType::UNIFORM_BUFFER)
             .add_binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
             .add_binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .flags(descriptor_backend.layout_create_flags())
             .build(
                 device,
                 vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
@@ -114,78 +202,349 @@ impl GLTFMetallicRoughness {
             .push_constant_ranges(&ranges);
 
         let pipeline_layout =
-            unsafe { device.create_pipeline_layout(&pipeline_layout_info, None) }?;
-        let pipeline = GraphicsPipelineInfo::builder()
-            .shaders([vert_shader, frag_shader])
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .cull_mode(vk::CullModeFlags::NONE)
-            .front_face(vk::FrontFace::CLOCKWISE)
-            .depth_enabled(true)
-            .color_attachment_format(draw_image.format())
-            .depth_format(depth_image.format())
-            .layout(pipeline_layout)
-            .build()
-            .create(device)?;
-
-        let mut material_map = MaterialMap::with_key();
-        let opaque_pipeline = MaterialPipeline {
-            pipeline,
-            layout: pipeline_layout,
+            match unsafe { device.create_pipeline_layout(&pipeline_layout_info, None) } {
+                Result::Ok(layout) => layout,
+                Err(e) => {
+                    unsafe { device.destroy_descriptor_set_layout(material_layout, None) };
+                    return Err(e.into());
+                }
+            };
+
+        let mut material = Self {
+            variants: HashMap::new(),
+            material_map: MaterialMap::with_key(),
+            material_layout,
+            config: PipelineConfig {
+                layout: pipeline_layout,
+                color_formats: color_formats.to_vec(),
+                depth_format: depth_image.format(),
+                samples: depth_image.samples(),
+                dynamic_blending,
+                pipeline_cache,
+                descriptor_backend,
+                enabled_stages: vulkan.graphics_stages(),
+            },
+            pending: HashMap::new(),
+            generation: 0,
+            dependencies: ShaderDependencies::default(),
         };
-        let opaque_handle = material_map.insert(opaque_pipeline);
-
-        let pipeline = GraphicsPipelineInfo::builder()
-            .shaders([vert_shader, frag_shader])
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .cull_mode(vk::CullModeFlags::NONE)
-            .front_face(vk::FrontFace::CLOCKWISE)
-            .depth_enabled(false)
-            .color_attachment_format(draw_image.format())
-            .depth_format(depth_image.format())
-            .layout(pipeline_layout)
-            .blending(Blending::Additive)
-            .build()
-            .create(device)?;
-        let transparent_pipeline = MaterialPipeline {
+        // The default opaque and transparent permutations are always needed, and stand in for
+        // the other variants while they build, so they are built upfront on this thread.
+        for pass in [MaterialPass::MainColor, MaterialPass::Transparent] {
+            let variant = material.variant(pass, MaterialFeatures::default());
+            if let Err(e) =
+                material.variant_handle(device, shader_compiler, None, variant, shader_errors)
+            {
+                material.destroy(device);
+                return Err(e);
+            }
+        }
+        Ok(material)
+    }
+    const fn variant(&self, pass: MaterialPass, features: MaterialFeatures) -> MaterialVariant {
+        MaterialVariant {
+            transparent: matches!(pass, MaterialPass::Transparent) && !self.config.dynamic_blending,
+            features,
+        }
+    }
+    /// State to set with [`DrawState::record`] after binding a material pipeline.
+    pub const fn dynamic_state(&self) -> &'static [DynamicGroup] {
+        self.config.dynamic_state()
+    }
+    /// The pipeline to draw `instance` with, which is the default variant of its pass until its
+    /// own finished building.
+    pub fn pipeline(&self, instance: &MaterialInstance) -> (vk::Pipeline, vk::PipelineLayout) {
+        let pipeline = self
+            .material_map
+            .get(instance.pipeline_handle)
+            .map_or_else(vk::Pipeline::null, |m| m.pipeline);
+        if pipeline != vk::Pipeline::null() {
+            return (pipeline, self.config.layout);
+        }
+        let default = self
+            .variants
+            .get(&self.variant(instance.pass, MaterialFeatures::default()))
+            .and_then(|handle| self.material_map.get(*handle))
+            .map_or_else(vk::Pipeline::null, |m| m.pipeline);
+        (default, self.config.layout)
+    }
+    /// Number of variants still building, which draw with the default variant of their pass.
+    pub fn building(&self) -> usize {
+        self.pending.len()
+    }
+    /// Returns the pipeline for `variant`. A new permutation is compiled on `pipeline_builder`
+    /// when there is one, and on this thread otherwise.
+    fn variant_handle(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        pipeline_builder: Option<&mut PipelineBuilder>,
+        variant: MaterialVariant,
+        shader_errors: &mut ShaderErrors,
+    ) -> eyre::Result<MaterialHandle> {
+        if let Some(handle) = self.variants.get(&variant) {
+            return Ok(*handle);
+        }
+        if let Some(pipeline_builder) = pipeline_builder {
+            let handle = self.material_map.insert(MaterialPipeline {
+                pipeline: vk::Pipeline::null(),
+                layout: self.config.layout,
+            });
+            self.variants.insert(variant, handle);
+            self.submit(pipeline_builder, handle, variant)?;
+            return Ok(handle);
+        }
+        let config = &self.config;
+        let built = config.build_pipeline(device, shader_compiler, variant, false);
+        let (pipeline, dependencies) = shader_errors.or_fallback(MATERIAL_NAME, built, || {
+            let (pipeline, _) = config.build_pipeline(device, shader_compiler, variant, true)?;
+            Ok((pipeline, ShaderDependencies::unknown()))
+        })?;
+        let handle = self.material_map.insert(MaterialPipeline {
             pipeline,
-            layout: pipeline_layout,
+            layout: self.config.layout,
+        });
+        self.variants.insert(variant, handle);
+        self.dependencies.extend(&dependencies);
+        Ok(handle)
+    }
+    fn submit(
+        &mut self,
+        pipeline_builder: &mut PipelineBuilder,
+        handle: MaterialHandle,
+        variant: MaterialVariant,
+    ) -> eyre::Result<()> {
+        let config = self.config.clone();
+        let ticket = pipeline_builder.submit(move |device, shader_compiler| {
+            config.build_pipeline(device, shader_compiler, variant, false)
+        })?;
+        self.pending.insert(
+            ticket,
+            PendingVariant {
+                handle,
+                variant,
+                generation: self.generation,
+            },
+        );
+        Ok(())
+    }
+    /// Swaps in the variants `pipeline_builder` finished. Variants that fail to build keep drawing
+    /// with the default variant and are retried on the next shader reload.
+    pub fn poll_pipelines(
+        &mut self,
+        device: &ash::Device,
+        pipeline_builder: &mut PipelineBuilder,
+        shader_errors: &mut ShaderErrors,
+    ) {
+        for (ticket, built) in pipeline_builder.poll() {
+            let Some(pending) = self.pending.remove(&ticket) else {
+                if let Result::Ok((pipeline, _)) = built {
+                    unsafe { device.destroy_pipeline(pipeline, None) };
+                }
+                continue;
+            };
+            if let Err(e) = self.finish_pipeline(device, pipeline_builder, &pending, built) {
+                tracing::error!("could not build a {MATERIAL_NAME} variant: {e:?}");
+                shader_errors.report(MATERIAL_NAME, &e);
+                self.dependencies = ShaderDependencies::unknown();
+            }
+        }
+    }
+    fn finish_pipeline(
+        &mut self,
+        device: &ash::Device,
+        pipeline_builder: &mut PipelineBuilder,
+        pending: &PendingVariant,
+        built: BuiltPipeline,
+    ) -> eyre::Result<()> {
+        let (pipeline, dependencies) = built?;
+        // The sources or render targets changed while it was building.
+        if pending.generation != self.generation {
+            unsafe { device.destroy_pipeline(pipeline, None) };
+            return self.submit(pipeline_builder, pending.handle, pending.variant);
+        }
+        if let Some(m) = self.material_map.get_mut(pending.handle) {
+            m.pipeline = pipeline;
+            tracing::info!("{MATERIAL_NAME} variant {:?} is ready", pending.variant);
+        }
+        self.dependencies.extend(&dependencies);
+        Ok(())
+    }
+}
+
+impl PipelineConfig {
+    const fn dynamic_state(&self) -> &'static [DynamicGroup] {
+        if self.dynamic_blending {
+            &[
+                DynamicGroup::Depth,
+                DynamicGroup::DepthBias,
+                DynamicGroup::Blending,
+                DynamicGroup::PolygonMode,
+            ]
+        } else {
+            &[DynamicGroup::Depth, DynamicGroup::DepthBias]
+        }
+    }
+    /// Builds the pipeline for `variant`, or one using the error shaders when `fallback` is set.
+    fn build_pipeline(
+        &self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        variant: MaterialVariant,
+        fallback: bool,
+    ) -> eyre::Result<(vk::Pipeline, ShaderDependencies)> {
+        let (description, mut dependencies) = if fallback {
+            (
+                PipelineDescription::fallback(LayoutKind::Material, "error.vert", "error.frag"),
+                ShaderDependencies::default(),
+            )
+        } else {
+            PipelineDescription::load(MATERIAL_DESCRIPTION, LayoutKind::Material)?
         };
-        let transparent_handle = material_map.insert(transparent_pipeline);
-
-        unsafe { device.destroy_shader_module(vert_shader, None) };
-        unsafe { device.destroy_shader_module(frag_shader, None) };
-        Ok(Self {
-            opaque_handle,
-            transparent_handle,
-            material_map,
-            material_layout,
-            pipeline_layout,
-        })
+        let description = if variant.transparent {
+            description.transparent(Blending::Additive)
+        } else {
+            description
+        };
+        let description = if fallback {
+            description
+        } else {
+            description.specialize(ShaderKind::Fragment, variant.features.specialization())
+        };
+        let defines = |kind| match kind {
+            ShaderKind::Vertex if fallback => ShaderDefines::new().define("USE_SCENE_DATA"),
+            ShaderKind::Fragment if !fallback => variant.features.defines(),
+            _ => ShaderDefines::new(),
+        };
+        let targets = RenderTargets {
+            color_formats: &self.color_formats,
+            depth_format: self.depth_format,
+            samples: self.samples,
+        };
+        let (pipeline, shader_dependencies) = description.create(
+            device,
+            shader_compiler,
+            self.layout,
+            self.pipeline_cache,
+            self.descriptor_backend,
+            self.enabled_stages,
+            &targets,
+            self.dynamic_state(),
+            &defines,
+        )?;
+        dependencies.extend(&shader_dependencies);
+        Ok((pipeline, dependencies))
     }
-    pub fn destroy(&mut self, device: &ash::Device) {
-        if let Some(m) = self.material_map.remove(self.opaque_handle) {
-            unsafe { device.destroy_pipeline(m.pipeline, None) };
+}
+
+impl GLTFMetallicRoughness {
+    /// Rebuilds every material pipeline in place so existing [`MaterialInstance`]s keep working,
+    /// handing the old pipelines to `retire`. On failure the current pipelines stay in use, unless
+    /// `samples` changed, in which case the fallback shaders are used and the error is still
+    /// returned.
+    pub fn reload(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        samples: vk::SampleCountFlags,
+        mut retire: impl FnMut(vk::Pipeline),
+    ) -> eyre::Result<()> {
+        self.generation += 1;
+        let previous_samples = std::mem::replace(&mut self.config.samples, samples);
+        let (result, rebuilt, dependencies) =
+            match self.build_variants(device, shader_compiler, false) {
+                Result::Ok((rebuilt, dependencies)) => (Ok(()), rebuilt, dependencies),
+                Err(e) if samples != previous_samples => {
+                    let (rebuilt, _) = self
+                        .build_variants(device, shader_compiler, true)
+                        .inspect_err(|_| self.config.samples = previous_samples)?;
+                    (Err(e), rebuilt, ShaderDependencies::unknown())
+                }
+                Err(e) => return Err(e),
+            };
+        for (handle, pipeline) in rebuilt {
+            if let Some(m) = self.material_map.get_mut(handle) {
+                retire(std::mem::replace(&mut m.pipeline, pipeline));
+            }
         }
-        if let Some(m) = self.material_map.remove(self.transparent_handle) {
-            unsafe { device.destroy_pipeline(m.pipeline, None) };
+        self.dependencies = dependencies;
+        result
+    }
+    /// Builds a new pipeline for every variant, destroying the ones already built on failure.
+    fn build_variants(
+        &self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        fallback: bool,
+    ) -> eyre::Result<(Vec<(MaterialHandle, vk::Pipeline)>, ShaderDependencies)> {
+        let mut rebuilt = Vec::with_capacity(self.variants.len());
+        let mut dependencies = ShaderDependencies::default();
+        for (variant, handle) in &self.variants {
+            // Those are rebuilt with the new sources or samples once they are polled.
+            if self
+                .pending
+                .values()
+                .any(|pending| pending.handle == *handle)
+            {
+                continue;
+            }
+            match self
+                .config
+                .build_pipeline(device, shader_compiler, *variant, fallback)
+            {
+                Result::Ok((pipeline, variant_dependencies)) => {
+                    dependencies.extend(&variant_dependencies);
+                    rebuilt.push((*handle, pipeline));
+                }
+                Err(e) => {
+                    for (_, pipeline) in rebuilt {
+                        unsafe { device.destroy_pipeline(pipeline, None) };
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok((rebuilt, dependencies))
+    }
+    pub const fn dependencies(&self) -> &ShaderDependencies {
+        &self.dependencies
+    }
+    pub fn destroy(&mut self, device: &ash::Device) {
+        for (_, handle) in self.variants.drain() {
+            if let Some(m) = self.material_map.remove(handle) {
+                unsafe { device.destroy_pipeline(m.pipeline, None) };
+            }
         }
         unsafe { device.destroy_descriptor_set_layout(self.material_layout, None) };
-        unsafe { device.destroy_pipeline_layout(self.pipeline_layout, None) };
+        unsafe { device.destroy_pipeline_layout(self.config.layout, None) };
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn write_material(
-        &self,
+        &mut self,
         device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        pipeline_builder: &mut PipelineBuilder,
         pass: MaterialPass,
+        features: MaterialFeatures,
         resources: &MaterialResources,
         descriptor_allocator: &DescriptorAllocator,
+        mut descriptor_buffer: Option<&mut DescriptorBufferAllocator>,
+        shader_errors: &mut ShaderErrors,
     ) -> eyre::Result<MaterialInstance> {
-        let pipeline_handle = match pass {
-            MaterialPass::MainColor | MaterialPass::Other => self.opaque_handle,
-            MaterialPass::Transparent => self.transparent_handle,
-        };
-        let set = descriptor_allocator.allocate(device, self.material_layout)?[0];
+        let variant = self.variant(pass, features);
+        let pipeline_handle = self.variant_handle(
+            device,
+            shader_compiler,
+            Some(pipeline_builder),
+            variant,
+            shader_errors,
+        )?;
+        let set = BoundDescriptor::allocate(
+            device,
+            descriptor_allocator,
+            descriptor_buffer.as_deref_mut(),
+            self.material_layout,
+        )?;
         DescriptorWriter::new()
             .write_buffer(
                 0,
@@ -208,7 +567,7 @@ impl GLTFMetallicRoughness {
                 vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
                 vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
             )
-            .update_set(device, set);
+            .update(device, descriptor_buffer.as_deref(), set)?;
 
         Ok(MaterialInstance {
             pipeline_handle,
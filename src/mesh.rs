@@ -4,7 +4,11 @@ use ash::vk;
 use eyre::{Context, OptionExt};
 use glam::{Mat4, Vec2, Vec3, Vec4};
 
-use crate::{buffer::AllocatedBuffer, immediate::ImmediateSubmit, utils::memcopy};
+use crate::{
+    buffer::AllocatedBuffer,
+    graphics::VertexLayout,
+    upload::{UploadHandle, UploadManager},
+};
 
 #[derive(Debug, Default, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C)]
@@ -16,6 +20,31 @@ pub struct Vertex {
     color: Vec4,
 }
 
+impl VertexLayout for Vertex {
+    const ATTRIBUTES: &'static [(vk::Format, u32)] = &[
+        (
+            vk::Format::R32G32B32_SFLOAT,
+            std::mem::offset_of!(Self, pos) as u32,
+        ),
+        (
+            vk::Format::R32_SFLOAT,
+            std::mem::offset_of!(Self, uv_x) as u32,
+        ),
+        (
+            vk::Format::R32G32B32_SFLOAT,
+            std::mem::offset_of!(Self, normal) as u32,
+        ),
+        (
+            vk::Format::R32_SFLOAT,
+            std::mem::offset_of!(Self, uv_y) as u32,
+        ),
+        (
+            vk::Format::R32G32B32A32_SFLOAT,
+            std::mem::offset_of!(Self, color) as u32,
+        ),
+    ];
+}
+
 impl Vertex {
     pub const fn new(pos: Vec3, color: Vec4) -> Self {
         let uv = Vec2::ZERO;
@@ -52,12 +81,23 @@ impl GPUSceneData {
         Self {
             view,
             proj,
-            view_proj: view * proj,
+            view_proj: proj * view,
             ambient_color,
             sun_direction,
             sun_color,
         }
     }
+    pub fn set_camera(&mut self, view: Mat4, proj: Mat4) {
+        self.view = view;
+        self.proj = proj;
+        self.view_proj = proj * view;
+    }
+    pub const fn view(&self) -> Mat4 {
+        self.view
+    }
+    pub const fn proj(&self) -> Mat4 {
+        self.proj
+    }
 }
 
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
@@ -103,8 +143,7 @@ pub fn load_gltf_from_path(
     path: impl AsRef<Path>,
     device: &ash::Device,
     allocator: &vk_mem::Allocator,
-    transfer_queue: vk::Queue,
-    transfer_immediate: &ImmediateSubmit,
+    uploads: &mut UploadManager,
 ) -> eyre::Result<Vec<Mesh>> {
     let (gltf, buffers, _) = gltf::import(path).wrap_err("could not open")?;
     let mut meshes = Vec::with_capacity(gltf.meshes().len());
@@ -173,14 +212,7 @@ pub fn load_gltf_from_path(
                 v.color = v.normal.extend(1.0);
             }
         }
-        let mesh_buffers = GPUMeshBuffers::new(
-            device,
-            allocator,
-            transfer_queue,
-            transfer_immediate,
-            &indices,
-            &vertices,
-        )?;
+        let mesh_buffers = GPUMeshBuffers::new(device, allocator, uploads, &indices, &vertices)?;
         meshes.push(Mesh {
             name,
             surfaces,
@@ -208,78 +240,68 @@ pub struct GPUMeshBuffers {
     index_buffer: AllocatedBuffer,
     vertex_buffer: AllocatedBuffer,
     vertex_buffer_addr: vk::DeviceAddress,
+    upload: UploadHandle,
 }
 
 impl GPUMeshBuffers {
     pub fn new(
         device: &ash::Device,
         allocator: &vk_mem::Allocator,
-        transfer_queue: vk::Queue,
-        immediate_submit: &ImmediateSubmit,
+        uploads: &mut UploadManager,
         indices: &[u32],
         vertices: &[Vertex],
     ) -> eyre::Result<Self> {
         let vertex_buffer_size = std::mem::size_of_val(vertices);
-        let vertex_buffer = AllocatedBuffer::new(
+        let vertex_buffer = AllocatedBuffer::shared(
             allocator,
             vertex_buffer_size as u64,
             vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::VERTEX_BUFFER
                 | vk::BufferUsageFlags::TRANSFER_DST
                 | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
             vk_mem::MemoryUsage::AutoPreferDevice,
+            uploads.queue_families(),
         )?;
 
         let index_buffer_size = std::mem::size_of_val(indices);
-        let index_buffer = AllocatedBuffer::new(
+        let index_buffer = AllocatedBuffer::shared(
             allocator,
             index_buffer_size as u64,
             vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
             vk_mem::MemoryUsage::AutoPreferDevice,
+            uploads.queue_families(),
         )?;
 
         let device_addr_info =
             vk::BufferDeviceAddressInfo::default().buffer(vertex_buffer.buffer());
         let vertex_buffer_addr = unsafe { device.get_buffer_device_address(&device_addr_info) };
 
-        // Write data
-
-        let mut staging = AllocatedBuffer::new(
+        let vertex_upload = uploads.upload_buffer(
+            device,
             allocator,
-            (vertex_buffer_size + index_buffer_size) as u64,
-            vk::BufferUsageFlags::TRANSFER_SRC,
-            vk_mem::MemoryUsage::AutoPreferHost,
+            bytemuck::cast_slice(vertices),
+            vertex_buffer.buffer(),
+            0,
+            // Pulled through its address or bound as a vertex buffer.
+            vk::PipelineStageFlags2::VERTEX_SHADER
+                | vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT,
+            vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::VERTEX_ATTRIBUTE_READ,
+        )?;
+        let index_upload = uploads.upload_buffer(
+            device,
+            allocator,
+            bytemuck::cast_slice(indices),
+            index_buffer.buffer(),
+            0,
+            vk::PipelineStageFlags2::INDEX_INPUT,
+            vk::AccessFlags2::INDEX_READ,
         )?;
-        let memory = unsafe { allocator.map_memory(&mut staging.allocation()) }?;
-
-        unsafe { memcopy(vertices, memory) };
-        let memory_indices = memory.wrapping_byte_add(vertex_buffer_size);
-        unsafe { memcopy(indices, memory_indices) };
-
-        immediate_submit.submit(device, transfer_queue, |cmd| {
-            let vertex_copy = vk::BufferCopy::default().size(vertex_buffer_size as u64);
-            unsafe {
-                device.cmd_copy_buffer(
-                    cmd,
-                    staging.buffer(),
-                    vertex_buffer.buffer(),
-                    &[vertex_copy],
-                );
-            };
-            let index_copy = vk::BufferCopy::default()
-                .src_offset(vertex_buffer_size as u64)
-                .size(index_buffer_size as u64);
-            unsafe {
-                device.cmd_copy_buffer(cmd, staging.buffer(), index_buffer.buffer(), &[index_copy]);
-            };
-        })?;
-
-        unsafe { allocator.unmap_memory(&mut staging.allocation()) };
-        staging.destroy(allocator);
 
         Ok(Self {
             index_buffer,
             vertex_buffer,
             vertex_buffer_addr,
+            upload: vertex_upload.max(index_upload),
         })
     }
     pub fn destroy(&mut self, allocator: &vk_mem::Allocator) {
@@ -287,6 +309,11 @@ impl GPUMeshBuffers {
         self.vertex_buffer.destroy(allocator);
     }
 
+    /// Becomes ready once both buffers hold their data and belong to the graphics queue.
+    pub const fn upload(&self) -> UploadHandle {
+        self.upload
+    }
+
     pub const fn vertex_buffer_addr(&self) -> u64 {
         self.vertex_buffer_addr
     }
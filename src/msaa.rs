@@ -0,0 +1,96 @@
+use ash::vk;
+
+use crate::vulkan::Vulkan;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MsaaLevel {
+    #[default]
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+impl MsaaLevel {
+    pub const ALL: [Self; 4] = [Self::Off, Self::X2, Self::X4, Self::X8];
+
+    pub const fn samples(self) -> vk::SampleCountFlags {
+        match self {
+            Self::Off => vk::SampleCountFlags::TYPE_1,
+            Self::X2 => vk::SampleCountFlags::TYPE_2,
+            Self::X4 => vk::SampleCountFlags::TYPE_4,
+            Self::X8 => vk::SampleCountFlags::TYPE_8,
+        }
+    }
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::Off => "Off",
+            Self::X2 => "2x",
+            Self::X4 => "4x",
+            Self::X8 => "8x",
+        }
+    }
+    /// The highest level up to `self` that both color and depth targets support.
+    pub fn clamp(self, vulkan: &Vulkan) -> Self {
+        let limits = unsafe {
+            vulkan
+                .instance()
+                .get_physical_device_properties(vulkan.physical_device())
+        }
+        .limits;
+        self.clamp_to(
+            limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts,
+        )
+    }
+    /// The highest level up to `self` whose sample count is in `supported`.
+    fn clamp_to(self, supported: vk::SampleCountFlags) -> Self {
+        Self::ALL
+            .into_iter()
+            .take_while(|level| *level != self)
+            .chain([self])
+            .filter(|level| supported.contains(level.samples()))
+            .last()
+            .unwrap_or(Self::Off)
+    }
+    pub const fn enabled(self) -> bool {
+        !matches!(self, Self::Off)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_supported_levels() {
+        let supported = vk::SampleCountFlags::TYPE_1
+            | vk::SampleCountFlags::TYPE_2
+            | vk::SampleCountFlags::TYPE_4
+            | vk::SampleCountFlags::TYPE_8;
+        for level in MsaaLevel::ALL {
+            assert_eq!(level.clamp_to(supported), level);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_lower_levels() {
+        let supported = vk::SampleCountFlags::TYPE_1 | vk::SampleCountFlags::TYPE_4;
+        assert_eq!(MsaaLevel::X8.clamp_to(supported), MsaaLevel::X4);
+        assert_eq!(MsaaLevel::X4.clamp_to(supported), MsaaLevel::X4);
+        assert_eq!(MsaaLevel::X2.clamp_to(supported), MsaaLevel::Off);
+        // Never picks a higher level than asked for.
+        assert_eq!(MsaaLevel::Off.clamp_to(supported), MsaaLevel::Off);
+    }
+
+    #[test]
+    fn falls_back_to_off() {
+        assert_eq!(
+            MsaaLevel::X8.clamp_to(vk::SampleCountFlags::TYPE_1),
+            MsaaLevel::Off
+        );
+        assert_eq!(
+            MsaaLevel::X8.clamp_to(vk::SampleCountFlags::empty()),
+            MsaaLevel::Off
+        );
+    }
+}
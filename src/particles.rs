@@ -0,0 +1,887 @@
+use std::{path::PathBuf, time::Instant};
+
+use ash::vk;
+use egui::{DragValue, Ui};
+use glam::{Affine3A, Mat4, Vec2, Vec3, Vec4};
+
+use crate::{
+    buffer::AllocatedBuffer,
+    compute::{ComputeAccess, ComputeDispatch, ComputePipeline, ComputeResource, DispatchSize},
+    descriptors::{DescriptorBackend, DescriptorBufferAllocator},
+    graphics::{Blending, ColorAttachment, GraphicsPipelineInfo, ShaderStage},
+    gui::vec3_drag_value,
+    shader::{ShaderCompiler, ShaderDependencies, ShaderKind},
+    shader_errors::ShaderErrors,
+    specialization::SpecializationConstants,
+    texture::AllocatedImage,
+    utils::QueueTransfer,
+};
+
+pub const PARTICLES_NAME: &str = "Particles";
+/// Scene node of emitters that are not attached to anything.
+pub const WORLD_NODE: &str = "World";
+/// Size of a `Particle` in `shaders/particles.glsl`.
+const PARTICLE_SIZE: u64 = 48;
+/// `PARTICLE_WORKGROUP_SIZE` in `shaders/particles.glsl`.
+const WORKGROUP_SIZE: u32 = 64;
+/// `AliveList` header in `shaders/particles.glsl`: an empty dispatch and a count of 0.
+const EMPTY_ALIVE_LIST: [u32; 4] = [0, 1, 1, 0];
+const DEFAULT_CAPACITY: u32 = 4096;
+/// Longest step simulated at once, so a stalled frame does not release a burst of particles.
+const MAX_DELTA_TIME: f32 = 0.1;
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct SpawnPushConstants {
+    particles: vk::DeviceAddress,
+    first: u32,
+    count: u32,
+    capacity: u32,
+    seed: u32,
+    lifetime: Vec2,
+    origin: Vec4,
+    /// `w` is the cosine of the spread angle.
+    direction: Vec4,
+    speed: Vec2,
+    alive: vk::DeviceAddress,
+    color: Vec4,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct UpdatePushConstants {
+    particles: vk::DeviceAddress,
+    alive: vk::DeviceAddress,
+    next_alive: vk::DeviceAddress,
+    capacity: u32,
+    delta_time: f32,
+    /// `w` is the drag.
+    gravity: Vec4,
+    spawn_first: u32,
+    spawn_count: u32,
+    _pad: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct DrawPushConstants {
+    view_proj: Mat4,
+    /// `w` is the particle size.
+    camera_right: Vec4,
+    camera_up: Vec4,
+    end_color: Vec4,
+    particles: vk::DeviceAddress,
+    _pad: u64,
+}
+
+/// How an emitter spawns particles and how they move, relative to the scene node it follows.
+#[derive(Debug, Clone)]
+pub struct EmitterConfig {
+    pub name: String,
+    /// Name of the scene node the emitter follows, [`WORLD_NODE`] for none.
+    pub node: String,
+    pub enabled: bool,
+    pub offset: Vec3,
+    /// Particles per second.
+    pub rate: f32,
+    pub lifetime: [f32; 2],
+    pub direction: Vec3,
+    /// Half angle of the emission cone, in degrees.
+    pub spread: f32,
+    pub speed: [f32; 2],
+    pub gravity: Vec3,
+    pub drag: f32,
+    pub size: f32,
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+}
+
+impl Default for EmitterConfig {
+    fn default() -> Self {
+        Self {
+            name: "Emitter".to_owned(),
+            node: WORLD_NODE.to_owned(),
+            enabled: true,
+            offset: Vec3::ZERO,
+            rate: 200.0,
+            lifetime: [1.0, 2.0],
+            direction: Vec3::Y,
+            spread: 20.0,
+            speed: [1.0, 2.0],
+            gravity: Vec3::new(0.0, -2.0, 0.0),
+            drag: 0.2,
+            size: 0.04,
+            start_color: [1.0, 0.6, 0.2, 1.0],
+            end_color: [0.6, 0.1, 0.0, 0.0],
+        }
+    }
+}
+
+/// An emitter and the storage buffer holding its particles, which only the GPU touches.
+struct Emitter {
+    config: EmitterConfig,
+    buffer: AllocatedBuffer,
+    address: vk::DeviceAddress,
+    /// Lists of the live particles, alternating between the one the update pass reads and the one
+    /// it and the spawn pass append to. Only the compute passes use them.
+    alive: [(AllocatedBuffer, vk::DeviceAddress); 2],
+    /// Index in `alive` of the list of the particles alive after the last simulation.
+    current: usize,
+    capacity: u32,
+    /// Where the next particle is spawned, the oldest ones are overwritten first.
+    next: u32,
+    /// Fraction of a particle carried over to the next frame.
+    pending: f32,
+    cleared: bool,
+    /// Released by the graphics queue, to be acquired before the next simulation.
+    released: bool,
+}
+
+impl Emitter {
+    fn new(
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+        config: EmitterConfig,
+        capacity: u32,
+    ) -> eyre::Result<Self> {
+        let buffer = AllocatedBuffer::new(
+            allocator,
+            u64::from(capacity) * PARTICLE_SIZE,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            vk_mem::MemoryUsage::AutoPreferDevice,
+        )?;
+        let address = buffer_address(device, &buffer);
+        let alive_list = || -> eyre::Result<_> {
+            let list = AllocatedBuffer::new(
+                allocator,
+                std::mem::size_of_val(&EMPTY_ALIVE_LIST) as u64 + u64::from(capacity) * 4,
+                vk::BufferUsageFlags::STORAGE_BUFFER
+                    | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                    | vk::BufferUsageFlags::INDIRECT_BUFFER
+                    | vk::BufferUsageFlags::TRANSFER_DST,
+                vk_mem::MemoryUsage::AutoPreferDevice,
+            )?;
+            let address = buffer_address(device, &list);
+            Ok((list, address))
+        };
+        Ok(Self {
+            config,
+            buffer,
+            address,
+            alive: [alive_list()?, alive_list()?],
+            current: 0,
+            capacity,
+            next: 0,
+            pending: 0.0,
+            cleared: false,
+            released: false,
+        })
+    }
+    /// Takes the particles to spawn this frame, returning the first ring index and the count.
+    fn take_spawns(&mut self, delta_time: f32) -> (u32, u32) {
+        if !self.config.enabled {
+            self.pending = 0.0;
+            return (self.next, 0);
+        }
+        self.pending += self.config.rate.max(0.0) * delta_time;
+        let count = (self.pending.floor() as u32).min(self.capacity);
+        self.pending -= self.pending.floor();
+        let first = self.next;
+        self.next = (self.next + count) % self.capacity;
+        (first, count)
+    }
+    /// Records the update pass over last frame's live particles, as counted on the GPU, then
+    /// the spawn pass. Both append the particles they leave alive to the other list.
+    #[allow(clippy::too_many_arguments)]
+    fn simulate(
+        &mut self,
+        device: &ash::Device,
+        pipelines: &ParticlePipelines,
+        descriptor_buffer: Option<&DescriptorBufferAllocator>,
+        cmd: vk::CommandBuffer,
+        delta_time: f32,
+        transform: Affine3A,
+        seed: u32,
+    ) -> eyre::Result<()> {
+        if !self.cleared {
+            // Zeroed particles have a lifetime of 0, so they start out dead.
+            unsafe {
+                device.cmd_fill_buffer(cmd, self.buffer.buffer(), 0, vk::WHOLE_SIZE, 0);
+                device.cmd_update_buffer(
+                    cmd,
+                    self.alive[self.current].0.buffer(),
+                    0,
+                    bytemuck::bytes_of(&EMPTY_ALIVE_LIST),
+                );
+            };
+            self.cleared = true;
+        }
+        unsafe {
+            device.cmd_update_buffer(
+                cmd,
+                self.alive[1 - self.current].0.buffer(),
+                0,
+                bytemuck::bytes_of(&EMPTY_ALIVE_LIST),
+            );
+        };
+        let spawned = self.take_spawns(delta_time);
+        self.update(
+            device,
+            pipelines,
+            descriptor_buffer,
+            cmd,
+            delta_time,
+            spawned,
+        )?;
+        if spawned.1 > 0 {
+            self.spawn(
+                device,
+                pipelines,
+                descriptor_buffer,
+                cmd,
+                transform,
+                seed,
+                spawned,
+            )?;
+        }
+        self.current = 1 - self.current;
+        Ok(())
+    }
+    /// Skips the particles in the `spawned` ring range, which the spawn pass overwrites.
+    fn update(
+        &self,
+        device: &ash::Device,
+        pipelines: &ParticlePipelines,
+        descriptor_buffer: Option<&DescriptorBufferAllocator>,
+        cmd: vk::CommandBuffer,
+        delta_time: f32,
+        (first, count): (u32, u32),
+    ) -> eyre::Result<()> {
+        let (alive, alive_address) = &self.alive[self.current];
+        let (next_alive, next_alive_address) = &self.alive[1 - self.current];
+        let resources = [
+            ComputeResource::Buffer {
+                buffer: self.buffer.buffer(),
+                access: ComputeAccess::ReadWrite,
+            },
+            ComputeResource::Buffer {
+                buffer: alive.buffer(),
+                access: ComputeAccess::Read,
+            },
+            ComputeResource::Buffer {
+                buffer: next_alive.buffer(),
+                access: ComputeAccess::ReadWrite,
+            },
+        ];
+        let update = ComputeDispatch::builder()
+            .push_constants(UpdatePushConstants {
+                particles: self.address,
+                alive: *alive_address,
+                next_alive: *next_alive_address,
+                capacity: self.capacity,
+                delta_time,
+                gravity: self.config.gravity.extend(self.config.drag),
+                spawn_first: first,
+                spawn_count: count,
+                _pad: [0; 2],
+            })
+            .size(DispatchSize::Indirect {
+                buffer: alive.buffer(),
+                offset: 0,
+            })
+            .resources(&resources)
+            .build();
+        pipelines
+            .update
+            .dispatch(device, descriptor_buffer, cmd, &update)
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn spawn(
+        &self,
+        device: &ash::Device,
+        pipelines: &ParticlePipelines,
+        descriptor_buffer: Option<&DescriptorBufferAllocator>,
+        cmd: vk::CommandBuffer,
+        transform: Affine3A,
+        seed: u32,
+        (first, count): (u32, u32),
+    ) -> eyre::Result<()> {
+        let config = &self.config;
+        let origin = transform.transform_point3(config.offset);
+        let direction = transform
+            .transform_vector3(config.direction)
+            .normalize_or(Vec3::Y);
+        let (next_alive, next_alive_address) = &self.alive[1 - self.current];
+        let resources = [
+            ComputeResource::Buffer {
+                buffer: self.buffer.buffer(),
+                access: ComputeAccess::Write,
+            },
+            ComputeResource::Buffer {
+                buffer: next_alive.buffer(),
+                access: ComputeAccess::ReadWrite,
+            },
+        ];
+        let spawn = ComputeDispatch::builder()
+            .push_constants(SpawnPushConstants {
+                particles: self.address,
+                first,
+                count,
+                capacity: self.capacity,
+                seed,
+                lifetime: Vec2::from_array(config.lifetime),
+                origin: origin.extend(1.0),
+                direction: direction.extend(config.spread.to_radians().cos()),
+                speed: Vec2::from_array(config.speed),
+                alive: *next_alive_address,
+                color: Vec4::from_array(config.start_color),
+            })
+            .size(DispatchSize::Direct([count.div_ceil(WORKGROUP_SIZE), 1, 1]))
+            .resources(&resources)
+            .build();
+        pipelines
+            .spawn
+            .dispatch(device, descriptor_buffer, cmd, &spawn)
+    }
+    fn ui(&mut self, ui: &mut Ui, nodes: &[&str]) {
+        let config = &mut self.config;
+        ui.text_edit_singleline(&mut config.name);
+        ui.checkbox(&mut config.enabled, "Enabled");
+        egui::ComboBox::from_label("Node")
+            .selected_text(&config.node)
+            .show_ui(ui, |ui| {
+                for node in std::iter::once(&WORLD_NODE).chain(nodes) {
+                    ui.selectable_value(&mut config.node, (*node).to_owned(), *node);
+                }
+            });
+        ui.label(format!("Capacity: {} particles", self.capacity));
+        vec3_drag_value(ui, &mut config.offset, "Offset");
+        vec3_drag_value(ui, &mut config.direction, "Direction");
+        ui.add(egui::Slider::new(&mut config.spread, 0.0..=180.0).text("Spread (deg)"));
+        ui.add(egui::Slider::new(&mut config.rate, 0.0..=5000.0).text("Rate (per second)"));
+        ui.horizontal(|ui| {
+            ui.add(
+                DragValue::new(&mut config.lifetime[0])
+                    .speed(0.01)
+                    .range(0.0..=60.0),
+            );
+            ui.add(
+                DragValue::new(&mut config.lifetime[1])
+                    .speed(0.01)
+                    .range(0.0..=60.0),
+            );
+            ui.label("Lifetime (s)");
+        });
+        ui.horizontal(|ui| {
+            ui.add(DragValue::new(&mut config.speed[0]).speed(0.01));
+            ui.add(DragValue::new(&mut config.speed[1]).speed(0.01));
+            ui.label("Speed");
+        });
+        vec3_drag_value(ui, &mut config.gravity, "Gravity");
+        ui.add(egui::Slider::new(&mut config.drag, 0.0..=10.0).text("Drag"));
+        ui.add(egui::Slider::new(&mut config.size, 0.001..=1.0).text("Size"));
+        ui.horizontal(|ui| {
+            ui.color_edit_button_rgba_unmultiplied(&mut config.start_color);
+            ui.label("Start Color");
+        });
+        ui.horizontal(|ui| {
+            ui.color_edit_button_rgba_unmultiplied(&mut config.end_color);
+            ui.label("End Color");
+        });
+    }
+    fn destroy(&mut self, allocator: &vk_mem::Allocator) {
+        self.buffer.destroy(allocator);
+        for (list, _) in &mut self.alive {
+            list.destroy(allocator);
+        }
+    }
+}
+
+fn buffer_address(device: &ash::Device, buffer: &AllocatedBuffer) -> vk::DeviceAddress {
+    let address_info = vk::BufferDeviceAddressInfo::default().buffer(buffer.buffer());
+    unsafe { device.get_buffer_device_address(&address_info) }
+}
+
+/// Billboards every particle of an emitter, blended additively over the geometry pass.
+struct ParticleDrawPipeline {
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    samples: vk::SampleCountFlags,
+    dependencies: ShaderDependencies,
+}
+
+impl ParticleDrawPipeline {
+    fn new(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+        pipeline_cache: vk::PipelineCache,
+    ) -> eyre::Result<Self> {
+        let push_constant = vk::PushConstantRange::default()
+            .offset(0)
+            .size(std::mem::size_of::<DrawPushConstants>() as u32)
+            .stage_flags(vk::ShaderStageFlags::VERTEX);
+        let push_constants = [push_constant];
+        let layout_info =
+            vk::PipelineLayoutCreateInfo::default().push_constant_ranges(&push_constants);
+        let layout = unsafe { device.create_pipeline_layout(&layout_info, None) }?;
+        let (pipeline, dependencies) = match Self::create_pipeline(
+            device,
+            shader_compiler,
+            layout,
+            pipeline_cache,
+            color_formats,
+            depth_image,
+        ) {
+            Ok(created) => created,
+            Err(e) => {
+                unsafe { device.destroy_pipeline_layout(layout, None) };
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            pipeline,
+            layout,
+            samples: depth_image.samples(),
+            dependencies,
+        })
+    }
+    fn create_pipeline(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        layout: vk::PipelineLayout,
+        pipeline_cache: vk::PipelineCache,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+    ) -> eyre::Result<(vk::Pipeline, ShaderDependencies)> {
+        let (vertex_shader, mut dependencies) =
+            shader_compiler.load_shader_module(device, "particle.vert", ShaderKind::Vertex)?;
+        let (frag_shader, frag_dependencies) =
+            match shader_compiler.load_shader_module(device, "particle.frag", ShaderKind::Fragment)
+            {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    unsafe { device.destroy_shader_module(vertex_shader, None) };
+                    return Err(e);
+                }
+            };
+        dependencies.extend(&frag_dependencies);
+
+        // Tested against the geometry, but without writing depth so particles do not hide each
+        // other.
+        let pipeline = GraphicsPipelineInfo::builder()
+            .layout(layout)
+            .stages(&[
+                ShaderStage::new(ShaderKind::Vertex, vertex_shader),
+                ShaderStage::new(ShaderKind::Fragment, frag_shader),
+            ])
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .front_face(vk::FrontFace::CLOCKWISE)
+            .color_attachments(&ColorAttachment::with_formats(
+                color_formats,
+                Blending::Additive,
+            ))
+            .depth_format(depth_image.format())
+            .depth_enabled(true)
+            .depth_write_enabled(false)
+            .depth_compare_op(vk::CompareOp::GREATER_OR_EQUAL)
+            .samples(depth_image.samples())
+            .pipeline_cache(pipeline_cache)
+            .build()
+            .create(device);
+
+        unsafe { device.destroy_shader_module(vertex_shader, None) };
+        unsafe { device.destroy_shader_module(frag_shader, None) };
+        Ok((pipeline?, dependencies))
+    }
+    fn destroy(&mut self, device: &ash::Device) {
+        unsafe { device.destroy_pipeline_layout(self.layout, None) };
+        unsafe { device.destroy_pipeline(self.pipeline, None) };
+    }
+}
+
+struct ParticlePipelines {
+    spawn: ComputePipeline<SpawnPushConstants>,
+    update: ComputePipeline<UpdatePushConstants>,
+    draw: ParticleDrawPipeline,
+}
+
+impl ParticlePipelines {
+    fn new(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+        descriptor_backend: DescriptorBackend,
+        pipeline_cache: vk::PipelineCache,
+    ) -> eyre::Result<Self> {
+        let mut spawn = ComputePipeline::new(
+            device,
+            shader_compiler,
+            "Particle Spawn",
+            "particle_spawn.comp",
+            SpecializationConstants::new(),
+            &[],
+            descriptor_backend,
+            pipeline_cache,
+            None,
+        )?;
+        let update = ComputePipeline::new(
+            device,
+            shader_compiler,
+            "Particle Update",
+            "particle_update.comp",
+            SpecializationConstants::new(),
+            &[],
+            descriptor_backend,
+            pipeline_cache,
+            None,
+        );
+        let mut update = match update {
+            Ok(update) => update,
+            Err(e) => {
+                spawn.destroy(device);
+                return Err(e);
+            }
+        };
+        let draw = match ParticleDrawPipeline::new(
+            device,
+            shader_compiler,
+            color_formats,
+            depth_image,
+            pipeline_cache,
+        ) {
+            Ok(draw) => draw,
+            Err(e) => {
+                spawn.destroy(device);
+                update.destroy(device);
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            spawn,
+            update,
+            draw,
+        })
+    }
+    fn affected_by(&self, changed: &[PathBuf]) -> bool {
+        self.spawn.dependencies().affected_by(changed)
+            || self.update.dependencies().affected_by(changed)
+            || self.draw.dependencies.affected_by(changed)
+    }
+    fn reload(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+        pipeline_cache: vk::PipelineCache,
+        mut retire: impl FnMut(vk::Pipeline),
+    ) -> eyre::Result<()> {
+        self.spawn.reload(device, shader_compiler, &mut retire)?;
+        self.update.reload(device, shader_compiler, &mut retire)?;
+        let (pipeline, dependencies) = ParticleDrawPipeline::create_pipeline(
+            device,
+            shader_compiler,
+            self.draw.layout,
+            pipeline_cache,
+            color_formats,
+            depth_image,
+        )?;
+        retire(std::mem::replace(&mut self.draw.pipeline, pipeline));
+        self.draw.samples = depth_image.samples();
+        self.draw.dependencies = dependencies;
+        Ok(())
+    }
+    fn destroy(&mut self, device: &ash::Device) {
+        self.spawn.destroy(device);
+        self.update.destroy(device);
+        self.draw.destroy(device);
+    }
+}
+
+/// Emitters whose particles are spawned, moved and drawn entirely on the GPU: each frame a spawn
+/// pass writes the new particles into the emitter's ring buffer, an update pass integrates them
+/// and the geometry pass draws them as camera facing billboards.
+pub struct ParticleSystem {
+    /// `None` in dev builds while the particle shaders do not compile, in which case nothing is
+    /// simulated or drawn.
+    pipelines: Option<ParticlePipelines>,
+    emitters: Vec<Emitter>,
+    descriptor_backend: DescriptorBackend,
+    pipeline_cache: vk::PipelineCache,
+    /// From the compute to the graphics queue, when particles are simulated on a queue of their
+    /// own.
+    transfer: Option<QueueTransfer>,
+    /// Whether the buffers were released to the graphics queue this frame.
+    handed_over: bool,
+    last_update: Instant,
+    seed: u32,
+}
+
+impl ParticleSystem {
+    /// With a `transfer`, [`Self::simulate`] records on the compute queue family it starts from.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+        descriptor_backend: DescriptorBackend,
+        pipeline_cache: vk::PipelineCache,
+        transfer: Option<QueueTransfer>,
+        shader_errors: &mut ShaderErrors,
+    ) -> eyre::Result<Self> {
+        let pipelines = shader_errors.or_fallback(
+            PARTICLES_NAME,
+            ParticlePipelines::new(
+                device,
+                shader_compiler,
+                color_formats,
+                depth_image,
+                descriptor_backend,
+                pipeline_cache,
+            )
+            .map(Some),
+            || Ok(None),
+        )?;
+        Ok(Self {
+            pipelines,
+            emitters: vec![],
+            descriptor_backend,
+            pipeline_cache,
+            transfer,
+            handed_over: false,
+            last_update: Instant::now(),
+            seed: 0,
+        })
+    }
+    pub fn add_emitter(
+        &mut self,
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+        config: EmitterConfig,
+    ) -> eyre::Result<()> {
+        self.emitters
+            .push(Emitter::new(device, allocator, config, DEFAULT_CAPACITY)?);
+        Ok(())
+    }
+    /// Whether [`Self::reload`] should run for `changed`. Always true while the shaders are
+    /// broken, like for the other pipelines' fallbacks.
+    pub fn affected_by(&self, changed: &[PathBuf]) -> bool {
+        self.pipelines
+            .as_ref()
+            .is_none_or(|pipelines| pipelines.affected_by(changed))
+    }
+    /// Rebuilds the pipelines for changed shaders or render targets. On failure the current
+    /// pipelines stay in use, unless the sample count changed, in which case particles are not
+    /// drawn until the shaders compile again. Sample counts only change while the device is idle.
+    pub fn reload(
+        &mut self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        color_formats: &[vk::Format],
+        depth_image: &AllocatedImage,
+        retire: impl FnMut(vk::Pipeline),
+    ) -> eyre::Result<()> {
+        let Some(pipelines) = &mut self.pipelines else {
+            self.pipelines = Some(ParticlePipelines::new(
+                device,
+                shader_compiler,
+                color_formats,
+                depth_image,
+                self.descriptor_backend,
+                self.pipeline_cache,
+            )?);
+            return Ok(());
+        };
+        let samples_changed = pipelines.draw.samples != depth_image.samples();
+        let result = pipelines.reload(
+            device,
+            shader_compiler,
+            color_formats,
+            depth_image,
+            self.pipeline_cache,
+            retire,
+        );
+        if result.is_err() && samples_changed {
+            pipelines.destroy(device);
+            self.pipelines = None;
+        }
+        result
+    }
+    /// Records the spawn and update passes of every emitter. `nodes` are the world transforms
+    /// of the scene nodes emitters can follow. With a queue transfer, `cmd` is submitted on the
+    /// compute queue and the buffers are then released to the graphics queue, which takes them
+    /// with [`Self::acquire`].
+    pub fn simulate(
+        &mut self,
+        device: &ash::Device,
+        descriptor_buffer: Option<&DescriptorBufferAllocator>,
+        cmd: vk::CommandBuffer,
+        nodes: &[(&str, Affine3A)],
+    ) -> eyre::Result<()> {
+        let now = Instant::now();
+        let delta_time = (now - self.last_update).as_secs_f32().min(MAX_DELTA_TIME);
+        self.last_update = now;
+        let Some(pipelines) = &self.pipelines else {
+            return Ok(());
+        };
+        if let Some(transfer) = self.transfer {
+            let released = self
+                .emitters
+                .iter_mut()
+                .filter_map(|emitter| {
+                    std::mem::take(&mut emitter.released).then(|| emitter.buffer.buffer())
+                })
+                .collect::<Vec<_>>();
+            transfer.reversed().acquire_buffers(
+                device,
+                cmd,
+                &released,
+                vk::PipelineStageFlags2::COMPUTE_SHADER,
+                vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE,
+            );
+        }
+        for emitter in &mut self.emitters {
+            let transform = nodes
+                .iter()
+                .find(|(name, _)| *name == emitter.config.node)
+                .map_or(Affine3A::IDENTITY, |(_, transform)| *transform);
+            self.seed = self.seed.wrapping_add(1);
+            emitter.simulate(
+                device,
+                pipelines,
+                descriptor_buffer,
+                cmd,
+                delta_time,
+                transform,
+                self.seed,
+            )?;
+        }
+        if let Some(transfer) = self.transfer {
+            transfer.release_buffers(
+                device,
+                cmd,
+                &self.buffers(),
+                vk::PipelineStageFlags2::COMPUTE_SHADER,
+                vk::AccessFlags2::SHADER_STORAGE_WRITE,
+            );
+            self.handed_over = true;
+        }
+        Ok(())
+    }
+    fn buffers(&self) -> Vec<vk::Buffer> {
+        self.emitters
+            .iter()
+            .map(|emitter| emitter.buffer.buffer())
+            .collect()
+    }
+    /// Takes the buffers released by [`Self::simulate`] over to the graphics queue, outside of
+    /// the geometry pass.
+    pub fn acquire(&self, device: &ash::Device, cmd: vk::CommandBuffer) {
+        if let Some(transfer) = self.transfer
+            && self.handed_over
+        {
+            transfer.acquire_buffers(
+                device,
+                cmd,
+                &self.buffers(),
+                vk::PipelineStageFlags2::VERTEX_SHADER,
+                vk::AccessFlags2::SHADER_STORAGE_READ,
+            );
+        }
+    }
+    /// Hands the buffers back to the compute queue after the geometry pass.
+    pub fn release(&mut self, device: &ash::Device, cmd: vk::CommandBuffer) {
+        if let Some(transfer) = self.transfer
+            && std::mem::take(&mut self.handed_over)
+        {
+            transfer.reversed().release_buffers(
+                device,
+                cmd,
+                &self.buffers(),
+                vk::PipelineStageFlags2::VERTEX_SHADER,
+                vk::AccessFlags2::NONE,
+            );
+            for emitter in &mut self.emitters {
+                emitter.released = true;
+            }
+        }
+    }
+    /// Draws every emitter's particles inside the geometry pass, which has its viewport and
+    /// scissor set.
+    pub fn draw(&self, device: &ash::Device, cmd: vk::CommandBuffer, view: Mat4, proj: Mat4) {
+        let Some(pipelines) = &self.pipelines else {
+            return;
+        };
+        let draw = &pipelines.draw;
+        unsafe { device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, draw.pipeline) };
+        let camera = view.inverse();
+        for emitter in &self.emitters {
+            let push_constants = DrawPushConstants {
+                view_proj: proj * view,
+                camera_right: camera.x_axis.truncate().extend(emitter.config.size),
+                camera_up: camera.y_axis.truncate().extend(0.0),
+                end_color: Vec4::from_array(emitter.config.end_color),
+                particles: emitter.address,
+                _pad: 0,
+            };
+            unsafe {
+                device.cmd_push_constants(
+                    cmd,
+                    draw.layout,
+                    vk::ShaderStageFlags::VERTEX,
+                    0,
+                    bytemuck::bytes_of(&push_constants),
+                );
+                device.cmd_draw(cmd, emitter.capacity * 6, 1, 0, 0);
+            };
+        }
+    }
+    /// Edits the emitters. Removing one waits for the device, since its buffer may be in use.
+    pub fn ui(
+        &mut self,
+        ui: &mut Ui,
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+        nodes: &[&str],
+    ) -> eyre::Result<()> {
+        let mut removed = None;
+        for (i, emitter) in self.emitters.iter_mut().enumerate() {
+            egui::CollapsingHeader::new(&emitter.config.name)
+                .id_salt(i)
+                .show(ui, |ui| {
+                    emitter.ui(ui, nodes);
+                    if ui.button("Remove Emitter").clicked() {
+                        removed = Some(i);
+                    }
+                });
+        }
+        if let Some(i) = removed {
+            unsafe { device.device_wait_idle() }?;
+            self.emitters.remove(i).destroy(allocator);
+        }
+        if ui.button("Add Emitter").clicked() {
+            let config = EmitterConfig {
+                name: format!("Emitter {}", self.emitters.len() + 1),
+                ..EmitterConfig::default()
+            };
+            self.add_emitter(device, allocator, config)?;
+        }
+        Ok(())
+    }
+    pub fn destroy(&mut self, device: &ash::Device, allocator: &vk_mem::Allocator) {
+        if let Some(pipelines) = &mut self.pipelines {
+            pipelines.destroy(device);
+        }
+        for emitter in &mut self.emitters {
+            emitter.destroy(allocator);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    sync::{Arc, Mutex, mpsc},
+    thread::JoinHandle,
+};
+
+use ash::vk;
+use eyre::eyre;
+
+use crate::shader::{ShaderCompiler, ShaderDependencies};
+
+pub type BuiltPipeline = eyre::Result<(vk::Pipeline, ShaderDependencies)>;
+type Job = Box<dyn FnOnce(&ash::Device, &ShaderCompiler) -> BuiltPipeline + Send>;
+
+/// Identifies a pipeline submitted to a [`PipelineBuilder`] until it is returned by
+/// [`PipelineBuilder::poll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PipelineTicket(u64);
+
+/// Compiles shaders and creates pipelines on worker threads, each with its own
+/// [`ShaderCompiler`], so that startup and new materials do not stall the frame.
+pub struct PipelineBuilder {
+    jobs: Option<mpsc::Sender<(PipelineTicket, Job)>>,
+    results: mpsc::Receiver<(PipelineTicket, BuiltPipeline)>,
+    workers: Vec<JoinHandle<()>>,
+    next_ticket: u64,
+}
+
+impl PipelineBuilder {
+    /// Spawns one worker per core, minus the one driving the render loop.
+    pub fn new(device: &ash::Device) -> eyre::Result<Self> {
+        let worker_count = std::thread::available_parallelism()
+            .map_or(1, |n| n.get().saturating_sub(1))
+            .max(1);
+        let (jobs, job_receiver) = mpsc::channel::<(PipelineTicket, Job)>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, results) = mpsc::channel();
+        let workers = (0..worker_count)
+            .map(|i| {
+                let device = device.clone();
+                let job_receiver = Arc::clone(&job_receiver);
+                let result_sender = result_sender.clone();
+                std::thread::Builder::new()
+                    .name(format!("pipeline builder {i}"))
+                    .spawn(move || {
+                        let shader_compiler = ShaderCompiler::new();
+                        loop {
+                            let Ok(receiver) = job_receiver.lock() else {
+                                return;
+                            };
+                            let job = receiver.recv();
+                            // Let the other workers take jobs while this one runs.
+                            drop(receiver);
+                            let Ok((ticket, job)) = job else {
+                                return;
+                            };
+                            let built = match &shader_compiler {
+                                Ok(shader_compiler) => job(&device, shader_compiler),
+                                Err(e) => Err(eyre!("no shader compiler on this worker: {e}")),
+                            };
+                            if result_sender.send((ticket, built)).is_err() {
+                                return;
+                            }
+                        }
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            jobs: Some(jobs),
+            results,
+            workers,
+            next_ticket: 0,
+        })
+    }
+    pub fn submit(
+        &mut self,
+        job: impl FnOnce(&ash::Device, &ShaderCompiler) -> BuiltPipeline + Send + 'static,
+    ) -> eyre::Result<PipelineTicket> {
+        let ticket = PipelineTicket(self.next_ticket);
+        self.next_ticket += 1;
+        self.jobs
+            .as_ref()
+            .ok_or_else(|| eyre!("the pipeline builder is shut down"))?
+            .send((ticket, Box::new(job)))
+            .map_err(|e| eyre!("the pipeline builder workers exited: {e}"))?;
+        Ok(ticket)
+    }
+    /// Returns the pipelines finished since the last call without blocking. The caller owns them.
+    pub fn poll(&self) -> Vec<(PipelineTicket, BuiltPipeline)> {
+        self.results.try_iter().collect()
+    }
+    /// Waits for the queued jobs and destroys the pipelines nobody polled.
+    pub fn destroy(&mut self, device: &ash::Device) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                tracing::error!("a pipeline builder worker panicked");
+            }
+        }
+        for (_, built) in self.results.try_iter() {
+            if let Ok((pipeline, _)) = built {
+                unsafe { device.destroy_pipeline(pipeline, None) };
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::{fs, path::PathBuf};
+
+use ash::vk;
+use eyre::Context;
+
+use crate::vulkan::Vulkan;
+
+/// `VkPipelineCacheHeaderVersionOne` is 32 bytes: header size, header version, vendor id and
+/// device id as u32s followed by the pipeline cache UUID.
+const HEADER_SIZE: usize = 32;
+
+/// A `VkPipelineCache` persisted to disk between runs.
+pub struct PipelineCache {
+    cache: vk::PipelineCache,
+    path: PathBuf,
+}
+
+/// Returns whether `data` was written by the same driver and device, anything else is rejected
+/// instead of being handed to the driver.
+fn header_matches(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    let Some(header) = data.get(..HEADER_SIZE) else {
+        return false;
+    };
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes([
+            header[offset],
+            header[offset + 1],
+            header[offset + 2],
+            header[offset + 3],
+        ])
+    };
+    u32_at(0) as usize >= HEADER_SIZE
+        && u32_at(4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && u32_at(8) == properties.vendor_id
+        && u32_at(12) == properties.device_id
+        && header[16..32] == properties.pipeline_cache_uuid
+}
+
+impl PipelineCache {
+    /// `VKGUIDE_PIPELINE_CACHE` if set, `.pipeline_cache` in the working directory otherwise.
+    pub fn default_path() -> PathBuf {
+        std::env::var_os("VKGUIDE_PIPELINE_CACHE")
+            .map_or_else(|| PathBuf::from(".pipeline_cache"), PathBuf::from)
+    }
+    pub fn new(vulkan: &Vulkan, path: impl Into<PathBuf>) -> eyre::Result<Self> {
+        let path = path.into();
+        let properties = unsafe {
+            vulkan
+                .instance()
+                .get_physical_device_properties(vulkan.physical_device())
+        };
+        let data = match fs::read(&path) {
+            Ok(data) if header_matches(&data, &properties) => data,
+            Ok(_) => {
+                tracing::info!(
+                    "ignoring {}, it was created by another driver or device",
+                    path.display()
+                );
+                vec![]
+            }
+            Err(_) => vec![],
+        };
+        let info = vk::PipelineCacheCreateInfo::default().initial_data(&data);
+        let cache = unsafe { vulkan.device().create_pipeline_cache(&info, None) }?;
+        Ok(Self { cache, path })
+    }
+    pub const fn cache(&self) -> vk::PipelineCache {
+        self.cache
+    }
+    pub fn save(&self, device: &ash::Device) -> eyre::Result<()> {
+        let data = unsafe { device.get_pipeline_cache_data(self.cache) }?;
+        // Write then rename so a crash never leaves a truncated cache behind.
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data).wrap_err_with(|| format!("could not write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+    pub fn destroy(&mut self, device: &ash::Device) {
+        unsafe { device.destroy_pipeline_cache(self.cache, None) };
+    }
+}
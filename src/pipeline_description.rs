@@ -0,0 +1,575 @@
+use std::{borrow::Cow, ffi::CString, fs, io::ErrorKind, path::Path};
+
+use ash::vk;
+use eyre::{WrapErr, eyre};
+use serde::Deserialize;
+
+use crate::{
+    descriptors::DescriptorBackend,
+    graphics::{
+        Blending, ColorAttachment, DynamicGroup, GraphicsPipelineInfo, ShaderStage, VertexFetch,
+        VertexInput, validate_stages,
+    },
+    mesh::Vertex,
+    shader::{ShaderCompiler, ShaderDefines, ShaderDependencies, ShaderKind, ShaderSource},
+    specialization::SpecializationConstants,
+};
+
+/// Directory holding the `<name>.toml` pipeline descriptions.
+pub const PIPELINE_DIR: &str = "pipelines";
+
+/// Descriptions the engine cannot run without, used when their file is missing.
+fn builtin_description(name: &str) -> Option<&'static str> {
+    let source = match name {
+        "material" => include_str!("../pipelines/material.toml"),
+        "mesh" => include_str!("../pipelines/mesh.toml"),
+        "mesh_attributes" => include_str!("../pipelines/mesh_attributes.toml"),
+        _ => return None,
+    };
+    Some(source)
+}
+
+/// Declares an enum read from its snake case name in pipeline descriptions, and the Vulkan value
+/// it stands for.
+macro_rules! vk_enum {
+    (
+        $(#[$meta:meta])*
+        $name:ident($vk:ty) { $($(#[$variant_meta:meta])* $variant:ident => $value:ident),+ $(,)? }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        enum $name {
+            $($(#[$variant_meta])* $variant),+
+        }
+
+        impl $name {
+            const fn vk(self) -> $vk {
+                match self {
+                    $(Self::$variant => <$vk>::$value),+
+                }
+            }
+        }
+    };
+}
+
+vk_enum!(
+    #[derive(Default)]
+    Topology(vk::PrimitiveTopology) {
+        PointList => POINT_LIST,
+        LineList => LINE_LIST,
+        LineStrip => LINE_STRIP,
+        #[default]
+        TriangleList => TRIANGLE_LIST,
+        TriangleStrip => TRIANGLE_STRIP,
+        TriangleFan => TRIANGLE_FAN,
+        PatchList => PATCH_LIST,
+    }
+);
+vk_enum!(
+    #[derive(Default)]
+    PolygonMode(vk::PolygonMode) {
+        #[default]
+        Fill => FILL,
+        Line => LINE,
+        Point => POINT,
+    }
+);
+vk_enum!(
+    #[derive(Default)]
+    CullMode(vk::CullModeFlags) {
+        #[default]
+        None => NONE,
+        Front => FRONT,
+        Back => BACK,
+        FrontAndBack => FRONT_AND_BACK,
+    }
+);
+vk_enum!(
+    #[derive(Default)]
+    FrontFace(vk::FrontFace) {
+        #[default]
+        Clockwise => CLOCKWISE,
+        CounterClockwise => COUNTER_CLOCKWISE,
+    }
+);
+vk_enum!(
+    CompareOp(vk::CompareOp) {
+        Never => NEVER,
+        Less => LESS,
+        Equal => EQUAL,
+        LessOrEqual => LESS_OR_EQUAL,
+        Greater => GREATER,
+        NotEqual => NOT_EQUAL,
+        GreaterOrEqual => GREATER_OR_EQUAL,
+        Always => ALWAYS,
+    }
+);
+vk_enum!(
+    Format(vk::Format) {
+        R8g8b8a8Unorm => R8G8B8A8_UNORM,
+        B8g8r8a8Unorm => B8G8R8A8_UNORM,
+        R16g16b16a16Sfloat => R16G16B16A16_SFLOAT,
+        R32g32b32a32Sfloat => R32G32B32A32_SFLOAT,
+        R32Sfloat => R32_SFLOAT,
+        R32Uint => R32_UINT,
+        D16Unorm => D16_UNORM,
+        D32Sfloat => D32_SFLOAT,
+        D24UnormS8Uint => D24_UNORM_S8_UINT,
+        D32SfloatS8Uint => D32_SFLOAT_S8_UINT,
+    }
+);
+
+/// Pipeline layouts, i.e. the descriptor sets and push constants the shaders of a description
+/// expect.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutKind {
+    /// [`crate::graphics::MeshPipeline`]'s: draw push constants and a single texture.
+    #[default]
+    Mesh,
+    /// The metallic roughness material's: scene data, material set and draw push constants.
+    Material,
+}
+
+/// Vertex structs a description can read through vertex input bindings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum VertexLayoutName {
+    Vertex,
+}
+
+/// Shader of each stage, as `file` or `file:EntryPoint`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StageDescriptions {
+    vertex: Option<String>,
+    tess_control: Option<String>,
+    tess_evaluation: Option<String>,
+    geometry: Option<String>,
+    task: Option<String>,
+    mesh: Option<String>,
+    fragment: Option<String>,
+}
+
+impl StageDescriptions {
+    fn stages(&self) -> Vec<(ShaderKind, ShaderSource)> {
+        [
+            (ShaderKind::Vertex, &self.vertex),
+            (ShaderKind::TessControl, &self.tess_control),
+            (ShaderKind::TessEvaluation, &self.tess_evaluation),
+            (ShaderKind::Geometry, &self.geometry),
+            (ShaderKind::Task, &self.task),
+            (ShaderKind::Mesh, &self.mesh),
+            (ShaderKind::Fragment, &self.fragment),
+        ]
+        .into_iter()
+        .filter_map(|(kind, shader)| {
+            let source = match shader.as_deref()?.split_once(':') {
+                Some((name, entry_point)) => ShaderSource::new(name).with_entry_point(entry_point),
+                None => ShaderSource::new(shader.as_deref()?),
+            };
+            Some((kind, source))
+        })
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct DepthDescription {
+    test: bool,
+    write: bool,
+    compare: CompareOp,
+}
+
+impl Default for DepthDescription {
+    fn default() -> Self {
+        Self {
+            test: true,
+            write: true,
+            compare: CompareOp::GreaterOrEqual,
+        }
+    }
+}
+
+/// Formats and sample count of the pass a described pipeline renders in.
+pub struct RenderTargets<'a> {
+    pub color_formats: &'a [vk::Format],
+    pub depth_format: vk::Format,
+    pub samples: vk::SampleCountFlags,
+}
+
+/// A graphics pipeline read from `pipelines/<name>.toml`. The pipeline layout, dynamic state and
+/// render targets come from the code drawing with it, the rest from the file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineDescription {
+    #[serde(default)]
+    layout: LayoutKind,
+    shaders: StageDescriptions,
+    #[serde(default)]
+    topology: Topology,
+    patch_control_points: Option<u32>,
+    #[serde(default)]
+    polygon_mode: PolygonMode,
+    #[serde(default)]
+    cull_mode: CullMode,
+    #[serde(default)]
+    front_face: FrontFace,
+    #[serde(default)]
+    depth: DepthDescription,
+    /// Of the first color attachment.
+    blending: Option<Blending>,
+    /// Checked against the render targets, which they default to.
+    color_formats: Option<Vec<Format>>,
+    depth_format: Option<Format>,
+    /// Vertices are pulled through buffer device addresses when unset.
+    vertex_input: Option<VertexLayoutName>,
+    /// Set by the code creating the pipeline, see [`Self::specialize`].
+    #[serde(skip)]
+    specialization: Vec<(ShaderKind, SpecializationConstants)>,
+}
+
+impl PipelineDescription {
+    /// Reads and validates the description `name`, written for the `layout` pipeline layout. Its
+    /// dependencies are the file itself.
+    pub fn load(name: &str, layout: LayoutKind) -> eyre::Result<(Self, ShaderDependencies)> {
+        let (description, dependencies) = Self::read(name)?;
+        if description.layout != layout {
+            return Err(eyre!(
+                "pipeline description {name} is written for the {:?} layout, not {layout:?}",
+                description.layout
+            ));
+        }
+        Ok((description, dependencies))
+    }
+    fn read(name: &str) -> eyre::Result<(Self, ShaderDependencies)> {
+        let path = Path::new(PIPELINE_DIR).join(format!("{name}.toml"));
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => Cow::Owned(source),
+            Err(e) if e.kind() == ErrorKind::NotFound => Cow::Borrowed(
+                builtin_description(name)
+                    .ok_or_else(|| eyre!("could not read {}: {e}", path.display()))?,
+            ),
+            Err(e) => return Err(e).wrap_err_with(|| format!("could not read {}", path.display())),
+        };
+        let description = Self::parse(&source)
+            .wrap_err_with(|| format!("invalid pipeline description {}", path.display()))?;
+        let files = fs::canonicalize(&path).into_iter().collect();
+        Ok((description, ShaderDependencies::from_files(files)))
+    }
+    fn parse(source: &str) -> eyre::Result<Self> {
+        let description: Self = toml::from_str(source)?;
+        let kinds: Vec<_> = description
+            .shaders
+            .stages()
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .collect();
+        // The stages the device supports are checked when the pipeline is created.
+        validate_stages(
+            &kinds,
+            description.topology.vk(),
+            description.patch_control_points,
+            vk::ShaderStageFlags::ALL_GRAPHICS
+                | vk::ShaderStageFlags::TASK_EXT
+                | vk::ShaderStageFlags::MESH_EXT,
+        )?;
+        if description.vertex_input.is_some() && !kinds.contains(&ShaderKind::Vertex) {
+            return Err(eyre!("vertex input needs a vertex shader"));
+        }
+        Ok(description)
+    }
+    /// Names of the descriptions in [`PIPELINE_DIR`] and the builtin ones, sorted.
+    pub fn names() -> Vec<String> {
+        let mut names: Vec<_> = ["material", "mesh", "mesh_attributes"]
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        if let Ok(entries) = fs::read_dir(PIPELINE_DIR) {
+            names.extend(entries.filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "toml" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_owned())
+            }));
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+    /// Reads every description, so that mistakes show up before one is used, and returns the
+    /// layout of the valid ones.
+    pub fn validate_all() -> Vec<(String, eyre::Result<LayoutKind>)> {
+        Self::names()
+            .into_iter()
+            .map(|name| {
+                let result = Self::read(&name).map(|(description, _)| description.layout);
+                (name, result)
+            })
+            .collect()
+    }
+    /// A plain description drawing with `vertex` and `fragment`, for the error shaders.
+    pub fn fallback(layout: LayoutKind, vertex: &str, fragment: &str) -> Self {
+        Self {
+            layout,
+            shaders: StageDescriptions {
+                vertex: Some(vertex.to_owned()),
+                fragment: Some(fragment.to_owned()),
+                ..StageDescriptions::default()
+            },
+            topology: Topology::default(),
+            patch_control_points: None,
+            polygon_mode: PolygonMode::default(),
+            cull_mode: CullMode::default(),
+            front_face: FrontFace::default(),
+            depth: DepthDescription::default(),
+            blending: None,
+            color_formats: None,
+            depth_format: None,
+            vertex_input: None,
+            specialization: Vec::new(),
+        }
+    }
+    /// Blends with `blending` without testing or writing depth.
+    #[must_use]
+    pub const fn transparent(mut self, blending: Blending) -> Self {
+        self.blending = Some(blending);
+        self.depth.test = false;
+        self.depth.write = false;
+        self
+    }
+    /// Specializes the shader of the `kind` stage with `constants`.
+    #[must_use]
+    pub fn specialize(mut self, kind: ShaderKind, constants: SpecializationConstants) -> Self {
+        self.specialization.push((kind, constants));
+        self
+    }
+    pub const fn vertex_fetch(&self) -> VertexFetch {
+        if self.vertex_input.is_some() {
+            VertexFetch::Attributes
+        } else {
+            VertexFetch::Pulling
+        }
+    }
+    fn check_targets(&self, targets: &RenderTargets) -> eyre::Result<()> {
+        if let Some(color_formats) = &self.color_formats {
+            let color_formats: Vec<_> = color_formats.iter().map(|format| format.vk()).collect();
+            if color_formats != targets.color_formats {
+                return Err(eyre!(
+                    "color formats {color_formats:?} do not match the pass formats {:?}",
+                    targets.color_formats
+                ));
+            }
+        }
+        if let Some(depth_format) = self.depth_format
+            && depth_format.vk() != targets.depth_format
+        {
+            return Err(eyre!(
+                "depth format {:?} does not match the pass format {:?}",
+                depth_format.vk(),
+                targets.depth_format
+            ));
+        }
+        Ok(())
+    }
+    /// Compiles the shaders, each with the `defines` of its stage, and creates the pipeline.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        &self,
+        device: &ash::Device,
+        shader_compiler: &ShaderCompiler,
+        layout: vk::PipelineLayout,
+        pipeline_cache: vk::PipelineCache,
+        descriptor_backend: DescriptorBackend,
+        enabled_stages: vk::ShaderStageFlags,
+        targets: &RenderTargets,
+        dynamic: &[DynamicGroup],
+        defines: &dyn Fn(ShaderKind) -> ShaderDefines,
+    ) -> eyre::Result<(vk::Pipeline, ShaderDependencies)> {
+        self.check_targets(targets)?;
+        let stages = self.shaders.stages();
+        let mut modules = Vec::with_capacity(stages.len());
+        let mut dependencies = ShaderDependencies::default();
+        for (kind, source) in &stages {
+            match shader_compiler.load_permutation(device, source.clone(), *kind, &defines(*kind)) {
+                Ok((module, stage_dependencies)) => {
+                    dependencies.extend(&stage_dependencies);
+                    modules.push(module);
+                }
+                Err(e) => {
+                    destroy_shader_modules(device, modules);
+                    return Err(e);
+                }
+            }
+        }
+        let pipeline = self.create_pipeline(
+            device,
+            layout,
+            pipeline_cache,
+            descriptor_backend,
+            enabled_stages,
+            targets,
+            dynamic,
+            &stages,
+            &modules,
+        );
+        destroy_shader_modules(device, modules);
+        Ok((pipeline?, dependencies))
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn create_pipeline(
+        &self,
+        device: &ash::Device,
+        layout: vk::PipelineLayout,
+        pipeline_cache: vk::PipelineCache,
+        descriptor_backend: DescriptorBackend,
+        enabled_stages: vk::ShaderStageFlags,
+        targets: &RenderTargets,
+        dynamic: &[DynamicGroup],
+        stages: &[(ShaderKind, ShaderSource)],
+        modules: &[vk::ShaderModule],
+    ) -> eyre::Result<vk::Pipeline> {
+        let entry_points = stages
+            .iter()
+            .map(|(_, source)| source.entry_point_c())
+            .collect::<eyre::Result<Vec<CString>>>()?;
+        let stages: Vec<_> = stages
+            .iter()
+            .zip(modules)
+            .zip(&entry_points)
+            .map(|(((kind, _), module), entry_point)| {
+                let stage = ShaderStage::new(*kind, *module).entry_point(entry_point);
+                self.specialization
+                    .iter()
+                    .find(|(specialized, _)| specialized == kind)
+                    .map_or(stage, |(_, constants)| stage.specialization(constants))
+            })
+            .collect();
+        let color_attachments = self.blending.map_or_else(
+            || {
+                targets
+                    .color_formats
+                    .iter()
+                    .map(|format| ColorAttachment::new(*format))
+                    .collect()
+            },
+            |blending| ColorAttachment::with_formats(targets.color_formats, blending),
+        );
+        let vertex_input = self.vertex_input.map(|layout| match layout {
+            VertexLayoutName::Vertex => VertexInput::new().layout::<Vertex>(0),
+        });
+        GraphicsPipelineInfo::builder()
+            .layout(layout)
+            .stages(&stages)
+            .maybe_vertex_input(vertex_input.as_ref())
+            .topology(self.topology.vk())
+            .maybe_patch_control_points(self.patch_control_points)
+            .polygon_mode(self.polygon_mode.vk())
+            .cull_mode(self.cull_mode.vk())
+            .front_face(self.front_face.vk())
+            .color_attachments(&color_attachments)
+            .depth_format(targets.depth_format)
+            .depth_enabled(self.depth.test)
+            .depth_write_enabled(self.depth.write)
+            .depth_compare_op(self.depth.compare.vk())
+            .dynamic(dynamic)
+            .samples(targets.samples)
+            .pipeline_cache(pipeline_cache)
+            .flags(descriptor_backend.pipeline_create_flags())
+            .enabled_stages(enabled_stages)
+            .build()
+            .create(device)
+    }
+}
+
+fn destroy_shader_modules(device: &ash::Device, modules: Vec<vk::ShaderModule>) {
+    for module in modules {
+        unsafe { device.destroy_shader_module(module, None) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_description() {
+        let description = PipelineDescription::parse(
+            r#"
+            layout = "material"
+            topology = "triangle_strip"
+            cull_mode = "back"
+            front_face = "counter_clockwise"
+            color_formats = ["r16g16b16a16_sfloat"]
+            depth_format = "d32_sfloat"
+
+            [shaders]
+            vertex = "mesh.vert"
+            fragment = "mesh.frag:frag_main"
+
+            [depth]
+            write = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(description.layout, LayoutKind::Material);
+        assert_eq!(description.topology, Topology::TriangleStrip);
+        assert_eq!(description.polygon_mode, PolygonMode::Fill);
+        assert_eq!(description.cull_mode, CullMode::Back);
+        assert_eq!(description.front_face, FrontFace::CounterClockwise);
+        assert_eq!(
+            description.color_formats,
+            Some(vec![Format::R16g16b16a16Sfloat])
+        );
+        assert_eq!(description.depth_format, Some(Format::D32Sfloat));
+        assert!(description.depth.test && !description.depth.write);
+        assert_eq!(description.depth.compare, CompareOp::GreaterOrEqual);
+        let kinds: Vec<_> = description
+            .shaders
+            .stages()
+            .into_iter()
+            .map(|(kind, source)| (kind, source.entry_point().to_owned()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (ShaderKind::Vertex, "main".to_owned()),
+                (ShaderKind::Fragment, "frag_main".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn builtin_descriptions_parse() {
+        for name in ["material", "mesh", "mesh_attributes"] {
+            PipelineDescription::parse(builtin_description(name).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn unknown_enum_value() {
+        let error = PipelineDescription::parse(
+            r#"
+            cull_mode = "sideways"
+
+            [shaders]
+            vertex = "mesh.vert"
+            fragment = "mesh.frag"
+            "#,
+        )
+        .unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("unknown variant `sideways`"), "{message}");
+        assert!(message.contains("cull_mode"), "{message}");
+    }
+
+    #[test]
+    fn missing_required_field() {
+        let error = PipelineDescription::parse(r#"topology = "triangle_list""#).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("missing field `shaders`"), "{message}");
+    }
+}
@@ -1,16 +1,289 @@
-use std::{
-    fs::{self, read_to_string},
-    path::Path,
-};
+#[cfg(feature = "runtime-shaders")]
+use std::{borrow::Cow, cell::RefCell, fs::read_to_string, path::Path};
+use std::{collections::BTreeMap, ffi::CString, fs, path::PathBuf};
 
 use ash::vk;
+#[cfg(feature = "runtime-shaders")]
+use eyre::Context;
+#[cfg(not(feature = "runtime-shaders"))]
 use eyre::{Context, OptionExt};
-use shaderc::ResolvedInclude;
+#[cfg(feature = "runtime-shaders")]
+use shaderc::{IncludeType, ResolvedInclude};
+
+#[cfg(feature = "runtime-shaders")]
+use crate::shader_cache::{SpirvCache, hash_parts};
+
+pub const SHADER_DIR: &str = "shaders";
+/// In dev mode shader sources are read from [`SHADER_DIR`] at runtime so they can be hot reloaded,
+/// otherwise the sources baked into the binary are used.
+pub const HOT_RELOAD_ENABLED: bool = cfg!(all(debug_assertions, feature = "runtime-shaders"));
+
+#[cfg(feature = "runtime-shaders")]
+fn embedded_source(name: &str) -> Option<&'static str> {
+    let source = match name {
+        "colored_triangle.frag" => include_str!("../shaders/colored_triangle.frag"),
+        "colored_triangle.vert" => include_str!("../shaders/colored_triangle.vert"),
+        "colored_triangle_mesh.vert" => include_str!("../shaders/colored_triangle_mesh.vert"),
+        "colored_triangle_mesh_attributes.vert" => {
+            include_str!("../shaders/colored_triangle_mesh_attributes.vert")
+        }
+        "copy_image.frag" => include_str!("../shaders/copy_image.frag"),
+        "error.comp" => include_str!("../shaders/error.comp"),
+        "error.frag" => include_str!("../shaders/error.frag"),
+        "error.vert" => include_str!("../shaders/error.vert"),
+        "fullscreen.vert" => include_str!("../shaders/fullscreen.vert"),
+        "gradient.comp" => include_str!("../shaders/gradient.comp"),
+        "gradient.comp.hlsl" => include_str!("../shaders/gradient.comp.hlsl"),
+        "gradient_color.comp" => include_str!("../shaders/gradient_color.comp"),
+        "mesh.frag" => include_str!("../shaders/mesh.frag"),
+        "mesh.vert" => include_str!("../shaders/mesh.vert"),
+        "particle.frag" => include_str!("../shaders/particle.frag"),
+        "particle.vert" => include_str!("../shaders/particle.vert"),
+        "particle_spawn.comp" => include_str!("../shaders/particle_spawn.comp"),
+        "particle_update.comp" => include_str!("../shaders/particle_update.comp"),
+        "sky.comp" => include_str!("../shaders/sky.comp"),
+        "tex_image.frag" => include_str!("../shaders/tex_image.frag"),
+        _ => return None,
+    };
+    Some(source)
+}
+
+// Generated by build.rs, which compiles every shader with glslc.
+#[cfg(not(feature = "runtime-shaders"))]
+include!(concat!(env!("OUT_DIR"), "/precompiled_shaders.rs"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderKind {
+    Vertex,
+    TessControl,
+    TessEvaluation,
+    Geometry,
+    Task,
+    Mesh,
+    Fragment,
+    Compute,
+}
+
+impl ShaderKind {
+    #[cfg(feature = "runtime-shaders")]
+    const fn to_shaderc(self) -> shaderc::ShaderKind {
+        match self {
+            Self::Vertex => shaderc::ShaderKind::Vertex,
+            Self::TessControl => shaderc::ShaderKind::TessControl,
+            Self::TessEvaluation => shaderc::ShaderKind::TessEvaluation,
+            Self::Geometry => shaderc::ShaderKind::Geometry,
+            Self::Task => shaderc::ShaderKind::Task,
+            Self::Mesh => shaderc::ShaderKind::Mesh,
+            Self::Fragment => shaderc::ShaderKind::Fragment,
+            Self::Compute => shaderc::ShaderKind::Compute,
+        }
+    }
+    pub const fn stage(self) -> vk::ShaderStageFlags {
+        match self {
+            Self::Vertex => vk::ShaderStageFlags::VERTEX,
+            Self::TessControl => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+            Self::TessEvaluation => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+            Self::Geometry => vk::ShaderStageFlags::GEOMETRY,
+            Self::Task => vk::ShaderStageFlags::TASK_EXT,
+            Self::Mesh => vk::ShaderStageFlags::MESH_EXT,
+            Self::Fragment => vk::ShaderStageFlags::FRAGMENT,
+            Self::Compute => vk::ShaderStageFlags::COMPUTE,
+        }
+    }
+}
+
+#[cfg(feature = "runtime-shaders")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderLanguage {
+    Glsl,
+    Hlsl,
+}
+
+#[cfg(feature = "runtime-shaders")]
+impl ShaderLanguage {
+    /// HLSL for `.hlsl` files, GLSL otherwise. HLSL files are named `<name>.<stage>.hlsl`.
+    pub fn from_name(name: &str) -> Self {
+        if std::path::Path::new(name)
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("hlsl"))
+        {
+            Self::Hlsl
+        } else {
+            Self::Glsl
+        }
+    }
+    const fn to_shaderc(self) -> shaderc::SourceLanguage {
+        match self {
+            Self::Glsl => shaderc::SourceLanguage::GLSL,
+            Self::Hlsl => shaderc::SourceLanguage::HLSL,
+        }
+    }
+}
+
+/// A shader file and the function it starts executing from, `main` unless told otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShaderSource {
+    name: String,
+    entry_point: String,
+}
+
+impl ShaderSource {
+    pub const DEFAULT_ENTRY_POINT: &str = "main";
+
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            entry_point: Self::DEFAULT_ENTRY_POINT.to_owned(),
+        }
+    }
+    #[must_use]
+    pub fn with_entry_point(mut self, entry_point: impl Into<String>) -> Self {
+        self.entry_point = entry_point.into();
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn entry_point(&self) -> &str {
+        &self.entry_point
+    }
+    /// The entry point as passed to `vk::PipelineShaderStageCreateInfo::name`.
+    pub fn entry_point_c(&self) -> eyre::Result<CString> {
+        CString::new(self.entry_point.as_str())
+            .wrap_err_with(|| format!("invalid entry point for {}", self.name))
+    }
+    #[cfg(feature = "runtime-shaders")]
+    pub fn language(&self) -> ShaderLanguage {
+        ShaderLanguage::from_name(&self.name)
+    }
+}
+
+impl From<&str> for ShaderSource {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+impl From<String> for ShaderSource {
+    fn from(name: String) -> Self {
+        Self::new(name)
+    }
+}
+
+/// Preprocessor macros selecting one permutation of a shader.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct ShaderDefines {
+    defines: BTreeMap<String, Option<String>>,
+}
+
+impl ShaderDefines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[must_use]
+    pub fn define(mut self, name: impl Into<String>) -> Self {
+        self.defines.insert(name.into(), None);
+        self
+    }
+    #[must_use]
+    pub fn define_value(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.defines.insert(name.into(), Some(value.into()));
+        self
+    }
+    /// Canonical `NAME` / `NAME=VALUE` list sorted by name, also used by build.rs to name
+    /// precompiled permutations.
+    pub fn fingerprint(&self) -> String {
+        self.defines
+            .iter()
+            .map(|(name, value)| {
+                value
+                    .as_ref()
+                    .map_or_else(|| name.clone(), |value| format!("{name}={value}"))
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Canonical paths of a shader source and every file it includes.
+#[derive(Debug, Default, Clone)]
+pub struct ShaderDependencies {
+    files: Vec<PathBuf>,
+    unknown: bool,
+}
+
+impl ShaderDependencies {
+    /// Dependencies of a shader that failed to compile. Its includes are not known, so any change
+    /// may fix it.
+    pub const fn unknown() -> Self {
+        Self {
+            files: vec![],
+            unknown: true,
+        }
+    }
+    pub const fn from_files(files: Vec<PathBuf>) -> Self {
+        Self {
+            files,
+            unknown: false,
+        }
+    }
+    pub fn extend(&mut self, other: &Self) {
+        self.unknown |= other.unknown;
+        for file in &other.files {
+            if !self.files.contains(file) {
+                self.files.push(file.clone());
+            }
+        }
+    }
+    pub fn affected_by(&self, changed: &[PathBuf]) -> bool {
+        self.unknown || self.files.iter().any(|f| changed.contains(f))
+    }
+}
+
+/// Directories searched for `#include <...>`, and for `#include "..."` when the file is not next to
+/// the including one: [`SHADER_DIR`] followed by the entries of `VKGUIDE_SHADER_INCLUDE_PATH`.
+fn default_include_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(SHADER_DIR)];
+    if let Some(extra) = std::env::var_os("VKGUIDE_SHADER_INCLUDE_PATH") {
+        paths.extend(std::env::split_paths(&extra));
+    }
+    paths
+}
+
+#[cfg(feature = "runtime-shaders")]
+fn resolve_include(
+    requested: &str,
+    include_type: IncludeType,
+    including_file: &str,
+    include_paths: &[PathBuf],
+) -> Result<PathBuf, String> {
+    let next_to_includer = match include_type {
+        IncludeType::Relative => Path::new(including_file)
+            .parent()
+            .map(|dir| dir.join(requested)),
+        IncludeType::Standard => None,
+    };
+    let path = next_to_includer
+        .into_iter()
+        .chain(include_paths.iter().map(|dir| dir.join(requested)))
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(|| {
+            format!(
+                "could not find {requested} included from {including_file}, searched {include_paths:?}"
+            )
+        })?;
+    fs::canonicalize(&path).map_err(|e| format!("could not canonicalize {}: {e}", path.display()))
+}
 
 pub struct ShaderCompiler {
+    #[cfg(feature = "runtime-shaders")]
     compiler: shaderc::Compiler,
+    include_paths: Vec<PathBuf>,
+    #[cfg(feature = "runtime-shaders")]
+    cache: Option<SpirvCache>,
 }
 
+#[cfg(feature = "runtime-shaders")]
 impl std::ops::Deref for ShaderCompiler {
     type Target = shaderc::Compiler;
 
@@ -19,95 +292,243 @@ impl std::ops::Deref for ShaderCompiler {
     }
 }
 
+/// Everything besides the sources that changes the generated SPIR-V, folded into cache keys.
+#[cfg(feature = "runtime-shaders")]
+const OPTIONS_FINGERPRINT: &str = "target-env=vulkan1.3";
+#[cfg(feature = "runtime-shaders")]
+const OPTIMIZATION_LEVEL: shaderc::OptimizationLevel = shaderc::OptimizationLevel::Zero;
+
 impl ShaderCompiler {
+    #[cfg(feature = "runtime-shaders")]
     pub fn new() -> eyre::Result<Self> {
         let compiler = shaderc::Compiler::new()?;
+        let cache = match SpirvCache::new(SpirvCache::default_dir()) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                tracing::warn!("SPIR-V cache disabled: {e}");
+                None
+            }
+        };
 
-        Ok(Self { compiler })
+        Ok(Self {
+            compiler,
+            include_paths: default_include_paths(),
+            cache,
+        })
     }
-    pub fn default_options<'a>() -> shaderc::Result<shaderc::CompileOptions<'a>> {
+    #[cfg(not(feature = "runtime-shaders"))]
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn new() -> eyre::Result<Self> {
+        Ok(Self {
+            include_paths: default_include_paths(),
+        })
+    }
+    /// Directories that includes are resolved against, which hot reload also watches.
+    pub fn include_paths(&self) -> &[PathBuf] {
+        &self.include_paths
+    }
+    /// Relative includes are resolved against the directory of the including file first, with
+    /// `source` being the path of the shader itself. Including a file that is already being
+    /// included further up the chain fails with the whole chain in the error.
+    #[cfg(feature = "runtime-shaders")]
+    fn options<'a>(
+        &self,
+        source: &Path,
+        language: ShaderLanguage,
+        defines: &ShaderDefines,
+        included: Option<&'a RefCell<Vec<PathBuf>>>,
+    ) -> shaderc::Result<shaderc::CompileOptions<'a>> {
         let mut options = shaderc::CompileOptions::new()?;
         options.set_target_env(
             shaderc::TargetEnv::Vulkan,
             shaderc::EnvVersion::Vulkan1_3 as u32,
         );
-        options.set_include_callback(|requested, include_type, requestee, depth| {
-            let files = std::fs::read_dir(Path::new("shaders"))
-                .map_err(|e| format!("could not open shaders dir {e}"))?;
-            if let Some(requested_file) = files.flatten().find(|f| f.file_name() == requested) {
-                let path = requested_file.path();
-                let abs_path = std::fs::canonicalize(&path)
-                    .map_err(|e| format!("could not canonicalize path {e}"))?;
-                let resolved_name = abs_path.as_os_str().to_string_lossy().to_string();
-
-                let content =
-                    fs::read_to_string(path).map_err(|e| format!("could not read content{e}"))?;
-                Ok(ResolvedInclude {
-                    resolved_name,
-                    content,
-                })
-            } else {
-                Err("could not find requested file".to_owned())
+        options.set_optimization_level(OPTIMIZATION_LEVEL);
+        options.set_source_language(language.to_shaderc());
+        for (name, value) in &defines.defines {
+            options.add_macro_definition(name, value.as_deref());
+        }
+        let include_paths = self.include_paths.clone();
+        let chain = RefCell::new(vec![source.to_path_buf()]);
+        options.set_include_callback(move |requested, include_type, requestee, depth| {
+            let path = resolve_include(requested, include_type, requestee, &include_paths)?;
+            let mut chain = chain.borrow_mut();
+            // Includes are resolved depth first, so everything deeper than `depth` is done.
+            chain.truncate(depth);
+            if chain.contains(&path) {
+                let cycle = chain
+                    .iter()
+                    .chain([&path])
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                return Err(format!("include cycle: {cycle}"));
             }
+            chain.push(path.clone());
+
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+            if let Some(included) = included {
+                included.borrow_mut().push(path.clone());
+            }
+            Ok(ResolvedInclude {
+                resolved_name: path.display().to_string(),
+                content,
+            })
         });
         Ok(options)
     }
-    pub fn create_shader_module_from_path(
+    /// Loads the shader `source` and creates a module for it. See [`Self::load_spirv`].
+    pub fn load_shader_module(
         &self,
         device: &ash::Device,
-        path: impl AsRef<Path>,
-        kind: shaderc::ShaderKind,
-        entry_point: &str,
-    ) -> eyre::Result<vk::ShaderModule> {
-        let spv = self.compile_from_path(path, kind, entry_point)?;
-        let info = vk::ShaderModuleCreateInfo::default().code(spv.as_binary());
-        unsafe {
-            device
-                .create_shader_module(&info, None)
-                .wrap_err("could not create shader module")
-        }
+        source: impl Into<ShaderSource>,
+        kind: ShaderKind,
+    ) -> eyre::Result<(vk::ShaderModule, ShaderDependencies)> {
+        self.load_permutation(device, source, kind, &ShaderDefines::default())
     }
-    pub fn create_shader_module_from_str(
+    /// Loads the permutation of `source` selected by `defines` and creates a module for it.
+    pub fn load_permutation(
         &self,
         device: &ash::Device,
-        source: &str,
-        kind: shaderc::ShaderKind,
-        file_name: &str,
-        entry_point: &str,
-    ) -> eyre::Result<vk::ShaderModule> {
-        let spv = self.compile_from_str(source, kind, file_name, entry_point)?;
-        let info = vk::ShaderModuleCreateInfo::default().code(spv.as_binary());
-        unsafe {
-            device
-                .create_shader_module(&info, None)
-                .wrap_err("could not create shader module")
-        }
+        source: impl Into<ShaderSource>,
+        kind: ShaderKind,
+        defines: &ShaderDefines,
+    ) -> eyre::Result<(vk::ShaderModule, ShaderDependencies)> {
+        let (spirv, dependencies) = self.load_spirv(&source.into(), kind, defines)?;
+        let info = vk::ShaderModuleCreateInfo::default().code(&spirv);
+        let module = unsafe { device.create_shader_module(&info, None) }
+            .wrap_err("could not create shader module")?;
+        Ok((module, dependencies))
     }
-
-    pub fn compile_from_str(
+    /// Compiles the shader `source`. It is read from [`SHADER_DIR`] in dev mode or when it is not
+    /// one of the embedded sources, and compiled as GLSL or HLSL depending on its extension.
+    /// Results are served from the on-disk SPIR-V cache when neither the source nor any of its
+    /// includes changed, so each permutation is only compiled once.
+    #[cfg(feature = "runtime-shaders")]
+    pub fn load_spirv(
         &self,
-        source: &str,
-        kind: shaderc::ShaderKind,
-        file_name: &str,
-        entry_point: &str,
-    ) -> shaderc::Result<shaderc::CompilationArtifact> {
-        let options = Self::default_options()?;
-        self.compiler
-            .compile_into_spirv(source, kind, file_name, entry_point, Some(&options))
-    }
-    pub fn compile_from_path(
+        shader: &ShaderSource,
+        kind: ShaderKind,
+        defines: &ShaderDefines,
+    ) -> eyre::Result<(Vec<u32>, ShaderDependencies)> {
+        let name = shader.name();
+        let path = Path::new(SHADER_DIR).join(name);
+        // Shaders that are not embedded, such as background effects, are always read from disk.
+        let source = match embedded_source(name) {
+            Some(source) if !HOT_RELOAD_ENABLED => Cow::Borrowed(source),
+            _ => Cow::Owned(
+                read_to_string(&path).wrap_err_with(|| format!("could not read {name}"))?,
+            ),
+        };
+        let source_path = fs::canonicalize(&path).ok();
+        let dependencies = |mut files: Vec<PathBuf>| {
+            files.extend(source_path.clone());
+            ShaderDependencies {
+                files,
+                unknown: false,
+            }
+        };
+        let include_paths = std::env::join_paths(&self.include_paths)?;
+        // A shaderc update can generate different SPIR-V for the same sources and options.
+        let (spirv_version, spirv_revision) = shaderc::get_spirv_version();
+
+        let key = hash_parts(&[
+            name.as_bytes(),
+            source.as_bytes(),
+            format!("{kind:?}").as_bytes(),
+            shader.entry_point().as_bytes(),
+            format!("{:?}", shader.language()).as_bytes(),
+            OPTIONS_FINGERPRINT.as_bytes(),
+            format!("{OPTIMIZATION_LEVEL:?}").as_bytes(),
+            &spirv_version.to_le_bytes(),
+            &spirv_revision.to_le_bytes(),
+            defines.fingerprint().as_bytes(),
+            include_paths.as_encoded_bytes(),
+        ]);
+        if let Some(cached) = self.cache.as_ref().and_then(|c| c.load(key)) {
+            tracing::debug!("loaded {name} from the SPIR-V cache");
+            return Ok((cached.spirv, dependencies(cached.includes)));
+        }
+
+        let included = RefCell::new(vec![]);
+        let input_path = source_path.as_deref().unwrap_or(&path);
+        let options = self.options(input_path, shader.language(), defines, Some(&included))?;
+        let artifact = self
+            .compiler
+            .compile_into_spirv(
+                &source,
+                kind.to_shaderc(),
+                &input_path.to_string_lossy(),
+                shader.entry_point(),
+                Some(&options),
+            )
+            .wrap_err_with(|| format!("could not compile {name} [{}]", defines.fingerprint()))?;
+        drop(options);
+        let included = included.into_inner();
+        let spirv = artifact.as_binary().to_vec();
+        if let Some(cache) = &self.cache
+            && let Err(e) = cache.store(key, &included, &spirv)
+        {
+            tracing::warn!("could not cache {name}: {e}");
+        }
+        Ok((spirv, dependencies(included)))
+    }
+    /// Loads the SPIR-V that build.rs compiled for the shader `source`. Permutations and entry
+    /// points other than the default ones must be listed in `shaders/permutations.txt`.
+    #[cfg(not(feature = "runtime-shaders"))]
+    #[allow(clippy::unused_self)]
+    pub fn load_spirv(
         &self,
-        path: impl AsRef<Path>,
-        kind: shaderc::ShaderKind,
-        entry_point: &str,
-    ) -> eyre::Result<shaderc::CompilationArtifact> {
-        let file_name = path
-            .as_ref()
-            .file_name()
-            .ok_or_eyre("could not get filename")?
-            .to_string_lossy();
-        let source = read_to_string(&path)?;
-        self.compile_from_str(&source, kind, &file_name, entry_point)
-            .wrap_err("could not compile shader")
+        shader: &ShaderSource,
+        _kind: ShaderKind,
+        defines: &ShaderDefines,
+    ) -> eyre::Result<(Vec<u32>, ShaderDependencies)> {
+        let name = shader.name();
+        let bytes = precompiled_spirv(name, shader.entry_point(), &defines.fingerprint())
+            .ok_or_eyre("shader permutation was not precompiled")?;
+        let spirv = ash::util::read_spv(&mut std::io::Cursor::new(bytes))
+            .wrap_err_with(|| format!("invalid SPIR-V for {name}"))?;
+        let files = fs::canonicalize(PathBuf::from(SHADER_DIR).join(name))
+            .into_iter()
+            .collect();
+        Ok((
+            spirv,
+            ShaderDependencies {
+                files,
+                unknown: false,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // build.rs is included for the keys it names precompiled permutations with.
+    #![allow(dead_code)]
+    include!("../build.rs");
+
+    use super::ShaderDefines;
+
+    #[test]
+    fn fingerprint_matches_precompiled_keys() {
+        let permutations = include_str!("../shaders/permutations.txt");
+        for (name, _, defines) in permutations.lines().filter_map(parse_permutation) {
+            let shader_defines =
+                defines
+                    .iter()
+                    .fold(ShaderDefines::new(), |shader_defines, (define, value)| {
+                        if value.is_empty() {
+                            shader_defines.define(define)
+                        } else {
+                            shader_defines.define_value(define, value)
+                        }
+                    });
+            assert_eq!(
+                shader_defines.fingerprint(),
+                permutation_key(&defines),
+                "{name}"
+            );
+        }
     }
 }
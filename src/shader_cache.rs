@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, eyre};
+
+const MAGIC: &[u8; 8] = b"VKGSPV01";
+
+/// 64-bit FNV-1a over every part, with each part prefixed by its length so that moving bytes from
+/// one part to the next changes the hash.
+pub fn hash_parts(parts: &[&[u8]]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let mut hash = OFFSET_BASIS;
+    for part in parts {
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(part.iter()) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    hash
+}
+
+fn hash_file(path: &Path) -> Option<u64> {
+    fs::read(path).ok().map(|content| hash_parts(&[&content]))
+}
+
+pub struct CachedSpirv {
+    pub spirv: Vec<u32>,
+    pub includes: Vec<PathBuf>,
+}
+
+/// Compiled SPIR-V stored on disk, one file per cache key.
+///
+/// Includes are only known after compiling, so each entry records the files it pulled in along
+/// with a hash of their contents, and is discarded when any of them changed.
+pub struct SpirvCache {
+    dir: PathBuf,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    const fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Some(head)
+    }
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+impl SpirvCache {
+    /// `VKGUIDE_SHADER_CACHE` if set, `.shader_cache` in the working directory otherwise.
+    pub fn default_dir() -> PathBuf {
+        std::env::var_os("VKGUIDE_SHADER_CACHE")
+            .map_or_else(|| PathBuf::from(".shader_cache"), PathBuf::from)
+    }
+    pub fn new(dir: impl Into<PathBuf>) -> eyre::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+    fn entry_path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}.spv"))
+    }
+    pub fn load(&self, key: u64) -> Option<CachedSpirv> {
+        let bytes = fs::read(self.entry_path(key)).ok()?;
+        let mut reader = Reader { bytes: &bytes };
+        if reader.take(MAGIC.len())? != MAGIC || reader.u64()? != key {
+            return None;
+        }
+        let include_count = reader.u32()?;
+        let mut includes = Vec::with_capacity(include_count as usize);
+        for _ in 0..include_count {
+            let len = reader.u32()? as usize;
+            let path = PathBuf::from(std::str::from_utf8(reader.take(len)?).ok()?);
+            let content_hash = reader.u64()?;
+            if hash_file(&path) != Some(content_hash) {
+                return None;
+            }
+            includes.push(path);
+        }
+        let word_count = reader.u32()? as usize;
+        let spirv = reader
+            .take(word_count * 4)?
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        Some(CachedSpirv { spirv, includes })
+    }
+    pub fn store(&self, key: u64, includes: &[PathBuf], spirv: &[u32]) -> eyre::Result<()> {
+        let mut bytes = Vec::with_capacity(spirv.len() * 4 + 64);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&key.to_le_bytes());
+        bytes.extend_from_slice(&(includes.len() as u32).to_le_bytes());
+        for include in includes {
+            let path = include
+                .to_str()
+                .ok_or_else(|| eyre!("non utf-8 include path {}", include.display()))?;
+            let content_hash = hash_file(include).ok_or_else(|| eyre!("could not read {path}"))?;
+            bytes.extend_from_slice(&(path.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(path.as_bytes());
+            bytes.extend_from_slice(&content_hash.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(spirv.len() as u32).to_le_bytes());
+        for word in spirv {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        // Write then rename so a crash never leaves a truncated entry behind.
+        let path = self.entry_path(key);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory per test, so tests running in parallel do not share entries.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vkguide-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("spirv-round-trip");
+        let include = dir.join("common.glsl");
+        fs::write(&include, "float f;").unwrap();
+        let cache = SpirvCache::new(dir.join("cache")).unwrap();
+        let spirv = [0x0723_0203, 1, 2, 0xdead_beef];
+
+        cache
+            .store(42, std::slice::from_ref(&include), &spirv)
+            .unwrap();
+        let cached = cache.load(42).unwrap();
+        assert_eq!(cached.spirv, spirv);
+        assert_eq!(cached.includes, std::slice::from_ref(&include));
+        assert!(cache.load(43).is_none());
+
+        fs::write(&include, "float g;").unwrap();
+        assert!(
+            cache.load(42).is_none(),
+            "a changed include invalidates the entry"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_file() {
+        let dir = temp_dir("spirv-corrupted");
+        let cache = SpirvCache::new(&dir).unwrap();
+        let spirv = [0x0723_0203, 1, 2, 3];
+        cache.store(7, &[], &spirv).unwrap();
+        let path = cache.entry_path(7);
+        let bytes = fs::read(&path).unwrap();
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(cache.load(7).is_none(), "truncated entry");
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 0xff;
+        fs::write(&path, &bad_magic).unwrap();
+        assert!(cache.load(7).is_none(), "wrong magic");
+
+        // An entry renamed to another key must not be served for it.
+        fs::write(cache.entry_path(8), &bytes).unwrap();
+        assert!(cache.load(8).is_none(), "key mismatch");
+
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(cache.load(7).unwrap().spirv, spirv);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
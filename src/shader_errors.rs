@@ -0,0 +1,162 @@
+use std::path::PathBuf;
+
+use egui::{Color32, RichText};
+
+use crate::shader::HOT_RELOAD_ENABLED;
+
+const EXCERPT_CONTEXT: usize = 3;
+
+/// One `file:line: error: message` line of a shader compiler log.
+struct Diagnostic {
+    file: PathBuf,
+    line: Option<usize>,
+    message: String,
+}
+
+impl Diagnostic {
+    fn parse(line: &str) -> Option<Self> {
+        let (location, message) = line
+            .split_once(": error: ")
+            .or_else(|| line.split_once(": warning: "))?;
+        // Single line logs come prefixed with "compilation error: ".
+        let location = location.rsplit_once(": ").map_or(location, |(_, l)| l);
+        let (file, line) = match location.rsplit_once(':') {
+            Some((file, line)) if line.parse::<usize>().is_ok() => (file, line.parse().ok()),
+            _ => (location, None),
+        };
+        Some(Self {
+            file: PathBuf::from(file),
+            line,
+            message: message.trim().to_owned(),
+        })
+    }
+    fn location(&self) -> String {
+        self.line.map_or_else(
+            || self.file.display().to_string(),
+            |line| format!("{}:{line}", self.file.display()),
+        )
+    }
+}
+
+struct ShaderError {
+    owner: String,
+    summary: String,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Lines around a diagnostic, read once when it is clicked.
+struct Excerpt {
+    location: String,
+    line: Option<usize>,
+    lines: Vec<(usize, String)>,
+}
+
+impl Excerpt {
+    fn read(diagnostic: &Diagnostic) -> Self {
+        let lines = std::fs::read_to_string(&diagnostic.file)
+            .map(|source| {
+                let target = diagnostic.line.unwrap_or(1);
+                source
+                    .lines()
+                    .enumerate()
+                    .map(|(i, line)| (i + 1, line.to_owned()))
+                    .skip(target.saturating_sub(EXCERPT_CONTEXT + 1))
+                    .take(EXCERPT_CONTEXT * 2 + 1)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            location: diagnostic.location(),
+            line: diagnostic.line,
+            lines,
+        }
+    }
+}
+
+/// Shader compile errors shown in an overlay in dev mode, keyed by the pipeline that failed.
+#[derive(Default)]
+pub struct ShaderErrors {
+    errors: Vec<ShaderError>,
+    excerpt: Option<Excerpt>,
+}
+
+impl ShaderErrors {
+    pub fn report(&mut self, owner: &str, error: &eyre::Report) {
+        self.clear(owner);
+        let diagnostics = error
+            .chain()
+            .flat_map(|cause| {
+                cause
+                    .to_string()
+                    .lines()
+                    .filter_map(Diagnostic::parse)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        self.errors.push(ShaderError {
+            owner: owner.to_owned(),
+            summary: error.to_string(),
+            diagnostics,
+        });
+    }
+    pub fn clear(&mut self, owner: &str) {
+        self.errors.retain(|e| e.owner != owner);
+    }
+    /// Passes `result` through, except that in dev mode a failure is reported and replaced with
+    /// `fallback()` so the engine keeps running.
+    pub fn or_fallback<T>(
+        &mut self,
+        owner: &str,
+        result: eyre::Result<T>,
+        fallback: impl FnOnce() -> eyre::Result<T>,
+    ) -> eyre::Result<T> {
+        match result {
+            Ok(value) => {
+                self.clear(owner);
+                Ok(value)
+            }
+            Err(e) if HOT_RELOAD_ENABLED => {
+                tracing::error!("{owner} does not compile, using a fallback: {e:?}");
+                self.report(owner, &e);
+                fallback()
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn ui(&mut self, ctx: &egui::Context) {
+        if self.errors.is_empty() {
+            self.excerpt = None;
+            return;
+        }
+        egui::Window::new("Shader Errors").show(ctx, |ui| {
+            for error in &self.errors {
+                ui.label(RichText::new(&error.owner).strong());
+                if error.diagnostics.is_empty() {
+                    ui.label(&error.summary);
+                }
+                for diagnostic in &error.diagnostics {
+                    let text = format!("{}: {}", diagnostic.location(), diagnostic.message);
+                    if ui
+                        .link(RichText::new(text).color(Color32::LIGHT_RED))
+                        .clicked()
+                    {
+                        self.excerpt = Some(Excerpt::read(diagnostic));
+                    }
+                }
+                ui.separator();
+            }
+            if let Some(excerpt) = &self.excerpt {
+                ui.label(&excerpt.location);
+                for (number, line) in &excerpt.lines {
+                    let text = RichText::new(format!("{number:>4} | {line}")).monospace();
+                    if Some(*number) == excerpt.line {
+                        ui.label(text.color(Color32::LIGHT_RED));
+                    } else {
+                        ui.label(text);
+                    }
+                }
+            }
+        });
+    }
+}
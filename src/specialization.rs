@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+
+use ash::vk;
+
+/// A scalar that can back a `layout(constant_id = N) const` declaration.
+pub trait SpecializationValue: Copy {
+    fn to_bytes(self) -> Vec<u8>;
+}
+
+macro_rules! impl_specialization_value {
+    ($($ty:ty),*) => {
+        $(impl SpecializationValue for $ty {
+            fn to_bytes(self) -> Vec<u8> {
+                self.to_ne_bytes().to_vec()
+            }
+        })*
+    };
+}
+
+impl_specialization_value!(u32, i32, f32, u64, i64, f64);
+
+impl SpecializationValue for bool {
+    // Booleans are 32 bit `VkBool32`s.
+    fn to_bytes(self) -> Vec<u8> {
+        vk::Bool32::from(self).to_ne_bytes().to_vec()
+    }
+}
+
+/// Specialization constants of one shader stage, keyed by `constant_id`. Constants that are not
+/// set keep the default value declared in the shader.
+#[derive(Debug, Default, Clone)]
+pub struct SpecializationConstants {
+    values: BTreeMap<u32, Vec<u8>>,
+    entries: Vec<vk::SpecializationMapEntry>,
+    data: Vec<u8>,
+}
+
+impl SpecializationConstants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set(mut self, constant_id: u32, value: impl SpecializationValue) -> Self {
+        self.values.insert(constant_id, value.to_bytes());
+        self.entries.clear();
+        self.data.clear();
+        for (constant_id, bytes) in &self.values {
+            self.entries.push(
+                vk::SpecializationMapEntry::default()
+                    .constant_id(*constant_id)
+                    .offset(self.data.len() as u32)
+                    .size(bytes.len()),
+            );
+            self.data.extend_from_slice(bytes);
+        }
+        self
+    }
+    pub fn info(&self) -> vk::SpecializationInfo<'_> {
+        vk::SpecializationInfo::default()
+            .map_entries(&self.entries)
+            .data(&self.data)
+    }
+}
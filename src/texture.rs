@@ -3,16 +3,22 @@ use glam::Vec4;
 use vk_mem::Alloc;
 
 use crate::{
-    buffer::AllocatedBuffer,
-    descriptors::{DescriptorAllocator, DescriptorLayoutBuilder, DescriptorWriter},
-    immediate::ImmediateSubmit,
-    utils::{
-        image_subresource_range, layout_to_flag, memcopy, pack_unorm_4x8, transition_image,
-        transition_image_queue,
+    descriptors::{
+        BoundDescriptor, DescriptorAllocator, DescriptorBackend, DescriptorBufferAllocator,
+        DescriptorLayoutBuilder, DescriptorWriter,
     },
-    vulkan::QueueFamilyIndices,
+    upload::UploadManager,
+    utils::{format_aspect, image_subresource_range, layout_to_flag, pack_unorm_4x8},
+    vulkan::{QueueFamilyIndices, Vulkan},
 };
 
+/// Depth formats with a stencil aspect, in order of preference, then plain depth.
+const DEPTH_FORMATS: [vk::Format; 3] = [
+    vk::Format::D32_SFLOAT_S8_UINT,
+    vk::Format::D24_UNORM_S8_UINT,
+    vk::Format::D32_SFLOAT,
+];
+
 pub const WHITE: Vec4 = Vec4::ONE;
 pub const GREY: Vec4 = Vec4::new(0.66, 0.66, 0.66, 1.0);
 pub const BLACK: Vec4 = Vec4::ZERO;
@@ -29,18 +35,16 @@ impl EngineImages {
     pub fn new(
         device: &ash::Device,
         allocator: &vk_mem::Allocator,
-        immediate_graphics: &ImmediateSubmit,
-        graphics_queue: vk::Queue,
+        uploads: &mut UploadManager,
     ) -> eyre::Result<Self> {
         let color_format = vk::Format::R8G8B8A8_UNORM;
 
-        let allocate_image = |pixels, extent| {
+        let mut allocate_image = |pixels, extent| {
             AllocatedImage::with_data(
                 pixels,
                 device,
                 allocator,
-                immediate_graphics,
-                graphics_queue,
+                uploads,
                 color_format,
                 extent,
                 vk::ImageUsageFlags::SAMPLED,
@@ -197,7 +201,7 @@ pub fn image_view_create_info<'a>(
 
 pub struct DrawImage {
     image: AllocatedImage,
-    descriptor_set: vk::DescriptorSet,
+    descriptor: BoundDescriptor,
     descriptor_set_layout: vk::DescriptorSetLayout,
 }
 
@@ -216,6 +220,7 @@ impl DrawImage {
         device: &ash::Device,
         allocator: &vk_mem::Allocator,
         descriptor_allocator: &DescriptorAllocator,
+        mut descriptor_buffer: Option<&mut DescriptorBufferAllocator>,
     ) -> eyre::Result<Self> {
         let extent = vk::Extent3D {
             width,
@@ -223,11 +228,22 @@ impl DrawImage {
             depth: 1,
         };
         let image = AllocatedImage::create_draw_image(device, allocator, extent)?;
+        let backend = if descriptor_buffer.is_some() {
+            DescriptorBackend::Buffer
+        } else {
+            DescriptorBackend::Pool
+        };
         let descriptor_set_layout = DescriptorLayoutBuilder::new()
             .add_binding(0, vk::DescriptorType::STORAGE_IMAGE)
+            .flags(backend.layout_create_flags())
             .build(device, vk::ShaderStageFlags::COMPUTE)?;
-        let set = descriptor_allocator.allocate(device, descriptor_set_layout)?[0];
 
+        let descriptor = BoundDescriptor::allocate(
+            device,
+            descriptor_allocator,
+            descriptor_buffer.as_deref_mut(),
+            descriptor_set_layout,
+        )?;
         DescriptorWriter::new()
             .write_image(
                 0,
@@ -236,11 +252,11 @@ impl DrawImage {
                 vk::ImageLayout::GENERAL,
                 vk::DescriptorType::STORAGE_IMAGE,
             )
-            .update_set(device, set);
+            .update(device, descriptor_buffer.as_deref(), descriptor)?;
 
         Ok(Self {
             image,
-            descriptor_set: set,
+            descriptor,
             descriptor_set_layout,
         })
     }
@@ -253,8 +269,8 @@ impl DrawImage {
         &self.image
     }
 
-    pub const fn descriptor_set(&self) -> vk::DescriptorSet {
-        self.descriptor_set
+    pub const fn descriptor(&self) -> BoundDescriptor {
+        self.descriptor
     }
 
     pub const fn descriptor_set_layout(&self) -> vk::DescriptorSetLayout {
@@ -267,18 +283,66 @@ pub struct AllocatedImage {
     allocation: vk_mem::Allocation,
     extent: vk::Extent3D,
     format: vk::Format,
+    samples: vk::SampleCountFlags,
 }
 impl AllocatedImage {
+    /// The first of [`DEPTH_FORMATS`] the device can render to, `D32_SFLOAT` being always
+    /// supported.
+    pub fn depth_format(vulkan: &Vulkan) -> vk::Format {
+        DEPTH_FORMATS
+            .into_iter()
+            .find(|format| {
+                let properties = unsafe {
+                    vulkan
+                        .instance()
+                        .get_physical_device_format_properties(vulkan.physical_device(), *format)
+                };
+                properties
+                    .optimal_tiling_features
+                    .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+            })
+            .unwrap_or(vk::Format::D32_SFLOAT)
+    }
     pub fn create_depth_image(
         device: &ash::Device,
         allocator: &vk_mem::Allocator,
         draw_image: &DrawImage,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
     ) -> Result<Self, eyre::Error> {
-        let format = vk::Format::D32_SFLOAT;
         let extent = draw_image.extent();
         let usage = vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT;
 
-        Self::new(device, allocator, format, extent, usage, false)
+        Self::with_samples(
+            device,
+            allocator,
+            format,
+            extent,
+            usage,
+            false,
+            samples,
+            &[],
+        )
+    }
+    /// Multisampled color target that is resolved into `draw_image` at the end of rendering. Not
+    /// transient, since the background pass stores into it and the geometry pass loads it.
+    pub fn create_msaa_color_image(
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+        draw_image: &DrawImage,
+        samples: vk::SampleCountFlags,
+    ) -> eyre::Result<Self> {
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT;
+        Self::with_samples(
+            device,
+            allocator,
+            draw_image.format(),
+            draw_image.extent(),
+            usage,
+            false,
+            samples,
+            &[],
+        )
     }
     fn create_draw_image(
         device: &ash::Device,
@@ -287,10 +351,12 @@ impl AllocatedImage {
     ) -> eyre::Result<Self> {
         let format = vk::Format::R16G16B16A16_SFLOAT;
 
+        // Sampled when the background is copied into the multisampled color target.
         let usage = vk::ImageUsageFlags::TRANSFER_SRC
             | vk::ImageUsageFlags::TRANSFER_DST
             | vk::ImageUsageFlags::STORAGE
-            | vk::ImageUsageFlags::COLOR_ATTACHMENT;
+            | vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | vk::ImageUsageFlags::SAMPLED;
 
         Self::new(device, allocator, format, extent, usage, false)
     }
@@ -303,7 +369,35 @@ impl AllocatedImage {
         usage: vk::ImageUsageFlags,
         mipmapped: bool,
     ) -> eyre::Result<Self> {
-        let mut image_info = image_create_info(format, usage, extent);
+        Self::with_samples(
+            device,
+            allocator,
+            format,
+            extent,
+            usage,
+            mipmapped,
+            vk::SampleCountFlags::TYPE_1,
+            &[],
+        )
+    }
+    /// Shared by `queue_families` like [`crate::buffer::AllocatedBuffer::shared`].
+    #[allow(clippy::too_many_arguments)]
+    fn with_samples(
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+        format: vk::Format,
+        extent: vk::Extent3D,
+        usage: vk::ImageUsageFlags,
+        mipmapped: bool,
+        samples: vk::SampleCountFlags,
+        queue_families: &[u32],
+    ) -> eyre::Result<Self> {
+        let mut image_info = image_create_info(format, usage, extent).samples(samples);
+        if queue_families.len() > 1 {
+            image_info = image_info
+                .sharing_mode(vk::SharingMode::CONCURRENT)
+                .queue_family_indices(queue_families);
+        }
         if mipmapped {
             let mip_levels = (extent.width.max(extent.height) as f32).log2().floor() as u32 + 1;
             image_info = image_info.mip_levels(mip_levels);
@@ -315,12 +409,7 @@ impl AllocatedImage {
         };
         let (image, allocation) = unsafe { allocator.create_image(&image_info, &alloc_info) }?;
 
-        let aspect_flags = if format == vk::Format::D32_SFLOAT {
-            vk::ImageAspectFlags::DEPTH
-        } else {
-            vk::ImageAspectFlags::COLOR
-        };
-        let mut image_view_info = image_view_create_info(format, image, aspect_flags);
+        let mut image_view_info = image_view_create_info(format, image, format_aspect(format));
         image_view_info.subresource_range = image_view_info
             .subresource_range
             .level_count(image_info.mip_levels);
@@ -333,6 +422,7 @@ impl AllocatedImage {
             allocation,
             extent,
             format,
+            samples,
         })
     }
     #[allow(clippy::too_many_arguments)]
@@ -340,72 +430,33 @@ impl AllocatedImage {
         data: &[u32],
         device: &ash::Device,
         allocator: &vk_mem::Allocator,
-        immediate_graphics: &ImmediateSubmit,
-        graphics_queue: vk::Queue,
+        uploads: &mut UploadManager,
         format: vk::Format,
         extent: vk::Extent3D,
         usage: vk::ImageUsageFlags,
         mipmapped: bool,
     ) -> eyre::Result<Self> {
-        let size = extent.depth * extent.width * extent.height * std::mem::size_of::<u32>() as u32;
         debug_assert!(data.len() == (extent.depth * extent.width * extent.height) as usize);
-        let mut staging_buffer = AllocatedBuffer::new(
-            allocator,
-            u64::from(size),
-            vk::BufferUsageFlags::TRANSFER_SRC,
-            vk_mem::MemoryUsage::Auto,
-        )?;
-        let memory = unsafe { allocator.map_memory(&mut staging_buffer.allocation()) }?;
-
-        unsafe { memcopy(data, memory) };
-
-        let image = Self::new(
+        let image = Self::with_samples(
             device,
             allocator,
             format,
             extent,
             usage | vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST,
             mipmapped,
+            vk::SampleCountFlags::TYPE_1,
+            uploads.queue_families(),
+        )?;
+        uploads.upload_image(
+            device,
+            allocator,
+            bytemuck::cast_slice(data),
+            image.image,
+            extent,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags2::FRAGMENT_SHADER,
+            vk::AccessFlags2::SHADER_SAMPLED_READ,
         )?;
-
-        immediate_graphics.submit(device, graphics_queue, |cmd| {
-            transition_image(
-                device,
-                cmd,
-                image.image,
-                vk::ImageLayout::UNDEFINED,
-                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            );
-            let image_subresource = vk::ImageSubresourceLayers::default()
-                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                .mip_level(0)
-                .base_array_layer(0)
-                .layer_count(1);
-            let copy = vk::BufferImageCopy::default()
-                .buffer_offset(0)
-                .buffer_row_length(0)
-                .buffer_image_height(0)
-                .image_extent(extent)
-                .image_subresource(image_subresource);
-            unsafe {
-                device.cmd_copy_buffer_to_image(
-                    cmd,
-                    staging_buffer.buffer(),
-                    image.image,
-                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    &[copy],
-                );
-            };
-            transition_image(
-                device,
-                cmd,
-                image.image,
-                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-            );
-        })?;
-        unsafe { allocator.unmap_memory(&mut staging_buffer.allocation()) };
-        staging_buffer.destroy(allocator);
         Ok(image)
     }
 
@@ -433,4 +484,8 @@ impl AllocatedImage {
     pub const fn format(&self) -> vk::Format {
         self.format
     }
+
+    pub const fn samples(&self) -> vk::SampleCountFlags {
+        self.samples
+    }
 }
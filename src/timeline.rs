@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ash::vk;
+
+/// A timeline semaphore counting submissions: every submission signals the next value, so
+/// whether submission `n` has finished is a matter of comparing `n` to the semaphore's value.
+pub struct Timeline {
+    semaphore: vk::Semaphore,
+    /// The value of the last submission. Atomic so that submissions can be numbered through
+    /// shared references, like the queues they go to.
+    last: AtomicU64,
+}
+
+impl Timeline {
+    pub fn new(device: &ash::Device) -> eyre::Result<Self> {
+        let mut type_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let semaphore_info = vk::SemaphoreCreateInfo::default().push_next(&mut type_info);
+        let semaphore = unsafe { device.create_semaphore(&semaphore_info, None) }?;
+        Ok(Self {
+            semaphore,
+            last: AtomicU64::new(0),
+        })
+    }
+    /// Reserves the value the next submission signals, see [`Self::signal_info`].
+    pub fn next(&self) -> u64 {
+        self.last.fetch_add(1, Ordering::Relaxed) + 1
+    }
+    /// The value of the last submission, 0 before the first one.
+    pub fn last(&self) -> u64 {
+        self.last.load(Ordering::Relaxed)
+    }
+    /// The value of the last submission the GPU finished.
+    pub fn completed(&self, device: &ash::Device) -> eyre::Result<u64> {
+        Ok(unsafe { device.get_semaphore_counter_value(self.semaphore) }?)
+    }
+    /// Blocks until submission `value` finished.
+    pub fn wait(&self, device: &ash::Device, value: u64) -> eyre::Result<()> {
+        let semaphores = [self.semaphore];
+        let values = [value];
+        let wait_info = vk::SemaphoreWaitInfo::default()
+            .semaphores(&semaphores)
+            .values(&values);
+        unsafe { device.wait_semaphores(&wait_info, u64::MAX) }?;
+        Ok(())
+    }
+    /// Makes the commands after `stage` wait for submission `value`.
+    pub fn wait_info(
+        &self,
+        stage: vk::PipelineStageFlags2,
+        value: u64,
+    ) -> vk::SemaphoreSubmitInfo<'static> {
+        vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.semaphore)
+            .stage_mask(stage)
+            .device_index(0)
+            .value(value)
+    }
+    /// Signals `value`, reserved with [`Self::next`], once the commands before `stage` finished.
+    pub fn signal_info(
+        &self,
+        stage: vk::PipelineStageFlags2,
+        value: u64,
+    ) -> vk::SemaphoreSubmitInfo<'static> {
+        self.wait_info(stage, value)
+    }
+    pub fn destroy(&self, device: &ash::Device) {
+        unsafe { device.destroy_semaphore(self.semaphore, None) };
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::VecDeque;
+
+use ash::vk;
+
+use crate::{
+    buffer::AllocatedBuffer,
+    timeline::Timeline,
+    utils::{QueueTransfer, create_cmd_buffer_info, memcopy, transition_image},
+    vulkan::Vulkan,
+};
+
+/// Size of the staging ring, uploads larger than that get a staging buffer of their own.
+pub const STAGING_RING_SIZE: u64 = 32 * 1024 * 1024;
+/// Staging offsets suit any texel size and the copy granularity of transfer only queues.
+const STAGING_ALIGNMENT: u64 = 16;
+
+/// Offset of `size` bytes in a ring of `ring_size` bytes whose next free byte is `head` and whose
+/// data still in use starts at `tail`, if any. Offsets are aligned to [`STAGING_ALIGNMENT`], and
+/// the end of the ring is skipped when the data does not fit there.
+fn ring_offset(head: u64, tail: Option<u64>, size: u64, ring_size: u64) -> Option<u64> {
+    let head = head.next_multiple_of(STAGING_ALIGNMENT);
+    match tail {
+        None => (size <= ring_size).then_some(0),
+        Some(tail) if head > tail => {
+            if head + size <= ring_size {
+                Some(head)
+            } else {
+                // Skips the end of the ring.
+                (size <= tail).then_some(0)
+            }
+        }
+        // Data wraps around, or fills the whole ring when `head == tail`.
+        Some(tail) => (head + size <= tail).then_some(head),
+    }
+}
+
+/// How destinations uploaded on the transfer queue get to the graphics queue, when the two are in
+/// different families.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadSharing {
+    /// Owned by one family at a time: released after the copy and acquired by a frame.
+    Exclusive,
+    /// Created for both families, which needs no ownership transfer but may be slower to access.
+    Concurrent,
+}
+
+impl UploadSharing {
+    /// Concurrent sharing is opt-in through `VKGUIDE_UPLOAD_SHARING=concurrent`.
+    pub fn preferred() -> Self {
+        match std::env::var("VKGUIDE_UPLOAD_SHARING").as_deref() {
+            Ok("concurrent") => Self::Concurrent,
+            _ => Self::Exclusive,
+        }
+    }
+}
+
+/// An upload that can be used on the graphics queue once [`UploadManager::is_ready`] says so.
+/// Handles compare in submission order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UploadHandle {
+    /// Timeline value of the batch the upload was recorded in.
+    value: u64,
+}
+
+/// Uploads recorded into one command buffer and submitted together.
+struct Batch {
+    cmd: vk::CommandBuffer,
+    value: u64,
+    /// Ring offset of the first staged upload, `None` while nothing was staged in the ring.
+    start: Option<u64>,
+    /// Staging buffers of uploads too large for the ring, freed with the batch.
+    dedicated: Vec<AllocatedBuffer>,
+    acquires: Vec<Acquire>,
+}
+
+/// What the graphics queue records for a destination before its first use there.
+enum Acquire {
+    Buffer {
+        buffer: vk::Buffer,
+        stage: vk::PipelineStageFlags2,
+        access: vk::AccessFlags2,
+    },
+    Image {
+        image: vk::Image,
+        layouts: (vk::ImageLayout, vk::ImageLayout),
+        stage: vk::PipelineStageFlags2,
+        access: vk::AccessFlags2,
+    },
+}
+
+/// Copies data into device local buffers and images from a staging ring on the transfer queue
+/// without waiting for the copies. Uploads are recorded into a batch, which is submitted by
+/// [`Self::flush`] or when the ring runs out of space, and the ring space of a batch is reused once
+/// its timeline value is reached. Once it is, [`Self::acquire`] hands the destinations over to the
+/// graphics queue.
+pub struct UploadManager {
+    queue: vk::Queue,
+    /// From the transfer to the graphics family, within the graphics family if they are the same or
+    /// destinations are shared.
+    transfer: QueueTransfer,
+    /// Families destinations are created for, see [`Self::queue_families`].
+    queue_families: Vec<u32>,
+    pool: vk::CommandPool,
+    ring: AllocatedBuffer,
+    ring_size: u64,
+    /// Where the next upload is staged.
+    head: u64,
+    recording: Option<Batch>,
+    /// Submitted batches, oldest first.
+    in_flight: VecDeque<Batch>,
+    free_cmds: Vec<vk::CommandBuffer>,
+    /// Submitted batches whose destinations the graphics queue did not acquire yet, oldest first.
+    to_acquire: VecDeque<(u64, Vec<Acquire>)>,
+    /// Value of the last batch acquired by the graphics queue.
+    acquired: u64,
+    /// Stages that wait for the last [`Self::acquire`], none if it had nothing to acquire.
+    acquire_stage: vk::PipelineStageFlags2,
+    /// Signaled by every batch.
+    timeline: Timeline,
+}
+
+impl UploadManager {
+    pub fn new(
+        vulkan: &Vulkan,
+        allocator: &vk_mem::Allocator,
+        sharing: UploadSharing,
+        ring_size: u64,
+    ) -> eyre::Result<Self> {
+        let device = vulkan.device();
+        let families = vulkan.queue_family_indices();
+        let (transfer, queue_families) =
+            if sharing == UploadSharing::Concurrent && families.transfer != families.graphics {
+                (
+                    QueueTransfer::new(families.graphics, families.graphics),
+                    vec![families.transfer, families.graphics],
+                )
+            } else {
+                (
+                    QueueTransfer::new(families.transfer, families.graphics),
+                    vec![],
+                )
+            };
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(families.transfer);
+        let pool = unsafe { device.create_command_pool(&pool_info, None) }?;
+        let ring = AllocatedBuffer::new(
+            allocator,
+            ring_size,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            vk_mem::MemoryUsage::AutoPreferHost,
+        )?;
+        Ok(Self {
+            queue: vulkan.transfer_queue(),
+            transfer,
+            queue_families,
+            pool,
+            ring,
+            ring_size,
+            head: 0,
+            recording: None,
+            in_flight: VecDeque::new(),
+            free_cmds: vec![],
+            to_acquire: VecDeque::new(),
+            acquired: 0,
+            acquire_stage: vk::PipelineStageFlags2::NONE,
+            timeline: Timeline::new(device)?,
+        })
+    }
+    /// Families destinations have to be created for with `vk::SharingMode::CONCURRENT`, empty when
+    /// they are exclusive.
+    pub fn queue_families(&self) -> &[u32] {
+        &self.queue_families
+    }
+    /// Copies `data` into `dst` at `dst_offset`. `stage` and `access` are the first use of `dst` on
+    /// the graphics queue, and `dst` is uploaded to once.
+    #[allow(clippy::too_many_arguments)]
+    pub fn upload_buffer(
+        &mut self,
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+        data: &[u8],
+        dst: vk::Buffer,
+        dst_offset: u64,
+        stage: vk::PipelineStageFlags2,
+        access: vk::AccessFlags2,
+    ) -> eyre::Result<UploadHandle> {
+        let (src, src_offset) = self.stage(device, allocator, data)?;
+        let transfer = self.transfer;
+        let batch = self.batch(device)?;
+        if !data.is_empty() {
+            let copy = vk::BufferCopy::default()
+                .src_offset(src_offset)
+                .dst_offset(dst_offset)
+                .size(data.len() as u64);
+            unsafe { device.cmd_copy_buffer(batch.cmd, src, dst, &[copy]) };
+        }
+        transfer.release_buffers(
+            device,
+            batch.cmd,
+            &[dst],
+            vk::PipelineStageFlags2::TRANSFER,
+            vk::AccessFlags2::TRANSFER_WRITE,
+        );
+        batch.acquires.push(Acquire::Buffer {
+            buffer: dst,
+            stage,
+            access,
+        });
+        Ok(UploadHandle { value: batch.value })
+    }
+    /// Copies tightly packed texels into the first mip level of the color `image`, which the
+    /// graphics queue moves to `layout` before its first `stage` and `access` of it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn upload_image(
+        &mut self,
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+        data: &[u8],
+        image: vk::Image,
+        extent: vk::Extent3D,
+        layout: vk::ImageLayout,
+        stage: vk::PipelineStageFlags2,
+        access: vk::AccessFlags2,
+    ) -> eyre::Result<UploadHandle> {
+        let (src, src_offset) = self.stage(device, allocator, data)?;
+        let transfer = self.transfer;
+        let batch = self.batch(device)?;
+        transition_image(
+            device,
+            batch.cmd,
+            image,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+        let image_subresource = vk::ImageSubresourceLayers::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1);
+        let copy = vk::BufferImageCopy::default()
+            .buffer_offset(src_offset)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_extent(extent)
+            .image_subresource(image_subresource);
+        unsafe {
+            device.cmd_copy_buffer_to_image(
+                batch.cmd,
+                src,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[copy],
+            );
+        };
+        // The transfer queue may not support the accesses of `layout`, so the transition is left
+        // to the graphics queue, or shared by the release and the acquire.
+        let layouts = (vk::ImageLayout::TRANSFER_DST_OPTIMAL, layout);
+        transfer.release_image(
+            device,
+            batch.cmd,
+            image,
+            layouts,
+            vk::PipelineStageFlags2::TRANSFER,
+            vk::AccessFlags2::TRANSFER_WRITE,
+        );
+        batch.acquires.push(Acquire::Image {
+            image,
+            layouts,
+            stage,
+            access,
+        });
+        Ok(UploadHandle { value: batch.value })
+    }
+    /// Submits the uploads recorded since the last flush.
+    pub fn flush(&mut self, device: &ash::Device) -> eyre::Result<()> {
+        let Some(mut batch) = self.recording.take() else {
+            return Ok(());
+        };
+        unsafe { device.end_command_buffer(batch.cmd) }?;
+        let value = self.timeline.next();
+        debug_assert_eq!(value, batch.value, "batches are submitted in order");
+        let cmd_infos = [vk::CommandBufferSubmitInfo::default()
+            .command_buffer(batch.cmd)
+            .device_mask(0)];
+        let signal_infos = [self
+            .timeline
+            .signal_info(vk::PipelineStageFlags2::ALL_COMMANDS, value)];
+        let submit_info = vk::SubmitInfo2::default()
+            .command_buffer_infos(&cmd_infos)
+            .signal_semaphore_infos(&signal_infos);
+        let submitted =
+            unsafe { device.queue_submit2(self.queue, &[submit_info], vk::Fence::null()) };
+        self.to_acquire
+            .push_back((batch.value, std::mem::take(&mut batch.acquires)));
+        self.in_flight.push_back(batch);
+        Ok(submitted?)
+    }
+    /// Whether the upload finished and was acquired by the graphics queue, so that commands
+    /// recorded after [`Self::acquire`] can use it.
+    pub const fn is_ready(&self, upload: UploadHandle) -> bool {
+        upload.value <= self.acquired
+    }
+    /// Records the acquire of every destination whose batch finished into `cmd`, which goes to the
+    /// graphics queue and waits with [`Self::wait_info`]. Does not block, batches still running are
+    /// acquired by a later call.
+    pub fn acquire(&mut self, device: &ash::Device, cmd: vk::CommandBuffer) -> eyre::Result<()> {
+        let completed = self.timeline.completed(device)?;
+        let transfer = self.transfer;
+        self.acquire_stage = vk::PipelineStageFlags2::NONE;
+        while let Some((value, _)) = self.to_acquire.front()
+            && *value <= completed
+        {
+            let Some((value, acquires)) = self.to_acquire.pop_front() else {
+                break;
+            };
+            for acquire in acquires {
+                match acquire {
+                    Acquire::Buffer {
+                        buffer,
+                        stage,
+                        access,
+                    } => {
+                        transfer.acquire_buffers(device, cmd, &[buffer], stage, access);
+                        self.acquire_stage |= stage;
+                    }
+                    Acquire::Image {
+                        image,
+                        layouts,
+                        stage,
+                        access,
+                    } => {
+                        transfer.acquire_image(device, cmd, image, layouts, stage, access);
+                        self.acquire_stage |= stage;
+                    }
+                }
+            }
+            self.acquired = value;
+        }
+        Ok(())
+    }
+    /// Makes the submission of the last [`Self::acquire`] wait for the releases it acquired. The
+    /// batches already finished, so this orders the two queues without stalling.
+    pub fn wait_info(&self) -> Option<vk::SemaphoreSubmitInfo<'static>> {
+        (!self.acquire_stage.is_empty())
+            .then(|| self.timeline.wait_info(self.acquire_stage, self.acquired))
+    }
+    /// Submits everything recorded and blocks until it finished.
+    pub fn finish(&mut self, device: &ash::Device) -> eyre::Result<()> {
+        self.flush(device)?;
+        self.timeline.wait(device, self.timeline.last())
+    }
+    /// Frees the staging space and command buffers of finished batches.
+    pub fn retire(
+        &mut self,
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+    ) -> eyre::Result<()> {
+        let completed = self.timeline.completed(device)?;
+        while let Some(batch) = self.in_flight.front()
+            && batch.value <= completed
+        {
+            let Some(mut batch) = self.in_flight.pop_front() else {
+                break;
+            };
+            unsafe {
+                device.reset_command_buffer(batch.cmd, vk::CommandBufferResetFlags::empty())
+            }?;
+            self.free_cmds.push(batch.cmd);
+            for buffer in &mut batch.dedicated {
+                buffer.destroy(allocator);
+            }
+        }
+        Ok(())
+    }
+    /// The batch uploads are recorded into, started if needed.
+    fn batch(&mut self, device: &ash::Device) -> eyre::Result<&mut Batch> {
+        if self.recording.is_none() {
+            let cmd = if let Some(cmd) = self.free_cmds.pop() {
+                cmd
+            } else {
+                let cmd_info = create_cmd_buffer_info().pool(self.pool).call();
+                unsafe { device.allocate_command_buffers(&cmd_info) }?[0]
+            };
+            let begin_info = vk::CommandBufferBeginInfo::default()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            unsafe { device.begin_command_buffer(cmd, &begin_info) }?;
+            self.recording = Some(Batch {
+                cmd,
+                // Only flushed batches signal the timeline, in order.
+                value: self.timeline.last() + 1,
+                start: None,
+                dedicated: vec![],
+                acquires: vec![],
+            });
+        }
+        Ok(self.recording.as_mut().expect("a batch is recording"))
+    }
+    /// Copies `data` to staging memory owned by the recording batch, returning the buffer and
+    /// offset to copy from.
+    fn stage(
+        &mut self,
+        device: &ash::Device,
+        allocator: &vk_mem::Allocator,
+        data: &[u8],
+    ) -> eyre::Result<(vk::Buffer, u64)> {
+        if data.is_empty() {
+            return Ok((self.ring.buffer(), 0));
+        }
+        let size = data.len() as u64;
+        if size > self.ring_size {
+            let buffer = AllocatedBuffer::new(
+                allocator,
+                size,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                vk_mem::MemoryUsage::AutoPreferHost,
+            )?;
+            let memory = buffer.alloc_info().mapped_data.cast::<u8>();
+            unsafe { memcopy(data, memory) };
+            allocator.flush_allocation(&buffer.allocation(), 0, vk::WHOLE_SIZE)?;
+            let handle = buffer.buffer();
+            self.batch(device)?.dedicated.push(buffer);
+            return Ok((handle, 0));
+        }
+        let offset = loop {
+            if let Some(offset) = self.allocate(size) {
+                break offset;
+            }
+            // Out of space: submit what is recorded and wait for the oldest batch to free its
+            // part of the ring.
+            self.flush(device)?;
+            let oldest = self
+                .in_flight
+                .front()
+                .map(|batch| batch.value)
+                .expect("a full ring has batches in flight");
+            self.timeline.wait(device, oldest)?;
+            self.retire(device, allocator)?;
+        };
+        let batch = self.batch(device)?;
+        batch.start.get_or_insert(offset);
+        let memory = self.ring.alloc_info().mapped_data.cast::<u8>();
+        unsafe { memcopy(data, memory.wrapping_byte_add(offset as usize)) };
+        allocator.flush_allocation(&self.ring.allocation(), offset, size)?;
+        Ok((self.ring.buffer(), offset))
+    }
+    /// Ring space for `size` bytes past the data of every unfinished batch, which starts at the
+    /// oldest batch's first upload and ends at `head`.
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        let tail = self
+            .in_flight
+            .iter()
+            .chain(&self.recording)
+            .find_map(|batch| batch.start);
+        let offset = ring_offset(self.head, tail, size, self.ring_size)?;
+        self.head = offset + size;
+        Some(offset)
+    }
+    /// Waits for every batch, the staging memory goes with the manager.
+    pub fn destroy(&mut self, device: &ash::Device, allocator: &vk_mem::Allocator) {
+        if let Err(e) = self.finish(device) {
+            tracing::error!("could not finish pending uploads: {e:?}");
+        }
+        for batch in &mut self.in_flight {
+            for buffer in &mut batch.dedicated {
+                buffer.destroy(allocator);
+            }
+        }
+        self.ring.destroy(allocator);
+        unsafe { device.destroy_command_pool(self.pool, None) };
+        self.timeline.destroy(device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RING: u64 = 256;
+
+    #[test]
+    fn empty_ring_starts_at_zero() {
+        assert_eq!(ring_offset(100, None, 64, RING), Some(0));
+        assert_eq!(ring_offset(0, None, RING, RING), Some(0));
+        assert_eq!(ring_offset(0, None, RING + 1, RING), None);
+    }
+
+    #[test]
+    fn offsets_are_aligned() {
+        assert_eq!(ring_offset(1, Some(0), 8, RING), Some(16));
+        assert_eq!(ring_offset(16, Some(0), 8, RING), Some(16));
+        assert_eq!(ring_offset(33, Some(128), 8, RING), Some(48));
+        // Aligning the head past the end of the ring wraps around.
+        assert_eq!(ring_offset(250, Some(64), 4, RING), Some(0));
+    }
+
+    #[test]
+    fn wraps_around() {
+        // Does not fit between the head and the end, but does before the tail.
+        assert_eq!(ring_offset(224, Some(64), 48, RING), Some(0));
+        assert_eq!(ring_offset(224, Some(64), 64, RING), Some(0));
+        assert_eq!(ring_offset(224, Some(64), 65, RING), None);
+        // Fits exactly at the end.
+        assert_eq!(ring_offset(224, Some(64), 32, RING), Some(224));
+        // Wrapped data grows up to the tail.
+        assert_eq!(ring_offset(32, Some(128), 96, RING), Some(32));
+        assert_eq!(ring_offset(32, Some(128), 97, RING), None);
+    }
+
+    #[test]
+    fn full_ring() {
+        assert_eq!(ring_offset(64, Some(64), 1, RING), None);
+        assert_eq!(ring_offset(RING, Some(0), 1, RING), None);
+    }
+}
@@ -17,6 +17,8 @@ pub fn pack_unorm_4x8(v: Vec4) -> u32 {
     bytemuck::cast(pack)
 }
 
+/// For binary semaphores, timeline ones are waited on and signaled through
+/// [`crate::timeline::Timeline`].
 pub fn semaphore_submit_info<'a>(
     stage_mask: vk::PipelineStageFlags2,
     semaphore: vk::Semaphore,
@@ -25,7 +27,6 @@ pub fn semaphore_submit_info<'a>(
         .semaphore(semaphore)
         .stage_mask(stage_mask)
         .device_index(0)
-        .value(1)
 }
 
 pub fn layout_to_flag(layout: vk::ImageLayout) -> vk::AccessFlags2 {
@@ -38,7 +39,9 @@ pub fn layout_to_flag(layout: vk::ImageLayout) -> vk::AccessFlags2 {
                 | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE
                 | vk::AccessFlags2::COLOR_ATTACHMENT_READ_NONCOHERENT_EXT
         }
-        vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL => {
+        vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL
+        | vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        | vk::ImageLayout::STENCIL_ATTACHMENT_OPTIMAL => {
             vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ
                 | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE
         }
@@ -47,35 +50,194 @@ pub fn layout_to_flag(layout: vk::ImageLayout) -> vk::AccessFlags2 {
     }
 }
 
-pub fn transition_image_queue(
-    device: &ash::Device,
-    cmd: vk::CommandBuffer,
-    image: vk::Image,
-    old_layout: vk::ImageLayout,
-    new_layout: vk::ImageLayout,
-    old_queue: u32,
-    new_queue: u32,
-) {
-    let subresource_range =
-        image_subresource_range(if new_layout == vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL {
+/// Aspects of a depth and/or stencil `format`, `COLOR` for everything else.
+pub fn format_aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::X8_D24_UNORM_PACK32 | vk::Format::D32_SFLOAT => {
             vk::ImageAspectFlags::DEPTH
+        }
+        vk::Format::D16_UNORM_S8_UINT
+        | vk::Format::D24_UNORM_S8_UINT
+        | vk::Format::D32_SFLOAT_S8_UINT => {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        }
+        vk::Format::S8_UINT => vk::ImageAspectFlags::STENCIL,
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
+
+/// Aspects a transition into `layout` applies to.
+fn layout_aspect(layout: vk::ImageLayout) -> vk::ImageAspectFlags {
+    match layout {
+        vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL => vk::ImageAspectFlags::DEPTH,
+        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL => {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        }
+        vk::ImageLayout::STENCIL_ATTACHMENT_OPTIMAL => vk::ImageAspectFlags::STENCIL,
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
+
+/// Layout a depth attachment of `format` is rendered in, covering its stencil aspect if any.
+pub fn depth_attachment_layout(format: vk::Format) -> vk::ImageLayout {
+    if format_aspect(format).contains(vk::ImageAspectFlags::STENCIL) {
+        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+    } else {
+        vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL
+    }
+}
+
+/// Moves resources created with `vk::SharingMode::EXCLUSIVE` from the `src` to the `dst` queue
+/// family. The release is recorded on the `src` queue, the acquire on the `dst` queue, and a
+/// semaphore orders the two submissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueTransfer {
+    pub src: u32,
+    pub dst: u32,
+}
+
+impl QueueTransfer {
+    pub const fn new(src: u32, dst: u32) -> Self {
+        Self { src, dst }
+    }
+    pub const fn reversed(self) -> Self {
+        Self::new(self.dst, self.src)
+    }
+    /// Recorded after the last `stage` and `access` of `buffers` on the `src` queue.
+    pub fn release_buffers(
+        self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        buffers: &[vk::Buffer],
+        stage: vk::PipelineStageFlags2,
+        access: vk::AccessFlags2,
+    ) {
+        self.buffer_barriers(
+            device,
+            cmd,
+            buffers,
+            (stage, access),
+            (vk::PipelineStageFlags2::NONE, vk::AccessFlags2::NONE),
+        );
+    }
+    /// Recorded before the first `stage` and `access` of `buffers` on the `dst` queue.
+    pub fn acquire_buffers(
+        self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        buffers: &[vk::Buffer],
+        stage: vk::PipelineStageFlags2,
+        access: vk::AccessFlags2,
+    ) {
+        self.buffer_barriers(
+            device,
+            cmd,
+            buffers,
+            (vk::PipelineStageFlags2::NONE, vk::AccessFlags2::NONE),
+            (stage, access),
+        );
+    }
+    /// Recorded after the last `stage` and `access` of `image` on the `src` queue, moving it
+    /// between `layouts` on the way.
+    pub fn release_image(
+        self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        image: vk::Image,
+        layouts: (vk::ImageLayout, vk::ImageLayout),
+        stage: vk::PipelineStageFlags2,
+        access: vk::AccessFlags2,
+    ) {
+        if self.src == self.dst {
+            return;
+        }
+        self.image_barrier(
+            device,
+            cmd,
+            image,
+            layouts,
+            (stage, access),
+            (vk::PipelineStageFlags2::NONE, vk::AccessFlags2::NONE),
+        );
+    }
+    /// Recorded before the first `stage` and `access` of `image` on the `dst` queue, with the
+    /// `layouts` of the release. Within one family there is nothing to acquire and this is the
+    /// layout transition alone, after a semaphore wait at `stage`.
+    pub fn acquire_image(
+        self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        image: vk::Image,
+        layouts: (vk::ImageLayout, vk::ImageLayout),
+        stage: vk::PipelineStageFlags2,
+        access: vk::AccessFlags2,
+    ) {
+        self.image_barrier(
+            device,
+            cmd,
+            image,
+            layouts,
+            (stage, vk::AccessFlags2::NONE),
+            (stage, access),
+        );
+    }
+    fn image_barrier(
+        self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        image: vk::Image,
+        (old_layout, new_layout): (vk::ImageLayout, vk::ImageLayout),
+        (src_stage, src_access): (vk::PipelineStageFlags2, vk::AccessFlags2),
+        (dst_stage, dst_access): (vk::PipelineStageFlags2, vk::AccessFlags2),
+    ) {
+        let (src_family, dst_family) = if self.src == self.dst {
+            (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
         } else {
-            vk::ImageAspectFlags::COLOR
-        });
-    let image_barrier = vk::ImageMemoryBarrier2::default()
-        .src_queue_family_index(old_queue)
-        .dst_queue_family_index(new_queue)
-        .src_access_mask(layout_to_flag(old_layout))
-        .dst_access_mask(layout_to_flag(new_layout))
-        .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
-        .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
-        .old_layout(old_layout)
-        .new_layout(new_layout)
-        .subresource_range(subresource_range)
-        .image(image);
-    let image_barriers = [image_barrier];
-    let dependency = vk::DependencyInfo::default().image_memory_barriers(&image_barriers);
-    unsafe { device.cmd_pipeline_barrier2(cmd, &dependency) };
+            (self.src, self.dst)
+        };
+        let image_barrier = vk::ImageMemoryBarrier2::default()
+            .src_stage_mask(src_stage)
+            .src_access_mask(src_access)
+            .dst_stage_mask(dst_stage)
+            .dst_access_mask(dst_access)
+            .src_queue_family_index(src_family)
+            .dst_queue_family_index(dst_family)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .subresource_range(image_subresource_range(layout_aspect(new_layout)))
+            .image(image);
+        let image_barriers = [image_barrier];
+        let dependency = vk::DependencyInfo::default().image_memory_barriers(&image_barriers);
+        unsafe { device.cmd_pipeline_barrier2(cmd, &dependency) };
+    }
+    fn buffer_barriers(
+        self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        buffers: &[vk::Buffer],
+        (src_stage, src_access): (vk::PipelineStageFlags2, vk::AccessFlags2),
+        (dst_stage, dst_access): (vk::PipelineStageFlags2, vk::AccessFlags2),
+    ) {
+        if buffers.is_empty() || self.src == self.dst {
+            return;
+        }
+        let buffer_barriers = buffers
+            .iter()
+            .map(|buffer| {
+                vk::BufferMemoryBarrier2::default()
+                    .src_stage_mask(src_stage)
+                    .src_access_mask(src_access)
+                    .dst_stage_mask(dst_stage)
+                    .dst_access_mask(dst_access)
+                    .src_queue_family_index(self.src)
+                    .dst_queue_family_index(self.dst)
+                    .buffer(*buffer)
+                    .size(vk::WHOLE_SIZE)
+            })
+            .collect::<Vec<_>>();
+        let dependency = vk::DependencyInfo::default().buffer_memory_barriers(&buffer_barriers);
+        unsafe { device.cmd_pipeline_barrier2(cmd, &dependency) };
+    }
 }
 
 pub fn transition_image(
@@ -85,12 +247,7 @@ pub fn transition_image(
     old_layout: vk::ImageLayout,
     new_layout: vk::ImageLayout,
 ) {
-    let subresource_range =
-        image_subresource_range(if new_layout == vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL {
-            vk::ImageAspectFlags::DEPTH
-        } else {
-            vk::ImageAspectFlags::COLOR
-        });
+    let subresource_range = image_subresource_range(layout_aspect(new_layout));
     let image_barrier = vk::ImageMemoryBarrier2::default()
         .src_access_mask(layout_to_flag(old_layout))
         .dst_access_mask(layout_to_flag(new_layout))
@@ -128,6 +285,8 @@ pub fn color_attachment_info<'a>(
     view: vk::ImageView,
     clear: Option<vk::ClearValue>,
     layout: Option<vk::ImageLayout>,
+    /// Averages a multisampled `view` into this single sampled view at the end of rendering.
+    resolve: Option<vk::ImageView>,
 ) -> ash::vk::RenderingAttachmentInfo<'a> {
     let mut info = vk::RenderingAttachmentInfo::default()
         .image_view(view)
@@ -141,6 +300,14 @@ pub fn color_attachment_info<'a>(
     if let Some(clear) = clear {
         info.clear_value = clear;
     }
+    if let Some(resolve) = resolve {
+        // Only the resolved image is read afterwards.
+        info = info
+            .resolve_mode(vk::ResolveModeFlags::AVERAGE)
+            .resolve_image_view(resolve)
+            .resolve_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE);
+    }
     info
 }
 #[bon::builder]
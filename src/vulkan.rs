@@ -11,6 +11,8 @@ use winit::{
     window::Window,
 };
 
+use crate::descriptors::DescriptorBackend;
+
 pub struct Vulkan {
     entry: ash::Entry,
     instance: ash::Instance,
@@ -22,6 +24,12 @@ pub struct Vulkan {
     graphics_queue: vk::Queue,
     present_queue: vk::Queue,
     transfer_queue: vk::Queue,
+    /// Only when [`QueueFamilyIndices::compute`] is a family of its own.
+    compute_queue: Option<vk::Queue>,
+    descriptor_backend: DescriptorBackend,
+    extended_dynamic_state3: bool,
+    /// Graphics stages the device was created with.
+    graphics_stages: vk::ShaderStageFlags,
 }
 
 const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
@@ -113,6 +121,7 @@ pub struct QueueFamilyIndices {
     pub graphics: u32,
     pub present: u32,
     pub transfer: u32,
+    /// A compute only family if the device has one, the graphics family otherwise.
     pub compute: u32,
 }
 
@@ -139,7 +148,14 @@ impl QueueFamilyIndices {
             .iter()
             .enumerate()
             .find_map(|(index, prop)| {
-                (prop.queue_flags.contains(vk::QueueFlags::COMPUTE)).then_some(index)
+                (prop.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                    && !prop.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+                .then_some(index)
+            })
+            .or_else(|| {
+                props.iter().enumerate().find_map(|(index, prop)| {
+                    (prop.queue_flags.contains(vk::QueueFlags::COMPUTE)).then_some(index)
+                })
             })
             .ok_or_eyre("could not find compute")? as u32;
         let present = props
@@ -173,6 +189,10 @@ impl QueueFamilyIndices {
             compute,
         })
     }
+    /// Whether compute work can run on a queue of its own, next to the graphics queue.
+    pub const fn async_compute(&self) -> bool {
+        self.compute != self.graphics
+    }
 }
 
 fn select_physical_device(
@@ -207,7 +227,8 @@ fn select_physical_device(
             let has_features = features_13.dynamic_rendering == b_true
                 && features_13.synchronization2 == b_true
                 && features_12.buffer_device_address == b_true
-                && features_12.descriptor_indexing == b_true;
+                && features_12.descriptor_indexing == b_true
+                && features_12.timeline_semaphore == b_true;
 
             (api_supported && has_features && is_discrete).then_some((pd, queue_family_indices))
         })
@@ -215,23 +236,141 @@ fn select_physical_device(
     Ok((*physical_device, queue_family_indices))
 }
 
+fn has_device_extension(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+    extension: &CStr,
+) -> bool {
+    let Ok(extensions) =
+        (unsafe { instance.enumerate_device_extension_properties(physical_device) })
+    else {
+        return false;
+    };
+    extensions.iter().any(|e| {
+        e.extension_name_as_c_str()
+            .is_ok_and(|name| name == extension)
+    })
+}
+
+fn supports_descriptor_buffer(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    if !has_device_extension(instance, physical_device, ash::ext::descriptor_buffer::NAME) {
+        return false;
+    }
+    let mut descriptor_buffer_features = vk::PhysicalDeviceDescriptorBufferFeaturesEXT::default();
+    let mut features2 =
+        vk::PhysicalDeviceFeatures2::default().push_next(&mut descriptor_buffer_features);
+    unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+    descriptor_buffer_features.descriptor_buffer == vk::TRUE
+}
+
+/// The parts of `VK_EXT_extended_dynamic_state3` [`crate::graphics::DrawState`] uses, and
+/// `fillModeNonSolid` for the polygon modes it sets. Cull mode, front face, depth state and depth
+/// bias enable are core in Vulkan 1.3.
+fn supports_extended_dynamic_state3(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    if !has_device_extension(
+        instance,
+        physical_device,
+        ash::ext::extended_dynamic_state3::NAME,
+    ) {
+        return false;
+    }
+    let mut dynamic_state_features = vk::PhysicalDeviceExtendedDynamicState3FeaturesEXT::default();
+    let mut features2 =
+        vk::PhysicalDeviceFeatures2::default().push_next(&mut dynamic_state_features);
+    unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+    let fill_mode_non_solid = features2.features.fill_mode_non_solid == vk::TRUE;
+    fill_mode_non_solid
+        && dynamic_state_features.extended_dynamic_state3_polygon_mode == vk::TRUE
+        && dynamic_state_features.extended_dynamic_state3_color_blend_enable == vk::TRUE
+        && dynamic_state_features.extended_dynamic_state3_color_blend_equation == vk::TRUE
+}
+
+/// Task and mesh shaders through `VK_EXT_mesh_shader`.
+fn supports_mesh_shader(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> bool {
+    if !has_device_extension(instance, physical_device, ash::ext::mesh_shader::NAME) {
+        return false;
+    }
+    let mut mesh_shader_features = vk::PhysicalDeviceMeshShaderFeaturesEXT::default();
+    let mut features2 = vk::PhysicalDeviceFeatures2::default().push_next(&mut mesh_shader_features);
+    unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+    mesh_shader_features.task_shader == vk::TRUE && mesh_shader_features.mesh_shader == vk::TRUE
+}
+
+/// Vertex and fragment stages, and the optional geometry, tessellation, task and mesh stages the
+/// device supports.
+fn supported_graphics_stages(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> vk::ShaderStageFlags {
+    let supported = unsafe { instance.get_physical_device_features(physical_device) };
+    let mut stages = vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT;
+    if supported.geometry_shader == vk::TRUE {
+        stages |= vk::ShaderStageFlags::GEOMETRY;
+    }
+    if supported.tessellation_shader == vk::TRUE {
+        stages |= vk::ShaderStageFlags::TESSELLATION_CONTROL
+            | vk::ShaderStageFlags::TESSELLATION_EVALUATION;
+    }
+    if supports_mesh_shader(instance, physical_device) {
+        stages |= vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT;
+    }
+    stages
+}
+
 fn build_device(
     instance: &ash::Instance,
     physical_device: vk::PhysicalDevice,
     queue_family_indices: &QueueFamilyIndices,
+    descriptor_backend: DescriptorBackend,
+    extended_dynamic_state3: bool,
+    graphics_stages: vk::ShaderStageFlags,
 ) -> eyre::Result<ash::Device> {
+    let mut descriptor_buffer_features =
+        vk::PhysicalDeviceDescriptorBufferFeaturesEXT::default().descriptor_buffer(true);
+    let mut extension_names = DEVICE_EXTENSION_NAMES.to_vec();
     let mut features_13 = vk::PhysicalDeviceVulkan13Features::default()
         .dynamic_rendering(true)
         .synchronization2(true);
+    if descriptor_backend == DescriptorBackend::Buffer {
+        extension_names.push(ash::ext::descriptor_buffer::NAME.as_ptr());
+        features_13.p_next = (&raw mut descriptor_buffer_features).cast();
+    }
+    // Optional stages are enabled whenever the device has them, pipelines using them are rejected
+    // by `validate_stages` otherwise.
+    let mut mesh_shader_features = vk::PhysicalDeviceMeshShaderFeaturesEXT::default()
+        .task_shader(true)
+        .mesh_shader(true);
+    if graphics_stages.contains(vk::ShaderStageFlags::MESH_EXT) {
+        extension_names.push(ash::ext::mesh_shader::NAME.as_ptr());
+        mesh_shader_features.p_next = features_13.p_next;
+        features_13.p_next = (&raw mut mesh_shader_features).cast();
+    }
+    let mut dynamic_state_features = vk::PhysicalDeviceExtendedDynamicState3FeaturesEXT::default()
+        .extended_dynamic_state3_polygon_mode(true)
+        .extended_dynamic_state3_color_blend_enable(true)
+        .extended_dynamic_state3_color_blend_equation(true);
+    if extended_dynamic_state3 {
+        extension_names.push(ash::ext::extended_dynamic_state3::NAME.as_ptr());
+        dynamic_state_features.p_next = features_13.p_next;
+        features_13.p_next = (&raw mut dynamic_state_features).cast();
+    }
     let mut features_12 = vk::PhysicalDeviceVulkan12Features::default()
         .buffer_device_address(true)
-        .descriptor_indexing(true);
+        .descriptor_indexing(true)
+        .timeline_semaphore(true);
     features_12.p_next = (&raw mut features_13).cast();
 
     let mut map = HashSet::new();
     map.insert(queue_family_indices.graphics);
     map.insert(queue_family_indices.transfer);
     map.insert(queue_family_indices.present);
+    map.insert(queue_family_indices.compute);
 
     let queue_infos = map
         .iter()
@@ -242,17 +381,22 @@ fn build_device(
         })
         .collect::<Vec<_>>();
 
-    let features = vk::PhysicalDeviceFeatures::default();
+    let features = vk::PhysicalDeviceFeatures::default()
+        .geometry_shader(graphics_stages.contains(vk::ShaderStageFlags::GEOMETRY))
+        .tessellation_shader(graphics_stages.contains(vk::ShaderStageFlags::TESSELLATION_CONTROL))
+        .fill_mode_non_solid(extended_dynamic_state3);
     let device_info = vk::DeviceCreateInfo::default()
         .queue_create_infos(&queue_infos)
-        .enabled_extension_names(DEVICE_EXTENSION_NAMES)
+        .enabled_extension_names(&extension_names)
         .enabled_features(&features)
         .push_next(&mut features_12);
     unsafe { instance.create_device(physical_device, &device_info, None) }
         .wrap_err("could not create device")
 }
 impl Vulkan {
-    pub fn new(window: &Window) -> eyre::Result<Self> {
+    /// Creates the device, enabling `VK_EXT_descriptor_buffer` if `descriptor_backend` asks for it
+    /// and the device supports it, falling back to descriptor pools otherwise.
+    pub fn new(window: &Window, descriptor_backend: DescriptorBackend) -> eyre::Result<Self> {
         let entry = unsafe { ash::Entry::load() }?;
         let display_handle = window.display_handle().wrap_err("window handle error")?;
         let window_handle = window.window_handle().wrap_err("window handle error")?;
@@ -279,10 +423,33 @@ impl Vulkan {
 
         let (physical_device, queue_family_indices) =
             select_physical_device(&entry, &instance, surface, api_version)?;
-        let device = build_device(&instance, physical_device, &queue_family_indices)?;
+        let descriptor_backend = match descriptor_backend {
+            DescriptorBackend::Buffer
+                if !supports_descriptor_buffer(&instance, physical_device) =>
+            {
+                tracing::warn!(
+                    "descriptor buffers not supported, falling back to descriptor pools"
+                );
+                DescriptorBackend::Pool
+            }
+            backend => backend,
+        };
+        let extended_dynamic_state3 = supports_extended_dynamic_state3(&instance, physical_device);
+        let graphics_stages = supported_graphics_stages(&instance, physical_device);
+        let device = build_device(
+            &instance,
+            physical_device,
+            &queue_family_indices,
+            descriptor_backend,
+            extended_dynamic_state3,
+            graphics_stages,
+        )?;
         let graphics_queue = unsafe { device.get_device_queue(queue_family_indices.graphics, 0) };
         let present_queue = unsafe { device.get_device_queue(queue_family_indices.present, 0) };
         let transfer_queue = unsafe { device.get_device_queue(queue_family_indices.transfer, 0) };
+        let compute_queue = queue_family_indices
+            .async_compute()
+            .then(|| unsafe { device.get_device_queue(queue_family_indices.compute, 0) });
 
         Ok(Self {
             entry,
@@ -295,6 +462,10 @@ impl Vulkan {
             graphics_queue,
             present_queue,
             transfer_queue,
+            compute_queue,
+            descriptor_backend,
+            extended_dynamic_state3,
+            graphics_stages,
         })
     }
 
@@ -314,6 +485,31 @@ impl Vulkan {
     pub fn swapchain_device(&self) -> ash::khr::swapchain::Device {
         ash::khr::swapchain::Device::new(&self.instance, &self.device)
     }
+    pub fn descriptor_buffer_device(&self) -> ash::ext::descriptor_buffer::Device {
+        ash::ext::descriptor_buffer::Device::new(&self.instance, &self.device)
+    }
+    /// `None` when the device lacks `VK_EXT_extended_dynamic_state3`, in which case blending and
+    /// polygon mode have to stay baked into pipelines.
+    pub fn extended_dynamic_state3_device(
+        &self,
+    ) -> Option<ash::ext::extended_dynamic_state3::Device> {
+        self.extended_dynamic_state3
+            .then(|| ash::ext::extended_dynamic_state3::Device::new(&self.instance, &self.device))
+    }
+    pub fn descriptor_buffer_properties(
+        &self,
+    ) -> vk::PhysicalDeviceDescriptorBufferPropertiesEXT<'static> {
+        let mut descriptor_buffer_props =
+            vk::PhysicalDeviceDescriptorBufferPropertiesEXT::default();
+        let mut props2 =
+            vk::PhysicalDeviceProperties2::default().push_next(&mut descriptor_buffer_props);
+        unsafe {
+            self.instance
+                .get_physical_device_properties2(self.physical_device, &mut props2);
+        };
+        descriptor_buffer_props.p_next = std::ptr::null_mut();
+        descriptor_buffer_props
+    }
 
     pub const fn physical_device(&self) -> vk::PhysicalDevice {
         self.physical_device
@@ -344,6 +540,20 @@ impl Vulkan {
     pub const fn transfer_queue(&self) -> vk::Queue {
         self.transfer_queue
     }
+    /// `None` without a dedicated compute family, compute work then goes on the graphics queue.
+    pub const fn compute_queue(&self) -> Option<vk::Queue> {
+        self.compute_queue
+    }
+
+    pub const fn descriptor_backend(&self) -> DescriptorBackend {
+        self.descriptor_backend
+    }
+    /// Stages graphics pipelines can use, checked by [`validate_stages`].
+    ///
+    /// [`validate_stages`]: crate::graphics::validate_stages
+    pub const fn graphics_stages(&self) -> vk::ShaderStageFlags {
+        self.graphics_stages
+    }
 }
 extern "system" fn debug_callback(
     severity: vk::DebugUtilsMessageSeverityFlagsEXT,